@@ -1,4 +1,4 @@
-use std::{cell::{Ref, RefCell}, rc::{Rc, Weak}};
+use std::{cell::{Ref, RefCell}, collections::HashMap, rc::{Rc, Weak}};
 
 use bitcoin::util::hash::Sha256dHash;
 use bitcoin::blockdata::block::BlockHeader;
@@ -12,11 +12,14 @@ pub struct BlockChain
 {
     // Nodes of current active chain
     active_nodes: Vec<Rc<RefCell<Node>>>,
+    // Heights of blocks in current active chain, so that peers can not make us scan the whole chain.
+    active_heights: HashMap<Sha256dHash, u32>,
 }
 
 pub struct ActiveChain<'a>
 {
     nodes: &'a Vec<Rc<RefCell<Node>>>,
+    heights: &'a HashMap<Sha256dHash, u32>,
 }
 
 impl BlockChain
@@ -28,10 +31,15 @@ impl BlockChain
 
     pub fn with_start(block_data: BlockData) -> BlockChain
     {
+        let mut active_heights = HashMap::new();
+        active_heights.insert(block_data.bitcoin_hash(), block_data.height());
         let node = Node::new(block_data);
         let mut vec = Vec::new();
         vec.push(node);
-        BlockChain { active_nodes: vec }
+        BlockChain {
+            active_nodes: vec,
+            active_heights,
+        }
     }
 
     pub fn try_add(&mut self, block_header: BlockHeader) -> Result<(), NotFoundPrevBlock>
@@ -43,6 +51,7 @@ impl BlockChain
     {
         ActiveChain {
             nodes: &self.active_nodes,
+            heights: &self.active_heights,
         }
     }
 }
@@ -91,6 +100,20 @@ impl<'a> ActiveChain<'a>
             .map(|node| Ref::map(node.as_ref().borrow(), |n| &n.block))
     }
 
+    /// Get the block which has given hash.
+    pub fn get_block_by_hash<'b>(&'b self, hash: &Sha256dHash) -> Option<Ref<'b, BlockData>>
+    {
+        self.get_block(*self.heights.get(hash)?)
+    }
+
+    /// Find the first block of `locator_hashes` in active chain, i.e. the last common block with a peer.
+    /// Returns `None` if none of them is found.
+    /// Active chain may not start from genesis, so the first block is not always common.
+    pub fn find_fork_point<'b>(&'b self, locator_hashes: &[Sha256dHash]) -> Option<Ref<'b, BlockData>>
+    {
+        locator_hashes.iter().filter_map(|hash| self.get_block_by_hash(hash)).next()
+    }
+
     /// Check whether active chain contains given block or not.
     pub fn contains(&self, block: &BlockData) -> bool
     {
@@ -210,7 +233,9 @@ impl BlockChain
     {
         let start_height = self.active_nodes[0].borrow().block.height();
         let rewind_idx = rewind_height - start_height + 1;
-        self.active_nodes.truncate(rewind_idx as usize);
+        for node in self.active_nodes.drain(rewind_idx as usize..) {
+            self.active_heights.remove(&node.borrow().block.bitcoin_hash());
+        }
     }
 
     /// Append nodes of given `node_ptr`'s branch.
@@ -225,6 +250,10 @@ impl BlockChain
                     self.borrow_then_append_nodes(prev_node);
                 }
                 // Now, `prev_node == active_chain.back().unwrap()`
+                {
+                    let block = &node_ptr.borrow().block;
+                    self.active_heights.insert(block.bitcoin_hash(), block.height());
+                }
                 self.active_nodes.push(node_ptr);
             },
         }
@@ -346,4 +375,46 @@ mod tests
         let headers: Vec<_> = active_chain.iter().map(|block| block.header).collect();
         assert_eq!(headers, vec![start_block_header, next_block_header]);
     }
+
+    #[test]
+    fn find_fork_point_from_locator()
+    {
+        let start_block_header = dummy_block_header(Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(BlockData::new(start_block_header, 0));
+        let mut prev_hash = start_block_header.bitcoin_hash();
+        for _ in 0..3 {
+            let header = dummy_block_header(prev_hash);
+            prev_hash = header.bitcoin_hash();
+            blocktree.try_add(header).unwrap();
+        }
+
+        let active_chain = blocktree.active_chain();
+        let block1_hash = active_chain.get_block(1).unwrap().bitcoin_hash();
+        let unknown_hash = Sha256dHash::from(&[1; 32][..]);
+        assert_eq!(active_chain.find_fork_point(&[unknown_hash, block1_hash]).unwrap().height(), 1);
+        assert!(active_chain.find_fork_point(&[unknown_hash]).is_none());
+    }
+
+    #[test]
+    fn index_hashes_of_active_chain_on_reorg()
+    {
+        let start_block_header = dummy_block_header(Sha256dHash::default());
+        let mut blocktree = BlockChain::with_start(BlockData::new(start_block_header, 100));
+        let stale = dummy_block_header(start_block_header.bitcoin_hash());
+        blocktree.try_add(stale).unwrap();
+
+        // A longer branch replaces the stale block.
+        let mut fork = stale;
+        fork.nonce = 1;
+        let fork_next = dummy_block_header(fork.bitcoin_hash());
+        blocktree.try_add(fork).unwrap();
+        blocktree.try_add(fork_next).unwrap();
+
+        let active_chain = blocktree.active_chain();
+        assert!(active_chain.get_block_by_hash(&stale.bitcoin_hash()).is_none());
+        assert_eq!(active_chain.get_block_by_hash(&fork.bitcoin_hash()).unwrap().height(), 101);
+        assert_eq!(active_chain.get_block_by_hash(&fork_next.bitcoin_hash()).unwrap().height(), 102);
+        // The start block is not genesis, so an unknown locator has no fork point.
+        assert!(active_chain.find_fork_point(&[stale.bitcoin_hash()]).is_none());
+    }
 }
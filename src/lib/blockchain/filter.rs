@@ -0,0 +1,322 @@
+use std::io::Cursor;
+
+use bitcoin::blockdata::{block::Block, opcodes::All as Opcode, script::Script};
+use bitcoin::network::{encodable::{ConsensusDecodable, VarInt}, serialize::{serialize, BitcoinHash, RawDecoder}};
+use bitcoin::util::hash::Sha256dHash;
+
+use util::siphash24;
+
+/// Filter type of the basic filter, which is the only one BIP158 defines.
+pub const BASIC_FILTER_TYPE: u8 = 0;
+
+// Parameters of the basic filter.
+const P: u8 = 19;
+const M: u64 = 784_931;
+
+/// Golomb-coded set of BIP158, serialized as the number of elements followed by the bit stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockFilter
+{
+    pub content: Vec<u8>,
+}
+
+impl BlockFilter
+{
+    /// Build a filter of the block from its elements, e.g. scripts. Duplicated elements are removed.
+    pub fn new<'a, I>(block_hash: &Sha256dHash, elements: I) -> BlockFilter
+    where I: IntoIterator<Item = &'a [u8]>
+    {
+        let mut elements: Vec<&[u8]> = elements.into_iter().collect();
+        elements.sort();
+        elements.dedup();
+
+        let n = elements.len() as u64;
+        let mut values = hashed_set(block_hash, elements.into_iter(), n);
+        values.sort();
+
+        let mut content = serialize(&VarInt(n)).unwrap(); // Never fail
+        let mut writer = BitWriter::new(&mut content);
+        let mut last = 0;
+        for value in values {
+            let delta = value - last;
+            last = value;
+            writer.write_unary(delta >> P);
+            writer.write_bits(delta, P);
+        }
+        writer.flush();
+        BlockFilter { content }
+    }
+
+    /// Basic filter of the block, which has output scripts and scripts which inputs spend.
+    /// `spent_scripts` are scripts of previous outputs of all inputs except coinbase.
+    /// Empty and `OP_RETURN` output scripts are excluded.
+    pub fn basic(block: &Block, spent_scripts: &[Script]) -> BlockFilter
+    {
+        let outputs = block
+            .txdata
+            .iter()
+            .flat_map(|tx| tx.output.iter())
+            .map(|output| &output.script_pubkey[..])
+            .filter(|script| script.first().map_or(false, |op| *op != Opcode::OP_RETURN as u8));
+        let spent = spent_scripts.iter().map(|script| &script[..]).filter(|script| !script.is_empty());
+        BlockFilter::new(&block.bitcoin_hash(), outputs.chain(spent))
+    }
+
+    /// Returns true if any of `queries` may be in the filter.
+    /// Malformed content is considered to match, so that the block is checked anyway.
+    pub fn match_any<'a, I>(&self, block_hash: &Sha256dHash, queries: I) -> bool
+    where I: IntoIterator<Item = &'a [u8]>
+    {
+        let mut decoder = RawDecoder::new(Cursor::new(&self.content));
+        let n = match ConsensusDecodable::consensus_decode(&mut decoder) {
+            // `n * M` must not overflow, and BIP158 limits the number of elements to 32 bits.
+            Ok(VarInt(n)) if n <= u32::max_value() as u64 => n,
+            _ => return true,
+        };
+        if n == 0 {
+            return false;
+        }
+        let offset = decoder.into_inner().position() as usize;
+
+        let mut queries = hashed_set(block_hash, queries.into_iter(), n);
+        queries.sort();
+
+        let mut reader = BitReader::new(&self.content[offset..]);
+        let mut value = 0;
+        let mut remaining = n;
+        for query in queries {
+            while value < query {
+                if remaining == 0 {
+                    return false;
+                }
+                value += match reader.read_golomb() {
+                    None => return true,
+                    Some(delta) => delta,
+                };
+                remaining -= 1;
+            }
+            if value == query {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns true if all of `queries` may be in the filter.
+    /// Unlike `match_any`, malformed content is considered not to match.
+    pub fn match_all<'a, I>(&self, block_hash: &Sha256dHash, queries: I) -> bool
+    where I: IntoIterator<Item = &'a [u8]>
+    {
+        let mut decoder = RawDecoder::new(Cursor::new(&self.content));
+        let n = match ConsensusDecodable::consensus_decode(&mut decoder) {
+            Ok(VarInt(n)) if n <= u32::max_value() as u64 => n,
+            _ => return false,
+        };
+        if n == 0 {
+            return queries.into_iter().next().is_none();
+        }
+        let offset = decoder.into_inner().position() as usize;
+
+        let mut queries = hashed_set(block_hash, queries.into_iter(), n);
+        queries.sort();
+
+        let mut reader = BitReader::new(&self.content[offset..]);
+        let mut value = 0;
+        let mut remaining = n;
+        for query in queries {
+            while value < query {
+                if remaining == 0 {
+                    return false;
+                }
+                value += match reader.read_golomb() {
+                    None => return false,
+                    Some(delta) => delta,
+                };
+                remaining -= 1;
+            }
+            if value != query {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Double SHA256 of the content, which is chained into filter headers.
+    pub fn filter_hash(&self) -> Sha256dHash
+    {
+        Sha256dHash::from_data(&self.content)
+    }
+
+    /// Filter header of the block, given that of the previous block.
+    pub fn header(&self, prev_header: &Sha256dHash) -> Sha256dHash
+    {
+        filter_header(&self.filter_hash(), prev_header)
+    }
+}
+
+/// Filter header is a hash of the filter hash and the previous filter header.
+/// The previous filter header of the genesis block is zero.
+pub fn filter_header(filter_hash: &Sha256dHash, prev_header: &Sha256dHash) -> Sha256dHash
+{
+    let mut data = filter_hash[..].to_vec();
+    data.extend_from_slice(&prev_header[..]);
+    Sha256dHash::from_data(&data)
+}
+
+/// Map elements to the range `[0, n * M)` with SipHash keyed by the block hash.
+/// `n` must fit in 32 bits so that the range does not overflow.
+fn hashed_set<'a, I>(block_hash: &Sha256dHash, elements: I, n: u64) -> Vec<u64>
+where I: Iterator<Item = &'a [u8]>
+{
+    let k0 = le_u64(&block_hash[..8]);
+    let k1 = le_u64(&block_hash[8..16]);
+    let f = n * M;
+    elements
+        .map(|e| ((siphash24(k0, k1, e) as u128 * f as u128) >> 64) as u64)
+        .collect()
+}
+
+fn le_u64(bytes: &[u8]) -> u64
+{
+    bytes.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64)
+}
+
+/// Bits are written from the most significant one of each byte.
+struct BitWriter<'a>
+{
+    buf: &'a mut Vec<u8>,
+    byte: u8,
+    bits: u8,
+}
+
+impl<'a> BitWriter<'a>
+{
+    fn new(buf: &'a mut Vec<u8>) -> BitWriter<'a>
+    {
+        BitWriter { buf, byte: 0, bits: 0 }
+    }
+
+    fn write_bit(&mut self, bit: bool)
+    {
+        self.byte = self.byte << 1 | bit as u8;
+        self.bits += 1;
+        if self.bits == 8 {
+            self.buf.push(self.byte);
+            self.byte = 0;
+            self.bits = 0;
+        }
+    }
+
+    fn write_unary(&mut self, n: u64)
+    {
+        for _ in 0..n {
+            self.write_bit(true);
+        }
+        self.write_bit(false);
+    }
+
+    fn write_bits(&mut self, value: u64, count: u8)
+    {
+        for i in (0..count).rev() {
+            self.write_bit(value >> i & 1 == 1);
+        }
+    }
+
+    /// Pad the last byte with zeros.
+    fn flush(&mut self)
+    {
+        if self.bits > 0 {
+            self.buf.push(self.byte << (8 - self.bits));
+            self.byte = 0;
+            self.bits = 0;
+        }
+    }
+}
+
+struct BitReader<'a>
+{
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a>
+{
+    fn new(buf: &'a [u8]) -> BitReader<'a>
+    {
+        BitReader { buf, pos: 0 }
+    }
+
+    fn read_bit(&mut self) -> Option<bool>
+    {
+        let byte = self.buf.get(self.pos / 8)?;
+        let bit = byte >> (7 - self.pos % 8) & 1 == 1;
+        self.pos += 1;
+        Some(bit)
+    }
+
+    fn read_golomb(&mut self) -> Option<u64>
+    {
+        let mut quotient = 0u64;
+        while self.read_bit()? {
+            quotient += 1;
+        }
+        let mut remainder = 0u64;
+        for _ in 0..P {
+            remainder = remainder << 1 | self.read_bit()? as u64;
+        }
+        Some(quotient << P | remainder)
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+
+    #[test]
+    fn basic_filter_of_testnet_genesis()
+    {
+        // A test vector of BIP158.
+        let block = genesis_block(Network::Testnet);
+        let block_hash = block.bitcoin_hash();
+        let script = block.txdata[0].output[0].script_pubkey.clone();
+        let filter = BlockFilter::basic(&block, &[]);
+        assert_eq!(filter.content, vec![0x01, 0x9d, 0xfc, 0xa8]);
+        assert_eq!(
+            filter.header(&Sha256dHash::default()).be_hex_string(),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+
+        assert!(filter.match_any(&block_hash, vec![&script[..], &[0u8; 3][..]]));
+        assert!(!filter.match_any(&block_hash, vec![&[0u8; 3][..]]));
+    }
+
+    #[test]
+    fn too_many_elements_are_malformed()
+    {
+        let block_hash = Sha256dHash::from_data(b"block");
+        let filter = BlockFilter {
+            content: serialize(&VarInt(u64::max_value())).unwrap(),
+        };
+        assert!(filter.match_any(&block_hash, vec![&b"script"[..]]));
+        assert!(!filter.match_all(&block_hash, vec![&b"script"[..]]));
+    }
+
+    #[test]
+    fn match_many_elements()
+    {
+        let block_hash = Sha256dHash::from_data(b"block");
+        let elements: Vec<Vec<u8>> = (0..200u32).map(|i| serialize(&i).unwrap()).collect();
+        let filter = BlockFilter::new(&block_hash, elements.iter().map(|e| &e[..]));
+
+        assert!(elements.iter().all(|e| filter.match_any(&block_hash, vec![&e[..]])));
+        let absent: Vec<Vec<u8>> = (1000..1010u32).map(|i| serialize(&i).unwrap()).collect();
+        assert!(!filter.match_any(&block_hash, absent.iter().map(|e| &e[..])));
+
+        assert!(filter.match_all(&block_hash, elements.iter().map(|e| &e[..])));
+        let some_absent = elements.iter().chain(absent.iter().take(1));
+        assert!(!filter.match_all(&block_hash, some_absent.map(|e| &e[..])));
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bitcoin::blockdata::{block::Block, script::Script, transaction::OutPoint};
+use bitcoin::network::{encodable::ConsensusDecodable, serialize::{serialize, BitcoinHash, RawDecoder}};
+use bitcoin::util::hash::Sha256dHash;
+use failure::Error;
+
+use super::{BlockFilter, UndoStore};
+
+/// Storage of basic filters and filter headers of blocks, which are served to peers.
+pub trait FilterStore
+{
+    fn get_filter(&self, block_hash: &Sha256dHash) -> Option<BlockFilter>;
+    fn get_filter_header(&self, block_hash: &Sha256dHash) -> Option<Sha256dHash>;
+
+    /// On error, the filter is not stored.
+    fn put_filter(
+        &mut self,
+        block_hash: Sha256dHash,
+        filter: BlockFilter,
+        filter_header: Sha256dHash,
+    ) -> io::Result<()>;
+}
+
+/// Keep all filters on memory.
+#[derive(Debug, Default)]
+pub struct MemoryFilterStore
+{
+    filters: HashMap<Sha256dHash, (BlockFilter, Sha256dHash)>,
+}
+
+impl MemoryFilterStore
+{
+    pub fn new() -> MemoryFilterStore
+    {
+        MemoryFilterStore::default()
+    }
+}
+
+impl FilterStore for MemoryFilterStore
+{
+    fn get_filter(&self, block_hash: &Sha256dHash) -> Option<BlockFilter>
+    {
+        self.filters.get(block_hash).map(|(filter, _)| filter.clone())
+    }
+
+    fn get_filter_header(&self, block_hash: &Sha256dHash) -> Option<Sha256dHash>
+    {
+        self.filters.get(block_hash).map(|(_, header)| *header)
+    }
+
+    fn put_filter(&mut self, block_hash: Sha256dHash, filter: BlockFilter, filter_header: Sha256dHash) -> io::Result<()>
+    {
+        self.filters.insert(block_hash, (filter, filter_header));
+        Ok(())
+    }
+}
+
+/// Append filters to a file, and keep only their positions and filter headers on memory.
+/// Each record is a block hash, a filter header and a filter content with its length.
+#[derive(Debug)]
+pub struct FileFilterStore
+{
+    file: File,
+    // Filter headers are small and read on every block, so they are kept with positions of records.
+    records: HashMap<Sha256dHash, (u64, Sha256dHash)>,
+}
+
+impl FileFilterStore
+{
+    /// Open or create the file. A broken record at the end, e.g. by crash on writing, is truncated.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<FileFilterStore>
+    {
+        let file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut records = HashMap::new();
+        let mut reader = BufReader::new(&file);
+        let mut valid_len = 0;
+        while valid_len < file_len {
+            match decode_record(&mut RawDecoder::new(&mut reader)) {
+                Ok((block_hash, filter_header, _)) => {
+                    records.insert(block_hash, (valid_len, filter_header));
+                    valid_len = reader.stream_position()?;
+                },
+                Err(e) => {
+                    warn!("Truncate broken filter record at {} : {:?}", valid_len, e);
+                    break;
+                },
+            }
+        }
+        drop(reader);
+        file.set_len(valid_len)?;
+        Ok(FileFilterStore { file, records })
+    }
+}
+
+impl FilterStore for FileFilterStore
+{
+    fn get_filter(&self, block_hash: &Sha256dHash) -> Option<BlockFilter>
+    {
+        let (position, _) = *self.records.get(block_hash)?;
+        let mut file = &self.file;
+        let record = file
+            .seek(SeekFrom::Start(position))
+            .map_err(Error::from)
+            .and_then(|_| decode_record(&mut RawDecoder::new(BufReader::new(file))));
+        match record {
+            Ok((_, _, filter)) => Some(filter),
+            Err(e) => {
+                warn!("Fail to read filter of {} : {:?}", block_hash, e);
+                None
+            },
+        }
+    }
+
+    fn get_filter_header(&self, block_hash: &Sha256dHash) -> Option<Sha256dHash>
+    {
+        self.records.get(block_hash).map(|(_, header)| *header)
+    }
+
+    fn put_filter(&mut self, block_hash: Sha256dHash, filter: BlockFilter, filter_header: Sha256dHash) -> io::Result<()>
+    {
+        if self.records.contains_key(&block_hash) {
+            return Ok(());
+        }
+        // Never fail
+        let mut record = serialize(&block_hash).unwrap();
+        record.extend_from_slice(&serialize(&filter_header).unwrap());
+        record.extend_from_slice(&serialize(&filter.content).unwrap());
+        let position = self.file.seek(SeekFrom::End(0))?;
+        if let Err(e) = self.file.write_all(&record).and_then(|()| self.file.flush()) {
+            warn!("Fail to write filter of {} : {:?}", block_hash, e);
+            // Records after a partial one would be truncated on next open.
+            if let Err(e) = self.file.set_len(position) {
+                warn!("Fail to truncate partial filter record at {} : {:?}", position, e);
+            }
+            return Err(e);
+        }
+        self.records.insert(block_hash, (position, filter_header));
+        Ok(())
+    }
+}
+
+fn decode_record<R: Read>(decoder: &mut RawDecoder<R>) -> Result<(Sha256dHash, Sha256dHash, BlockFilter), Error>
+{
+    Ok((
+        ConsensusDecodable::consensus_decode(decoder)?,
+        ConsensusDecodable::consensus_decode(decoder)?,
+        BlockFilter {
+            content: ConsensusDecodable::consensus_decode(decoder)?,
+        },
+    ))
+}
+
+#[derive(Debug)]
+pub enum FilterIndexError
+{
+    /// Filter header of the previous block is not stored yet.
+    NotFoundPrevFilter(Sha256dHash),
+
+    /// The number of spent scripts differs from that of inputs of the block except coinbase.
+    InvalidUndo(Sha256dHash),
+
+    /// Undo data of the block is not stored.
+    NotFoundUndo(Sha256dHash),
+
+    /// `FilterStore` fails to store the filter.
+    Store(io::Error),
+}
+
+/// Compute basic filters of full blocks and put them to the store.
+///
+/// Filters include scripts which inputs spend, so caller gives them as undo data of each block,
+/// e.g. from its UTXO set. Undo data is kept in `UndoStore` rather than on memory,
+/// so that blocks can be disconnected even after restart.
+pub struct FilterIndex
+{
+    filters: Arc<Mutex<dyn FilterStore + Send>>,
+    undo: Arc<Mutex<dyn UndoStore + Send>>,
+}
+
+impl FilterIndex
+{
+    pub fn new(filters: Arc<Mutex<dyn FilterStore + Send>>, undo: Arc<Mutex<dyn UndoStore + Send>>) -> FilterIndex
+    {
+        FilterIndex { filters, undo }
+    }
+
+    /// Compute and store the filter of the block, and keep `spent_scripts` as its undo data.
+    /// `spent_scripts` are scripts of outputs which inputs spend, in order of inputs except coinbase.
+    /// Returns its filter header. On error, nothing is changed.
+    pub fn connect_block(&mut self, block: &Block, spent_scripts: Vec<Script>) -> Result<Sha256dHash, FilterIndexError>
+    {
+        let block_hash = block.bitcoin_hash();
+        let num_inputs: usize = block.txdata.iter().skip(1).map(|tx| tx.input.len()).sum();
+        if spent_scripts.len() != num_inputs {
+            return Err(FilterIndexError::InvalidUndo(block_hash));
+        }
+
+        let prev_filter_header = if block.header.prev_blockhash == Sha256dHash::default() {
+            Sha256dHash::default()
+        } else {
+            let prev = self.filters.lock().unwrap().get_filter_header(&block.header.prev_blockhash);
+            prev.ok_or(FilterIndexError::NotFoundPrevFilter(block.header.prev_blockhash))?
+        };
+
+        let filter = BlockFilter::basic(block, &spent_scripts);
+        let filter_header = filter.header(&prev_filter_header);
+        self.filters
+            .lock()
+            .unwrap()
+            .put_filter(block_hash, filter, filter_header)
+            .map_err(FilterIndexError::Store)?;
+        self.undo.lock().unwrap().put_undo(block_hash, spent_scripts);
+        Ok(filter_header)
+    }
+
+    /// Undo `connect_block` of the tip on reorg. Returns outputs which the block spends, so that caller restores them.
+    /// Stored filters and undo data are kept, since they are indexed by block hash.
+    pub fn disconnect_block(&mut self, block: &Block) -> Result<Vec<(OutPoint, Script)>, FilterIndexError>
+    {
+        let block_hash = block.bitcoin_hash();
+        let spent_scripts = self.undo.lock().unwrap().get_undo(&block_hash);
+        let spent_scripts = spent_scripts.ok_or(FilterIndexError::NotFoundUndo(block_hash))?;
+        let outpoints = block.txdata.iter().skip(1).flat_map(|tx| tx.input.iter()).map(|input| input.previous_output);
+        Ok(outpoints.zip(spent_scripts).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::blockdata::transaction::{Transaction, TxIn, TxOut};
+    use bitcoin::network::constants::Network;
+    use blockchain::{FileUndoStore, MemoryUndoStore};
+
+    fn temp_path(name: &str) -> ::std::path::PathBuf
+    {
+        let path = ::std::env::temp_dir().join(format!("{}-{}.dat", name, ::std::process::id()));
+        let _ = ::std::fs::remove_file(&path);
+        path
+    }
+
+    // A block on genesis which spends the output of genesis coinbase.
+    fn spending_block(genesis: &Block) -> Block
+    {
+        let tx = |previous_output: OutPoint, script: Vec<u8>| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output,
+                script_sig: Script::new(),
+                sequence: 0xFFFF_FFFF,
+                witness: Vec::new(),
+            }],
+            output: vec![TxOut {
+                value: 50,
+                script_pubkey: Script::from(script),
+            }],
+        };
+        let coinbase = tx(OutPoint::default(), vec![0x51]);
+        let spend = tx(OutPoint { txid: genesis.txdata[0].txid(), vout: 0 }, vec![0x52]);
+        let mut header = genesis.header;
+        header.prev_blockhash = genesis.bitcoin_hash();
+        Block {
+            header,
+            txdata: vec![coinbase, spend],
+        }
+    }
+
+    #[test]
+    fn index_genesis_and_reopen_file_store()
+    {
+        let path = temp_path("filters");
+        let block = genesis_block(Network::Testnet);
+
+        let store = Arc::new(Mutex::new(FileFilterStore::open(&path).unwrap()));
+        let undo = Arc::new(Mutex::new(MemoryUndoStore::new()));
+        let filter_header = FilterIndex::new(store.clone(), undo).connect_block(&block, Vec::new()).unwrap();
+        assert_eq!(
+            filter_header.be_hex_string(),
+            "21584579b7eb08997773e5aeff3a7f932700042d0ed2a6129012b7d7ae81b750"
+        );
+        let filter = store.lock().unwrap().get_filter(&block.bitcoin_hash()).unwrap();
+        drop(store);
+
+        let reopened = FileFilterStore::open(&path).unwrap();
+        assert_eq!(reopened.get_filter(&block.bitcoin_hash()), Some(filter));
+        assert_eq!(reopened.get_filter_header(&block.bitcoin_hash()), Some(filter_header));
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn fail_to_put_filter_on_write_error()
+    {
+        let path = temp_path("readonly-filters");
+        let block = genesis_block(Network::Testnet);
+        let mut store = FileFilterStore::open(&path).unwrap();
+        store.file = File::open(&path).unwrap();
+
+        let filter = BlockFilter::basic(&block, &[]);
+        assert!(store.put_filter(block.bitcoin_hash(), filter, Sha256dHash::default()).is_err());
+        assert_eq!(store.get_filter_header(&block.bitcoin_hash()), None);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn include_spent_scripts_in_filter()
+    {
+        let genesis = genesis_block(Network::Testnet);
+        let block = spending_block(&genesis);
+        let spent_script = genesis.txdata[0].output[0].script_pubkey.clone();
+
+        let store = Arc::new(Mutex::new(MemoryFilterStore::new()));
+        let undo = Arc::new(Mutex::new(MemoryUndoStore::new()));
+        let mut index = FilterIndex::new(store.clone(), undo);
+        let genesis_header = index.connect_block(&genesis, Vec::new()).unwrap();
+
+        match index.connect_block(&block, Vec::new()) {
+            Err(FilterIndexError::InvalidUndo(hash)) => assert_eq!(hash, block.bitcoin_hash()),
+            res => panic!("unexpected result : {:?}", res),
+        }
+        assert_eq!(store.lock().unwrap().get_filter(&block.bitcoin_hash()), None);
+
+        let filter_header = index.connect_block(&block, vec![spent_script.clone()]).unwrap();
+        let filter = store.lock().unwrap().get_filter(&block.bitcoin_hash()).unwrap();
+        assert_eq!(filter, BlockFilter::basic(&block, &[spent_script.clone()]));
+        assert_eq!(filter_header, filter.header(&genesis_header));
+        assert!(filter.match_any(&block.bitcoin_hash(), vec![&spent_script[..]]));
+    }
+
+    #[test]
+    fn disconnect_block_after_restart()
+    {
+        let path = temp_path("undo");
+        let genesis = genesis_block(Network::Testnet);
+        let block = spending_block(&genesis);
+        let spent_script = genesis.txdata[0].output[0].script_pubkey.clone();
+
+        let store = Arc::new(Mutex::new(MemoryFilterStore::new()));
+        {
+            let undo = Arc::new(Mutex::new(FileUndoStore::open(&path).unwrap()));
+            let mut index = FilterIndex::new(store.clone(), undo);
+            index.connect_block(&genesis, Vec::new()).unwrap();
+            index.connect_block(&block, vec![spent_script.clone()]).unwrap();
+        }
+
+        let undo = Arc::new(Mutex::new(FileUndoStore::open(&path).unwrap()));
+        let mut index = FilterIndex::new(store.clone(), undo);
+        let spent = index.disconnect_block(&block).unwrap();
+        assert_eq!(spent, vec![(block.txdata[1].input[0].previous_output, spent_script)]);
+        assert_eq!(index.disconnect_block(&genesis).unwrap(), Vec::new());
+
+        // Filters are kept in case the block is connected again.
+        assert!(store.lock().unwrap().get_filter(&block.bitcoin_hash()).is_some());
+        let unknown = spending_block(&block);
+        match index.disconnect_block(&unknown) {
+            Err(FilterIndexError::NotFoundUndo(hash)) => assert_eq!(hash, unknown.bitcoin_hash()),
+            res => panic!("unexpected result : {:?}", res),
+        }
+        ::std::fs::remove_file(&path).unwrap();
+    }
+}
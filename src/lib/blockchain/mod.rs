@@ -1,8 +1,14 @@
 mod blockchain;
 mod block;
+mod store;
+mod filter;
+mod filter_store;
 
 pub use self::blockchain::BlockChain;
 pub use self::block::{BlockData, BlockDataLike, FullBlockData};
+pub use self::store::{BlockStore, FileUndoStore, MemoryBlockStore, MemoryUndoStore, UndoStore};
+pub use self::filter::{filter_header, BlockFilter, BASIC_FILTER_TYPE};
+pub use self::filter_store::{FileFilterStore, FilterIndex, FilterIndexError, FilterStore, MemoryFilterStore};
 
 use bitcoin::blockdata::block::BlockHeader;
 
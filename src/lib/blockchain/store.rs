@@ -0,0 +1,168 @@
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use bitcoin::blockdata::{block::Block, script::Script};
+use bitcoin::network::{encodable::ConsensusDecodable, serialize::{serialize, BitcoinHash, RawDecoder}};
+use bitcoin::util::hash::Sha256dHash;
+use failure::Error;
+
+/// Storage of full blocks, which are served to peers.
+/// `BlockChain` keeps only headers, so blocks are stored separately.
+pub trait BlockStore
+{
+    fn get_block(&self, hash: &Sha256dHash) -> Option<Block>;
+    fn put_block(&mut self, block: Block);
+}
+
+/// Keep all blocks on memory.
+#[derive(Debug, Default)]
+pub struct MemoryBlockStore
+{
+    blocks: HashMap<Sha256dHash, Block>,
+}
+
+impl MemoryBlockStore
+{
+    pub fn new() -> MemoryBlockStore
+    {
+        MemoryBlockStore::default()
+    }
+}
+
+impl BlockStore for MemoryBlockStore
+{
+    fn get_block(&self, hash: &Sha256dHash) -> Option<Block>
+    {
+        self.blocks.get(hash).cloned()
+    }
+
+    fn put_block(&mut self, block: Block)
+    {
+        self.blocks.insert(block.bitcoin_hash(), block);
+    }
+}
+
+/// Storage of undo data of blocks, i.e. scripts of outputs which inputs of each block spend.
+/// Scripts are in order of inputs except coinbase.
+/// They are needed to compute filters of blocks, and to restore spent outputs on reorg.
+pub trait UndoStore
+{
+    fn get_undo(&self, block_hash: &Sha256dHash) -> Option<Vec<Script>>;
+    fn put_undo(&mut self, block_hash: Sha256dHash, spent_scripts: Vec<Script>);
+}
+
+/// Keep all undo data on memory.
+#[derive(Debug, Default)]
+pub struct MemoryUndoStore
+{
+    undo: HashMap<Sha256dHash, Vec<Script>>,
+}
+
+impl MemoryUndoStore
+{
+    pub fn new() -> MemoryUndoStore
+    {
+        MemoryUndoStore::default()
+    }
+}
+
+impl UndoStore for MemoryUndoStore
+{
+    fn get_undo(&self, block_hash: &Sha256dHash) -> Option<Vec<Script>>
+    {
+        self.undo.get(block_hash).cloned()
+    }
+
+    fn put_undo(&mut self, block_hash: Sha256dHash, spent_scripts: Vec<Script>)
+    {
+        self.undo.insert(block_hash, spent_scripts);
+    }
+}
+
+/// Append undo data to a file, and keep only their positions on memory.
+/// Each record is a block hash and scripts with their number.
+#[derive(Debug)]
+pub struct FileUndoStore
+{
+    file: File,
+    positions: HashMap<Sha256dHash, u64>,
+}
+
+impl FileUndoStore
+{
+    /// Open or create the file. A broken record at the end, e.g. by crash on writing, is truncated.
+    pub fn open<P: AsRef<Path>>(path: P) -> ::std::io::Result<FileUndoStore>
+    {
+        let file = OpenOptions::new().read(true).write(true).create(true).open(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut positions = HashMap::new();
+        let mut reader = BufReader::new(&file);
+        let mut valid_len = 0;
+        while valid_len < file_len {
+            match decode_undo_record(&mut RawDecoder::new(&mut reader)) {
+                Ok((block_hash, _)) => {
+                    positions.insert(block_hash, valid_len);
+                    valid_len = reader.stream_position()?;
+                },
+                Err(e) => {
+                    warn!("Truncate broken undo record at {} : {:?}", valid_len, e);
+                    break;
+                },
+            }
+        }
+        drop(reader);
+        file.set_len(valid_len)?;
+        Ok(FileUndoStore { file, positions })
+    }
+}
+
+impl UndoStore for FileUndoStore
+{
+    fn get_undo(&self, block_hash: &Sha256dHash) -> Option<Vec<Script>>
+    {
+        let position = *self.positions.get(block_hash)?;
+        let mut file = &self.file;
+        let record = file
+            .seek(SeekFrom::Start(position))
+            .map_err(Error::from)
+            .and_then(|_| decode_undo_record(&mut RawDecoder::new(BufReader::new(file))));
+        match record {
+            Ok((_, spent_scripts)) => Some(spent_scripts),
+            Err(e) => {
+                warn!("Fail to read undo data of {} : {:?}", block_hash, e);
+                None
+            },
+        }
+    }
+
+    fn put_undo(&mut self, block_hash: Sha256dHash, spent_scripts: Vec<Script>)
+    {
+        if self.positions.contains_key(&block_hash) {
+            return;
+        }
+        // Never fail
+        let mut record = serialize(&block_hash).unwrap();
+        record.extend_from_slice(&serialize(&spent_scripts).unwrap());
+        let written = self
+            .file
+            .seek(SeekFrom::End(0))
+            .and_then(|position| self.file.write_all(&record).and_then(|()| self.file.flush()).map(|()| position));
+        match written {
+            Ok(position) => {
+                self.positions.insert(block_hash, position);
+            },
+            Err(e) => warn!("Fail to write undo data of {} : {:?}", block_hash, e),
+        }
+    }
+}
+
+fn decode_undo_record<R: Read>(decoder: &mut RawDecoder<R>) -> Result<(Sha256dHash, Vec<Script>), Error>
+{
+    Ok((
+        ConsensusDecodable::consensus_decode(decoder)?,
+        ConsensusDecodable::consensus_decode(decoder)?,
+    ))
+}
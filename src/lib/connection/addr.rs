@@ -0,0 +1,313 @@
+use std::{io::Cursor, net::{Ipv4Addr, Ipv6Addr, SocketAddr}};
+
+use bitcoin::network::{address::Address, encodable::{ConsensusDecodable, VarInt},
+                       serialize::{serialize, RawDecoder}};
+use crypto::{digest::Digest, sha3::Sha3};
+use failure::Error;
+
+use connection::{error::ConnectionError, socks5::TargetAddr};
+
+/// Maximum number of entries in `addr` and `addrv2` message.
+pub const MAX_ADDR_ENTRIES: u32 = 1_000;
+
+/// Maximum length of an address in `addrv2` message.
+pub const MAX_ADDRV2_ADDR_SIZE: u32 = 512;
+
+// Network IDs defined in BIP155. Tor v2 (3) is obsolete, so it is treated as unknown.
+const NET_IPV4: u8 = 1;
+const NET_IPV6: u8 = 2;
+const NET_TORV3: u8 = 4;
+const NET_I2P: u8 = 5;
+const NET_CJDNS: u8 = 6;
+
+const TORV3_VERSION: u8 = 3;
+
+/// Network address of a peer.
+/// Unlike `Address` of bitcoin crate, it covers networks which `addrv2` (BIP155) introduces.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum NetAddr
+{
+    Ipv4(Ipv4Addr),
+    Ipv6(Ipv6Addr),
+
+    /// Ed25519 public key of a Tor v3 hidden service.
+    TorV3([u8; 32]),
+
+    /// SHA256 hash of an I2P destination.
+    I2p([u8; 32]),
+
+    /// CJDNS address, which is in fc00::/8.
+    Cjdns(Ipv6Addr),
+}
+
+/// An entry of `addr` or `addrv2` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerAddr
+{
+    /// Last time the peer is seen.
+    pub time: u32,
+    pub services: u64,
+    pub addr: NetAddr,
+    pub port: u16,
+}
+
+impl NetAddr
+{
+    /// Returns true if we can connect to it without proxy.
+    /// CJDNS address looks like IPv6, but it is reachable only in CJDNS network.
+    pub fn is_ip(&self) -> bool
+    {
+        match self {
+            NetAddr::Ipv4(_) | NetAddr::Ipv6(_) => true,
+            NetAddr::TorV3(_) | NetAddr::I2p(_) | NetAddr::Cjdns(_) => false,
+        }
+    }
+
+    /// Tor and I2P addresses become domain names which only a proxy can resolve.
+    pub fn to_target(&self, port: u16) -> TargetAddr
+    {
+        match self {
+            NetAddr::Ipv4(ip) => TargetAddr::Ip(SocketAddr::new((*ip).into(), port)),
+            NetAddr::Ipv6(ip) | NetAddr::Cjdns(ip) => TargetAddr::Ip(SocketAddr::new((*ip).into(), port)),
+            NetAddr::TorV3(pubkey) => TargetAddr::Domain(onion_v3_name(pubkey), port),
+            NetAddr::I2p(hash) => TargetAddr::Domain(format!("{}.b32.i2p", base32(hash)), port),
+        }
+    }
+
+    fn network_id(&self) -> u8
+    {
+        match self {
+            NetAddr::Ipv4(_) => NET_IPV4,
+            NetAddr::Ipv6(_) => NET_IPV6,
+            NetAddr::TorV3(_) => NET_TORV3,
+            NetAddr::I2p(_) => NET_I2P,
+            NetAddr::Cjdns(_) => NET_CJDNS,
+        }
+    }
+
+    fn to_bytes(&self) -> Vec<u8>
+    {
+        match self {
+            NetAddr::Ipv4(ip) => ip.octets().to_vec(),
+            NetAddr::Ipv6(ip) | NetAddr::Cjdns(ip) => ip.octets().to_vec(),
+            NetAddr::TorV3(bytes) | NetAddr::I2p(bytes) => bytes.to_vec(),
+        }
+    }
+
+    /// Returns `Ok(None)` if network ID is unknown. BIP155 requires us to ignore such address.
+    fn from_bytes(network_id: u8, bytes: &[u8]) -> Result<Option<NetAddr>, Error>
+    {
+        let expected_len = match network_id {
+            NET_IPV4 => 4,
+            NET_IPV6 | NET_CJDNS => 16,
+            NET_TORV3 | NET_I2P => 32,
+            _ => return Ok(None),
+        };
+        if bytes.len() != expected_len {
+            info!("Invalid address length {} for network {}", bytes.len(), network_id);
+            return Err(Error::from(ConnectionError::MisbehavePeer));
+        }
+
+        let mut buf = [0u8; 32];
+        buf[..expected_len].copy_from_slice(bytes);
+        let addr = match network_id {
+            NET_IPV4 => NetAddr::Ipv4(Ipv4Addr::new(buf[0], buf[1], buf[2], buf[3])),
+            NET_IPV6 => NetAddr::Ipv6(ipv6_from_slice(bytes)),
+            NET_CJDNS => NetAddr::Cjdns(ipv6_from_slice(bytes)),
+            NET_TORV3 => NetAddr::TorV3(buf),
+            NET_I2P => NetAddr::I2p(buf),
+            _ => unreachable!(),
+        };
+        Ok(Some(addr))
+    }
+}
+
+impl PeerAddr
+{
+    /// Convert an entry of legacy `addr` message.
+    pub fn from_legacy(time: u32, addr: &Address) -> PeerAddr
+    {
+        let a = addr.address;
+        let ipv6 = Ipv6Addr::new(a[0], a[1], a[2], a[3], a[4], a[5], a[6], a[7]);
+        let net_addr = match a {
+            [0, 0, 0, 0, 0, 0xffff, _, _] => NetAddr::Ipv4(Ipv4Addr::from((a[6] as u32) << 16 | a[7] as u32)),
+            _ => NetAddr::Ipv6(ipv6),
+        };
+        PeerAddr {
+            time,
+            services: addr.services,
+            addr: net_addr,
+            port: addr.port,
+        }
+    }
+
+    pub fn to_target(&self) -> TargetAddr
+    {
+        self.addr.to_target(self.port)
+    }
+}
+
+/// Decode a payload of `addrv2` message.
+/// Entries whose network is unknown are skipped.
+pub fn decode_addrv2(src: &[u8]) -> Result<Vec<PeerAddr>, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+
+    let VarInt(count) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    if count > MAX_ADDR_ENTRIES as u64 {
+        info!("Too many addrv2 entries : {}", count);
+        return Err(Error::from(ConnectionError::MisbehavePeer));
+    }
+
+    let mut addrs = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let time: u32 = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        let VarInt(services) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        let network_id: u8 = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        // Check the length before allocating a buffer.
+        let VarInt(len) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        if len > MAX_ADDRV2_ADDR_SIZE as u64 {
+            info!("Too long addrv2 address : {} bytes", len);
+            return Err(Error::from(ConnectionError::MisbehavePeer));
+        }
+        let bytes = (0..len)
+            .map(|_| ConsensusDecodable::consensus_decode(&mut decoder))
+            .collect::<Result<Vec<u8>, _>>()?;
+        // Port is big endian unlike any other fields.
+        let port: [u8; 2] = ConsensusDecodable::consensus_decode(&mut decoder)?;
+
+        if let Some(addr) = NetAddr::from_bytes(network_id, &bytes)? {
+            addrs.push(PeerAddr {
+                time,
+                services,
+                addr,
+                port: (port[0] as u16) << 8 | port[1] as u16,
+            });
+        }
+    }
+    Ok(addrs)
+}
+
+/// Encode a payload of `addrv2` message.
+pub fn encode_addrv2(addrs: &[PeerAddr]) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&VarInt(addrs.len() as u64)).unwrap();
+    for addr in addrs {
+        buf.extend_from_slice(&serialize(&addr.time).unwrap());
+        buf.extend_from_slice(&serialize(&VarInt(addr.services)).unwrap());
+        buf.push(addr.addr.network_id());
+        buf.extend_from_slice(&serialize(&addr.addr.to_bytes()).unwrap());
+        buf.push((addr.port >> 8) as u8);
+        buf.push(addr.port as u8);
+    }
+    buf
+}
+
+fn ipv6_from_slice(bytes: &[u8]) -> Ipv6Addr
+{
+    let mut octets = [0u8; 16];
+    octets.copy_from_slice(bytes);
+    Ipv6Addr::from(octets)
+}
+
+/// "<base32 of pubkey, checksum and version>.onion" defined in Tor's rend-spec-v3.
+fn onion_v3_name(pubkey: &[u8; 32]) -> String
+{
+    let mut hasher = Sha3::sha3_256();
+    hasher.input(b".onion checksum");
+    hasher.input(pubkey);
+    hasher.input(&[TORV3_VERSION]);
+    let mut checksum = [0u8; 32];
+    hasher.result(&mut checksum);
+
+    let mut bytes = pubkey.to_vec();
+    bytes.extend_from_slice(&checksum[..2]);
+    bytes.push(TORV3_VERSION);
+    format!("{}.onion", base32(&bytes))
+}
+
+/// Lower case base32 (RFC 4648) without padding.
+fn base32(bytes: &[u8]) -> String
+{
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+    let mut s = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buf: u16 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buf = buf << 8 | *byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            s.push(ALPHABET[(buf >> bits) as usize & 0x1f] as char);
+        }
+    }
+    if bits > 0 {
+        s.push(ALPHABET[(buf << (5 - bits)) as usize & 0x1f] as char);
+    }
+    s
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn onion_v3_name_of_pubkey()
+    {
+        let pubkey = [
+            0xd1, 0xb3, 0x8b, 0x83, 0xa8, 0x3b, 0x3e, 0xd9, 0x18, 0xc5, 0xbb, 0x69, 0xdd, 0x44, 0x4a, 0xd5, 0x6b, 0xc8,
+            0xd5, 0x83, 0x5a, 0x91, 0x4d, 0xe7, 0x34, 0x47, 0x47, 0x4e, 0x5f, 0x02, 0x59, 0x1b,
+        ];
+        let expected = "2gzyxa5ihm7nsggfxnu52rck2vv4rvmdlkiu3zzui5du4xyclen53wid.onion";
+        assert_eq!(NetAddr::TorV3(pubkey).to_target(8333), TargetAddr::Domain(expected.into(), 8333));
+    }
+
+    #[test]
+    fn addrv2_roundtrip_skipping_unknown_network()
+    {
+        let addrs = vec![
+            PeerAddr {
+                time: 1,
+                services: 1 << 10,
+                addr: NetAddr::Ipv4(Ipv4Addr::new(1, 2, 3, 4)),
+                port: 8333,
+            },
+            PeerAddr {
+                time: 2,
+                services: 1,
+                addr: NetAddr::TorV3([7; 32]),
+                port: 8333,
+            },
+            PeerAddr {
+                time: 3,
+                services: 0,
+                addr: NetAddr::Cjdns("fc00::1".parse().unwrap()),
+                port: 18333,
+            },
+        ];
+        let mut encoded = encode_addrv2(&addrs);
+
+        // Append an entry of Tor v2, which we do not understand.
+        encoded[0] += 1;
+        encoded.extend_from_slice(&[0, 0, 0, 0, 0, 3, 10]);
+        encoded.extend_from_slice(&[0; 10]);
+        encoded.extend_from_slice(&[0x20, 0x8d]);
+
+        assert_eq!(decode_addrv2(&encoded).unwrap(), addrs);
+    }
+
+    #[test]
+    fn reject_too_long_addrv2_address_before_reading_it()
+    {
+        // One entry claiming a 513 bytes address, without the bytes.
+        let encoded = [1, 0, 0, 0, 0, 0, NET_IPV6, 0xfd, 0x01, 0x02];
+        let err = decode_addrv2(&encoded).unwrap_err();
+        match err.downcast::<ConnectionError>() {
+            Ok(ConnectionError::MisbehavePeer) => (),
+            other => panic!("unexpected result : {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,210 @@
+use crypto::{chacha20::ChaCha20, mac::Mac, poly1305::Poly1305, symmetriccipher::SynchronousStreamCipher,
+             util::fixed_time_eq};
+
+/// Both ciphers change their keys after this number of messages.
+const REKEY_INTERVAL: u32 = 224;
+
+pub const TAG_SIZE: usize = 16;
+
+/// FSChaCha20 of BIP324, which encrypts 3 bytes length of each packet.
+/// Keystream continues over packets.
+pub struct LengthCipher
+{
+    chacha: ChaCha20,
+    chunk_counter: u32,
+    rekey_counter: u64,
+}
+
+/// FSChaCha20Poly1305 of BIP324, which encrypts contents of each packet.
+pub struct PacketCipher
+{
+    key: [u8; 32],
+    packet_counter: u32,
+    rekey_counter: u64,
+}
+
+impl LengthCipher
+{
+    pub fn new(key: [u8; 32]) -> LengthCipher
+    {
+        LengthCipher {
+            chacha: ChaCha20::new(&key, &nonce(0, 0)),
+            chunk_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    /// Encryption and decryption are same operations.
+    pub fn crypt(&mut self, input: &[u8; 3]) -> [u8; 3]
+    {
+        let mut output = [0u8; 3];
+        self.chacha.process(input, &mut output);
+
+        self.chunk_counter += 1;
+        if self.chunk_counter == REKEY_INTERVAL {
+            let mut key = [0u8; 32];
+            self.chacha.process(&[0u8; 32], &mut key);
+            self.chunk_counter = 0;
+            self.rekey_counter += 1;
+            self.chacha = ChaCha20::new(&key, &nonce(0, self.rekey_counter));
+        }
+        output
+    }
+}
+
+impl PacketCipher
+{
+    pub fn new(key: [u8; 32]) -> PacketCipher
+    {
+        PacketCipher {
+            key,
+            packet_counter: 0,
+            rekey_counter: 0,
+        }
+    }
+
+    /// Returns ciphertext followed by a tag.
+    pub fn encrypt(&mut self, aad: &[u8], plaintext: &[u8]) -> Vec<u8>
+    {
+        let (mut chacha, poly_key) = self.next_packet();
+        let mut output = vec![0u8; plaintext.len() + TAG_SIZE];
+        chacha.process(plaintext, &mut output[..plaintext.len()]);
+        let tag = poly1305_tag(&poly_key, aad, &output[..plaintext.len()]);
+        output[plaintext.len()..].copy_from_slice(&tag);
+        output
+    }
+
+    /// Returns `None` if a tag does not match.
+    ///
+    /// # Panic
+    /// If `ciphertext` is shorter than a tag.
+    pub fn decrypt(&mut self, aad: &[u8], ciphertext: &[u8]) -> Option<Vec<u8>>
+    {
+        let (mut chacha, poly_key) = self.next_packet();
+        let (ciphertext, tag) = ciphertext.split_at(ciphertext.len() - TAG_SIZE);
+        if !fixed_time_eq(&poly1305_tag(&poly_key, aad, ciphertext), tag) {
+            return None;
+        }
+        let mut output = vec![0u8; ciphertext.len()];
+        chacha.process(ciphertext, &mut output);
+        Some(output)
+    }
+
+    /// Decrypt the beginning of the next packet without authentication, and without stepping counters.
+    /// It is only for checking the header before whole packet arrives.
+    pub fn peek(&self, ciphertext: &[u8]) -> Vec<u8>
+    {
+        let mut chacha = ChaCha20::new(&self.key, &nonce(self.packet_counter, self.rekey_counter));
+        chacha.process(&[0u8; 64], &mut [0u8; 64]);
+        let mut output = vec![0u8; ciphertext.len()];
+        chacha.process(ciphertext, &mut output);
+        output
+    }
+
+    // Returns a cipher for a packet and a key of Poly1305 (RFC 8439), and then step counters.
+    fn next_packet(&mut self) -> (ChaCha20, [u8; 32])
+    {
+        let mut chacha = ChaCha20::new(&self.key, &nonce(self.packet_counter, self.rekey_counter));
+        let mut first_block = [0u8; 64];
+        chacha.process(&[0u8; 64], &mut first_block);
+        let mut poly_key = [0u8; 32];
+        poly_key.copy_from_slice(&first_block[..32]);
+
+        self.packet_counter += 1;
+        if self.packet_counter == REKEY_INTERVAL {
+            // The new key is 32 zero bytes encrypted by AEAD, whose keystream starts at the second block
+            // since the first one is taken for Poly1305.
+            let mut rekey_chacha = ChaCha20::new(&self.key, &nonce(0xFFFF_FFFF, self.rekey_counter));
+            rekey_chacha.process(&[0u8; 64], &mut [0u8; 64]);
+            rekey_chacha.process(&[0u8; 32], &mut self.key);
+            self.packet_counter = 0;
+            self.rekey_counter += 1;
+        }
+        (chacha, poly_key)
+    }
+}
+
+fn nonce(first: u32, second: u64) -> [u8; 12]
+{
+    let mut nonce = [0u8; 12];
+    for i in 0..4 {
+        nonce[i] = (first >> (i * 8)) as u8;
+    }
+    for i in 0..8 {
+        nonce[4 + i] = (second >> (i * 8)) as u8;
+    }
+    nonce
+}
+
+fn poly1305_tag(key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> [u8; TAG_SIZE]
+{
+    let pad = |len: usize| vec![0u8; (16 - len % 16) % 16];
+    let mut poly = Poly1305::new(key);
+    poly.input(aad);
+    poly.input(&pad(aad.len()));
+    poly.input(ciphertext);
+    poly.input(&pad(ciphertext.len()));
+    poly.input(&le64(aad.len() as u64));
+    poly.input(&le64(ciphertext.len() as u64));
+
+    let mut tag = [0u8; TAG_SIZE];
+    poly.raw_result(&mut tag);
+    tag
+}
+
+fn le64(n: u64) -> [u8; 8]
+{
+    let mut bytes = [0u8; 8];
+    for i in 0..8 {
+        bytes[i] = (n >> (i * 8)) as u8;
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::util::misc::hex_bytes;
+
+    #[test]
+    fn aead_matches_rfc8439()
+    {
+        // The AEAD test vector of RFC 8439 section 2.8.2, whose nonce is 07000000 4041424344454647.
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&hex_bytes("808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f").unwrap());
+        let mut cipher = PacketCipher {
+            key,
+            packet_counter: 7,
+            rekey_counter: 0x4746_4544_4342_4140,
+        };
+        let aad = hex_bytes("50515253c0c1c2c3c4c5c6c7").unwrap();
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, \
+                          sunscreen would be it.";
+        let expected = hex_bytes(
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d63dbea45e8ca9671282fafb69da92728b\
+             1a71de0a9e060b2905d6a5b67ecd3b3692ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+             3ff4def08e4b7a9de576d26586cec64b6116\
+             1ae10b594f09e26a7e902ecbd0600691",
+        ).unwrap();
+        assert_eq!(cipher.encrypt(&aad, plaintext), expected);
+    }
+
+    #[test]
+    fn packet_cipher_rekeys_with_aead_of_zeros()
+    {
+        let key = [0x42u8; 32];
+        let mut cipher = PacketCipher::new(key);
+        for _ in 0..REKEY_INTERVAL {
+            cipher.encrypt(&[], &[0u8; 3]);
+        }
+        assert_eq!(cipher.rekey_counter, 1);
+
+        // BIP324: the new key is the first 32 bytes of AEAD ciphertext of 32 zero bytes
+        // under nonce (0xffffffff, rekey counter), i.e. the keystream following the Poly1305 key.
+        let mut keystream = [0u8; 96];
+        ChaCha20::new(&key, &nonce(0xFFFF_FFFF, 0)).process(&[0u8; 96], &mut keystream);
+        let encrypted_zeros = &keystream[64..];
+        assert_eq!(&cipher.key[..], encrypted_zeros);
+    }
+}
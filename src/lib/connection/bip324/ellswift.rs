@@ -0,0 +1,348 @@
+use rand::{thread_rng, RngCore};
+use secp256k1::{key::{PublicKey, SecretKey}, Secp256k1};
+use crypto::{digest::Digest, sha2::Sha256};
+
+/// Size of an ElligatorSwift encoded public key.
+pub const ENCODED_KEY_SIZE: usize = 64;
+
+const ECDH_TAG: &[u8] = b"bip324_ellswift_xonly_ecdh";
+
+/// Generate a secret key and ElligatorSwift encoding of its public key.
+/// The encoding is indistinguishable from 64 random bytes.
+pub fn generate() -> (SecretKey, [u8; ENCODED_KEY_SIZE])
+{
+    let secp = Secp256k1::new();
+    let mut rng = thread_rng();
+
+    let secret = loop {
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        if let Ok(secret) = SecretKey::from_slice(&secp, &bytes) {
+            break secret;
+        }
+    };
+    let public = PublicKey::from_secret_key(&secp, &secret).serialize_uncompressed();
+    let x = Fe::from_bytes(&public[1..33]);
+
+    loop {
+        let mut bytes = [0u8; 32];
+        rng.fill_bytes(&mut bytes);
+        let u = Fe::from_bytes(&bytes);
+        let case = rng.next_u32() as u8 & 7;
+        if let Some(t) = xswiftec_inv(&x, &u, case) {
+            let mut encoded = [0u8; ENCODED_KEY_SIZE];
+            encoded[..32].copy_from_slice(&u.to_bytes());
+            encoded[32..].copy_from_slice(&t.to_bytes());
+            return (secret, encoded);
+        }
+    }
+}
+
+/// X-only ECDH between ElligatorSwift encoded public keys, hashed together with both encodings.
+pub fn ecdh(
+    secret: &SecretKey,
+    initiator_key: &[u8; ENCODED_KEY_SIZE],
+    responder_key: &[u8; ENCODED_KEY_SIZE],
+    initiating: bool,
+) -> [u8; 32]
+{
+    let secp = Secp256k1::new();
+    let their_key = if initiating { responder_key } else { initiator_key };
+
+    // Either of two points which have the x coordinate is fine, since we use only x of the result.
+    let x = decode(their_key);
+    let mut compressed = [2u8; 33];
+    compressed[1..].copy_from_slice(&x.to_bytes());
+    let mut point = PublicKey::from_slice(&secp, &compressed).expect("Decoded x is always on the curve");
+    point.mul_assign(&secp, secret).expect("Secret key is always valid");
+    let shared_x = &point.serialize()[1..];
+
+    let mut tag_hash = [0u8; 32];
+    let mut hasher = Sha256::new();
+    hasher.input(ECDH_TAG);
+    hasher.result(&mut tag_hash);
+
+    let mut hasher = Sha256::new();
+    hasher.input(&tag_hash);
+    hasher.input(&tag_hash);
+    hasher.input(initiator_key);
+    hasher.input(responder_key);
+    hasher.input(shared_x);
+    let mut secret = [0u8; 32];
+    hasher.result(&mut secret);
+    secret
+}
+
+fn decode(encoded: &[u8; ENCODED_KEY_SIZE]) -> Fe
+{
+    xswiftec(&Fe::from_bytes(&encoded[..32]), &Fe::from_bytes(&encoded[32..]))
+}
+
+/// Map field elements `(u, t)` to an x coordinate on the curve.
+fn xswiftec(u: &Fe, t: &Fe) -> Fe
+{
+    let u = if u.is_zero() { Fe::from_u64(1) } else { *u };
+    let mut t = if t.is_zero() { Fe::from_u64(1) } else { *t };
+
+    let seven = Fe::from_u64(7);
+    let u3_plus_7 = u.square().mul(&u).add(&seven);
+    if u3_plus_7.add(&t.square()).is_zero() {
+        t = t.add(&t);
+    }
+
+    let x = u3_plus_7.sub(&t.square()).div(&t.add(&t));
+    let y = x.add(&t).div(&minus_3_sqrt().mul(&u));
+    let two = Fe::from_u64(2);
+
+    let x3 = u.add(&Fe::from_u64(4).mul(&y.square()));
+    if is_valid_x(&x3) {
+        return x3;
+    }
+    let x2 = x.neg().div(&y).sub(&u).div(&two);
+    if is_valid_x(&x2) {
+        return x2;
+    }
+    x.div(&y).sub(&u).div(&two)
+}
+
+/// Find `t` such that `xswiftec(u, t) == x`.
+/// `case` (0 to 7) selects one of the solutions. Returns `None` if it does not exist.
+fn xswiftec_inv(x: &Fe, u: &Fe, case: u8) -> Option<Fe>
+{
+    let seven = Fe::from_u64(7);
+    let two = Fe::from_u64(2);
+    let u3_plus_7 = u.square().mul(u).add(&seven);
+
+    let (s, v) = if case & 2 == 0 {
+        if is_valid_x(&x.neg().sub(u)) {
+            return None;
+        }
+        let s = u3_plus_7.neg().div(&u.square().add(&u.mul(x)).add(&x.square()));
+        (s, *x)
+    } else {
+        let s = x.sub(u);
+        if s.is_zero() {
+            return None;
+        }
+        let four_u3_plus_7 = Fe::from_u64(4).mul(&u3_plus_7);
+        let three_s_u2 = Fe::from_u64(3).mul(&s).mul(&u.square());
+        let r = s.neg().mul(&four_u3_plus_7.add(&three_s_u2)).sqrt()?;
+        if case & 1 == 1 && r.is_zero() {
+            return None;
+        }
+        (s, r.div(&s).sub(u).div(&two))
+    };
+
+    let w = s.sqrt()?;
+    let c = minus_3_sqrt();
+    let one = Fe::from_u64(1);
+    let minus_c_part = one.sub(&c).div(&two).mul(u).add(&v);
+    let plus_c_part = one.add(&c).div(&two).mul(u).add(&v);
+    let t = match case & 5 {
+        0 => w.neg().mul(&minus_c_part),
+        1 => w.mul(&plus_c_part),
+        4 => w.mul(&minus_c_part),
+        _ => w.neg().mul(&plus_c_part),
+    };
+    Some(t)
+}
+
+fn is_valid_x(x: &Fe) -> bool
+{
+    x.square().mul(x).add(&Fe::from_u64(7)).sqrt().is_some()
+}
+
+fn minus_3_sqrt() -> Fe
+{
+    Fe::from_u64(3).neg().sqrt().expect("-3 is a square")
+}
+
+/// An element of the field which secp256k1 is defined over.
+/// Limbs are little endian and the value is always less than `P`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Fe([u64; 4]);
+
+const P: [u64; 4] = [0xFFFF_FFFE_FFFF_FC2F, !0, !0, !0];
+
+// 2^256 mod P
+const R: u64 = 0x1_0000_03D1;
+
+const P_MINUS_2: [u64; 4] = [0xFFFF_FFFE_FFFF_FC2D, !0, !0, !0];
+
+const P_PLUS_1_DIV_4: [u64; 4] = [0xFFFF_FFFF_BFFF_FF0C, !0, !0, 0x3FFF_FFFF_FFFF_FFFF];
+
+impl Fe
+{
+    fn from_u64(n: u64) -> Fe
+    {
+        Fe([n, 0, 0, 0])
+    }
+
+    /// Interpret 32 bytes as a big endian integer, reduced by `P`.
+    fn from_bytes(bytes: &[u8]) -> Fe
+    {
+        assert!(bytes.len() == 32);
+        let mut limbs = [0u64; 4];
+        for (i, chunk) in bytes.chunks(8).enumerate() {
+            limbs[3 - i] = chunk.iter().fold(0, |acc, b| acc << 8 | *b as u64);
+        }
+        Fe::normalize(limbs, false)
+    }
+
+    fn to_bytes(&self) -> [u8; 32]
+    {
+        let mut bytes = [0u8; 32];
+        for i in 0..32 {
+            bytes[31 - i] = (self.0[i / 8] >> (i % 8 * 8)) as u8;
+        }
+        bytes
+    }
+
+    fn is_zero(&self) -> bool
+    {
+        self.0 == [0; 4]
+    }
+
+    // `overflow` means the value is `limbs + 2^256`. It must be less than `2 * P`.
+    fn normalize(limbs: [u64; 4], overflow: bool) -> Fe
+    {
+        let geq_p = (0..4).rev().find(|&i| limbs[i] != P[i]).map_or(true, |i| limbs[i] > P[i]);
+        if !overflow && !geq_p {
+            return Fe(limbs);
+        }
+        let mut res = [0u64; 4];
+        let mut borrow = false;
+        for i in 0..4 {
+            let (d, b1) = limbs[i].overflowing_sub(P[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            res[i] = d;
+            borrow = b1 || b2;
+        }
+        Fe(res)
+    }
+
+    fn add(&self, other: &Fe) -> Fe
+    {
+        let mut res = [0u64; 4];
+        let mut carry = 0u128;
+        for i in 0..4 {
+            let sum = self.0[i] as u128 + other.0[i] as u128 + carry;
+            res[i] = sum as u64;
+            carry = sum >> 64;
+        }
+        Fe::normalize(res, carry != 0)
+    }
+
+    fn neg(&self) -> Fe
+    {
+        if self.is_zero() {
+            return *self;
+        }
+        let mut res = [0u64; 4];
+        let mut borrow = false;
+        for i in 0..4 {
+            let (d, b1) = P[i].overflowing_sub(self.0[i]);
+            let (d, b2) = d.overflowing_sub(borrow as u64);
+            res[i] = d;
+            borrow = b1 || b2;
+        }
+        Fe(res)
+    }
+
+    fn sub(&self, other: &Fe) -> Fe
+    {
+        self.add(&other.neg())
+    }
+
+    fn mul(&self, other: &Fe) -> Fe
+    {
+        let mut wide = [0u64; 8];
+        for i in 0..4 {
+            let mut carry = 0u128;
+            for j in 0..4 {
+                let x = wide[i + j] as u128 + self.0[i] as u128 * other.0[j] as u128 + carry;
+                wide[i + j] = x as u64;
+                carry = x >> 64;
+            }
+            wide[i + 4] = carry as u64;
+        }
+
+        // Fold upper 256 bits using 2^256 = R (mod P), twice.
+        let mut res = [0u64; 4];
+        let mut carry = 0u128;
+        for i in 0..4 {
+            let x = wide[i] as u128 + wide[i + 4] as u128 * R as u128 + carry;
+            res[i] = x as u64;
+            carry = x >> 64;
+        }
+        let mut carry = carry * R as u128;
+        for i in 0..4 {
+            let x = res[i] as u128 + carry;
+            res[i] = x as u64;
+            carry = x >> 64;
+        }
+        Fe::normalize(res, carry != 0)
+    }
+
+    fn square(&self) -> Fe
+    {
+        self.mul(self)
+    }
+
+    fn pow(&self, exp: &[u64; 4]) -> Fe
+    {
+        let mut res = Fe::from_u64(1);
+        for i in (0..4).rev() {
+            for bit in (0..64).rev() {
+                res = res.square();
+                if exp[i] >> bit & 1 == 1 {
+                    res = res.mul(self);
+                }
+            }
+        }
+        res
+    }
+
+    fn div(&self, other: &Fe) -> Fe
+    {
+        self.mul(&other.pow(&P_MINUS_2))
+    }
+
+    fn sqrt(&self) -> Option<Fe>
+    {
+        let root = self.pow(&P_PLUS_1_DIV_4);
+        if root.square() == *self {
+            Some(root)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn decode_zero_encoding()
+    {
+        // A test vector of BIP324.
+        let expected = [
+            0xed, 0xd1, 0xfd, 0x3e, 0x32, 0x7c, 0xe9, 0x0c, 0xc7, 0xa3, 0x54, 0x26, 0x14, 0x28, 0x9a, 0xee, 0x96, 0x82,
+            0x00, 0x3e, 0x9c, 0xf7, 0xdc, 0xc9, 0xcf, 0x2c, 0xa9, 0x74, 0x3b, 0xe5, 0xaa, 0x0c,
+        ];
+        assert_eq!(decode(&[0; ENCODED_KEY_SIZE]).to_bytes(), expected);
+    }
+
+    #[test]
+    fn both_sides_derive_same_secret()
+    {
+        let (initiator_secret, initiator_key) = generate();
+        let (responder_secret, responder_key) = generate();
+        assert_eq!(
+            ecdh(&initiator_secret, &initiator_key, &responder_key, true),
+            ecdh(&responder_secret, &initiator_key, &responder_key, false)
+        );
+    }
+}
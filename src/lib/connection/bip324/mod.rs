@@ -0,0 +1,703 @@
+mod cipher;
+mod ellswift;
+
+use std::{cmp, io::{self, Read, Write}};
+
+use bitcoin::network::{constants::Network, serialize::serialize};
+use crypto::{hkdf::{hkdf_expand, hkdf_extract}, sha2::Sha256};
+use futures::{future::{self, loop_fn, poll_fn, Either, Loop}, Async, Future, Poll};
+use rand::{thread_rng, Rng};
+use tokio::io::{flush, read_exact, write_all, AsyncRead, AsyncWrite};
+use failure::Error;
+
+use self::cipher::{LengthCipher, PacketCipher, TAG_SIZE};
+use self::ellswift::ENCODED_KEY_SIZE;
+use connection::{error::ConnectionError,
+                 socket::{encode_raw, max_payload_size, COMMAND_SIZE, MAX_PAYLOAD_SIZE,
+                          RAW_NETWORK_MESSAGE_HEADER_SIZE}};
+
+/// Service bit which advertises that we accept v2 transport.
+pub const NODE_P2P_V2: u64 = 1 << 11;
+
+const MAX_GARBAGE_LEN: usize = 4095;
+const GARBAGE_TERMINATOR_LEN: usize = 16;
+const LENGTH_FIELD_LEN: usize = 3;
+
+// Packets with this bit in their header are decoys and must be ignored.
+const IGNORE_BIT: u8 = 0x80;
+
+/// Commands which are encoded in 1 byte. Short ID of each command is its index + 1.
+/// Other commands are encoded as 0 followed by 12 bytes command.
+const SHORT_IDS: [&str; 28] = [
+    "addr",
+    "block",
+    "blocktxn",
+    "cmpctblock",
+    "feefilter",
+    "filteradd",
+    "filterclear",
+    "filterload",
+    "getblocks",
+    "getblocktxn",
+    "getdata",
+    "getheaders",
+    "headers",
+    "inv",
+    "mempool",
+    "merkleblock",
+    "notfound",
+    "ping",
+    "pong",
+    "sendcmpct",
+    "tx",
+    "getcfilters",
+    "cfilter",
+    "getcfheaders",
+    "cfheaders",
+    "getcfcheckpt",
+    "cfcheckpt",
+    "addrv2",
+];
+
+/// A stream which speaks BIP324 v2 transport with remote peer.
+///
+/// Upper layer reads and writes v1 formatted messages as usual,
+/// and this stream translates them from/to encrypted packets.
+/// So `Socket` works on it without any change.
+///
+/// If peer does not support v2 transport, it passes bytes through as they are (plaintext mode).
+pub struct Bip324Stream<S>
+{
+    stream: S,
+    network: Network,
+    // `None` in plaintext mode.
+    session: Option<Session>,
+    // Bytes read from `stream` but not decrypted yet.
+    read_buf: Vec<u8>,
+    // Messages which are decrypted and reformatted as v1, but not read by upper layer yet.
+    plain_buf: Vec<u8>,
+    // Bytes written by upper layer, which are not a complete message yet.
+    write_buf: Vec<u8>,
+    // Encrypted packets which are not written to `stream` yet.
+    encrypted_buf: Vec<u8>,
+}
+
+struct Session
+{
+    send_length: LengthCipher,
+    send_packet: PacketCipher,
+    recv_length: LengthCipher,
+    recv_packet: PacketCipher,
+    // Length of a packet which we are receiving. Its length field is already decrypted.
+    recv_len: Option<usize>,
+    // Whether the command of the receiving packet is already checked against its maximum payload size.
+    recv_checked: bool,
+    // Peer's garbage is authenticated with the first packet.
+    recv_aad: Vec<u8>,
+    // Peer's first non-decoy packet is a version packet, which does not contain a message.
+    recv_version: bool,
+    session_id: [u8; 32],
+}
+
+struct SessionKeys
+{
+    send_length: [u8; 32],
+    send_packet: [u8; 32],
+    recv_length: [u8; 32],
+    recv_packet: [u8; 32],
+    send_garbage_terminator: [u8; GARBAGE_TERMINATOR_LEN],
+    recv_garbage_terminator: [u8; GARBAGE_TERMINATOR_LEN],
+    session_id: [u8; 32],
+}
+
+impl<S> Bip324Stream<S>
+{
+    /// Create a stream in plaintext mode.
+    /// `prefix` is bytes which are already read from `stream`.
+    pub fn plaintext(stream: S, network: Network, prefix: Vec<u8>) -> Bip324Stream<S>
+    {
+        Bip324Stream {
+            stream,
+            network,
+            session: None,
+            read_buf: Vec::new(),
+            plain_buf: prefix,
+            write_buf: Vec::new(),
+            encrypted_buf: Vec::new(),
+        }
+    }
+
+    fn encrypted(stream: S, network: Network, session: Session, read_buf: Vec<u8>) -> Bip324Stream<S>
+    {
+        Bip324Stream {
+            session: Some(session),
+            read_buf,
+            ..Bip324Stream::plaintext(stream, network, Vec::new())
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool
+    {
+        self.session.is_some()
+    }
+
+    /// Both sides derive the same session ID. Comparing it out of band detects man-in-the-middle.
+    pub fn session_id(&self) -> Option<&[u8; 32]>
+    {
+        self.session.as_ref().map(|s| &s.session_id)
+    }
+
+    // Decrypt a packet in `read_buf` if it is complete.
+    // Returns true if a packet is consumed.
+    fn decrypt_packet(&mut self) -> io::Result<bool>
+    {
+        let session = self.session.as_mut().unwrap();
+
+        if session.recv_len.is_none() {
+            if self.read_buf.len() < LENGTH_FIELD_LEN {
+                return Ok(false);
+            }
+            let len = session.recv_length.crypt(&[self.read_buf[0], self.read_buf[1], self.read_buf[2]]);
+            let len = len[0] as usize | (len[1] as usize) << 8 | (len[2] as usize) << 16;
+            // Command is unknown yet, so limit it by the largest one.
+            if len > 1 + COMMAND_SIZE + MAX_PAYLOAD_SIZE as usize {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "too large packet"));
+            }
+            self.read_buf.drain(..LENGTH_FIELD_LEN);
+            session.recv_len = Some(len);
+            session.recv_checked = false;
+        }
+        let len = session.recv_len.unwrap();
+
+        // Check the command before buffering whole packet.
+        // It follows the header, and is encoded in 1 byte or 0 followed by 12 bytes.
+        if !session.recv_checked && self.read_buf.len() >= cmp::min(2, 1 + len) {
+            let long_command = len > 0 && session.recv_packet.peek(&self.read_buf[..2])[1] == 0;
+            let prefix_len = cmp::min(if long_command { 2 + COMMAND_SIZE } else { 2 }, 1 + len);
+            if self.read_buf.len() >= prefix_len {
+                let prefix = session.recv_packet.peek(&self.read_buf[..prefix_len]);
+                // Decoy and version packets may contain anything.
+                if prefix[0] & IGNORE_BIT == 0 && !session.recv_version {
+                    let (command, payload_prefix) = decode_contents(&prefix[1..])?;
+                    let payload_len = len - (prefix_len - 1 - payload_prefix.len());
+                    if payload_len > max_payload_size(&command) as usize {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "too large payload"));
+                    }
+                }
+                session.recv_checked = true;
+            }
+        }
+
+        // Header, contents and tag
+        let packet_len = 1 + len + TAG_SIZE;
+        if self.read_buf.len() < packet_len {
+            return Ok(false);
+        }
+        let aad = ::std::mem::replace(&mut session.recv_aad, Vec::new());
+        let plaintext = session
+            .recv_packet
+            .decrypt(&aad, &self.read_buf[..packet_len])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "packet authentication failed"))?;
+        self.read_buf.drain(..packet_len);
+        session.recv_len = None;
+
+        if plaintext[0] & IGNORE_BIT != 0 {
+            return Ok(true);
+        }
+        if session.recv_version {
+            // Contents of version packet are reserved for future extensions.
+            session.recv_version = false;
+            return Ok(true);
+        }
+
+        let (command, payload) = decode_contents(&plaintext[1..])?;
+        self.plain_buf.extend(encode_raw(&command, payload, self.network));
+        Ok(true)
+    }
+
+    // Encrypt all complete messages in `write_buf`.
+    fn encrypt_msgs(&mut self)
+    {
+        let session = self.session.as_mut().unwrap();
+        while self.write_buf.len() >= RAW_NETWORK_MESSAGE_HEADER_SIZE {
+            // Header is magic, command, payload size and checksum. We need only command and payload.
+            let size_bytes = &self.write_buf[16..20];
+            let payload_size = size_bytes.iter().rev().fold(0, |acc, b| acc << 8 | *b as usize);
+            let msg_len = RAW_NETWORK_MESSAGE_HEADER_SIZE + payload_size;
+            if self.write_buf.len() < msg_len {
+                return;
+            }
+
+            let command: Vec<u8> = self.write_buf[4..16].iter().cloned().take_while(|b| *b != 0).collect();
+            let command = String::from_utf8_lossy(&command);
+            let contents = encode_contents(&command, &self.write_buf[RAW_NETWORK_MESSAGE_HEADER_SIZE..msg_len]);
+            let packet = session.encrypt_packet(&contents, &[]);
+            self.encrypted_buf.extend(packet);
+            self.write_buf.drain(..msg_len);
+        }
+    }
+}
+
+impl<S: Write> Bip324Stream<S>
+{
+    fn write_encrypted(&mut self) -> io::Result<()>
+    {
+        while !self.encrypted_buf.is_empty() {
+            let n = self.stream.write(&self.encrypted_buf)?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::WriteZero));
+            }
+            self.encrypted_buf.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read> Read for Bip324Stream<S>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        loop {
+            if !self.plain_buf.is_empty() {
+                let n = cmp::min(buf.len(), self.plain_buf.len());
+                buf[..n].copy_from_slice(&self.plain_buf[..n]);
+                self.plain_buf.drain(..n);
+                return Ok(n);
+            }
+            if self.session.is_none() {
+                return self.stream.read(buf);
+            }
+            if self.decrypt_packet()? {
+                continue;
+            }
+
+            let mut chunk = [0u8; 4096];
+            let n = self.stream.read(&mut chunk)?;
+            if n == 0 {
+                let in_middle = !self.read_buf.is_empty() || self.session.as_ref().unwrap().recv_len.is_some();
+                if in_middle {
+                    info!("Socket is closed in the middle of a packet");
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+                return Ok(0);
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<S: Write> Write for Bip324Stream<S>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        if self.session.is_none() {
+            return self.stream.write(buf);
+        }
+
+        // Do not accept more bytes until previous packets are written.
+        self.write_encrypted()?;
+
+        self.write_buf.extend_from_slice(buf);
+        self.encrypt_msgs();
+        match self.write_encrypted() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {},
+            res => res?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        self.write_encrypted()?;
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for Bip324Stream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for Bip324Stream<S>
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error>
+    {
+        match self.write_encrypted() {
+            Ok(()) => self.stream.shutdown(),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl Session
+{
+    fn new(keys: &SessionKeys) -> Session
+    {
+        Session {
+            send_length: LengthCipher::new(keys.send_length),
+            send_packet: PacketCipher::new(keys.send_packet),
+            recv_length: LengthCipher::new(keys.recv_length),
+            recv_packet: PacketCipher::new(keys.recv_packet),
+            recv_len: None,
+            recv_checked: false,
+            recv_aad: Vec::new(),
+            recv_version: true,
+            session_id: keys.session_id,
+        }
+    }
+
+    fn encrypt_packet(&mut self, contents: &[u8], aad: &[u8]) -> Vec<u8>
+    {
+        let len = contents.len();
+        let len = self.send_length.crypt(&[len as u8, (len >> 8) as u8, (len >> 16) as u8]);
+
+        let mut plaintext = Vec::with_capacity(1 + contents.len());
+        plaintext.push(0); // Header
+        plaintext.extend_from_slice(contents);
+
+        let mut packet = len.to_vec();
+        packet.extend(self.send_packet.encrypt(aad, &plaintext));
+        packet
+    }
+}
+
+/// Start v2 transport as an initiator of a connection.
+/// If peer closes a connection without sending its key, it probably does not support v2 transport.
+/// Then this fails with `ConnectionError::V2Unsupported` and caller should reconnect with v1.
+pub fn initiate<S>(stream: S, network: Network) -> impl Future<Item = Bip324Stream<S>, Error = Error>
+where S: AsyncRead + AsyncWrite
+{
+    let (secret, our_key) = ellswift::generate();
+    let garbage = random_garbage();
+    let mut first = our_key.to_vec();
+    first.extend_from_slice(&garbage);
+
+    write_all(stream, first)
+        .and_then(|(stream, _)| read_exact(stream, [0u8; ENCODED_KEY_SIZE]))
+        .map_err(|e| {
+            match e.kind() {
+                io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset => {
+                    Error::from(ConnectionError::V2Unsupported)
+                },
+                _ => Error::from(e),
+            }
+        })
+        .and_then(move |(stream, their_key)| {
+            let shared_secret = ellswift::ecdh(&secret, &our_key, &their_key, true);
+            let keys = derive_keys(&shared_secret, network, true);
+            finish_handshake(stream, network, keys, garbage)
+        })
+}
+
+/// Accept v2 transport as a responder of a connection.
+/// If peer starts with v1 `version` message, falls back to plaintext mode.
+pub fn respond<S>(stream: S, network: Network) -> impl Future<Item = Bip324Stream<S>, Error = Error>
+where S: AsyncRead + AsyncWrite
+{
+    let v1_prefix = encode_raw("version", &[], network);
+    read_exact(stream, [0u8; 16])
+        .map_err(Error::from)
+        .and_then(move |(stream, prefix)| {
+            // Magic and command of v1 `version` message.
+            if prefix[..] == v1_prefix[..16] {
+                debug!("Peer uses v1 transport");
+                return Either::A(future::ok(Bip324Stream::plaintext(stream, network, prefix.to_vec())));
+            }
+
+            let f = read_exact(stream, [0u8; ENCODED_KEY_SIZE - 16])
+                .map_err(Error::from)
+                .and_then(move |(stream, rest)| {
+                    let mut their_key = [0u8; ENCODED_KEY_SIZE];
+                    their_key[..16].copy_from_slice(&prefix);
+                    their_key[16..].copy_from_slice(&rest);
+
+                    let (secret, our_key) = ellswift::generate();
+                    let shared_secret = ellswift::ecdh(&secret, &their_key, &our_key, false);
+                    let keys = derive_keys(&shared_secret, network, false);
+
+                    let garbage = random_garbage();
+                    let mut first = our_key.to_vec();
+                    first.extend_from_slice(&garbage);
+                    write_all(stream, first)
+                        .map_err(Error::from)
+                        .and_then(move |(stream, _)| finish_handshake(stream, network, keys, garbage))
+                });
+            Either::B(f)
+        })
+}
+
+// Send our garbage terminator and version packet, and then skip peer's garbage.
+fn finish_handshake<S>(
+    stream: S,
+    network: Network,
+    keys: SessionKeys,
+    garbage: Vec<u8>,
+) -> impl Future<Item = Bip324Stream<S>, Error = Error>
+where S: AsyncRead + AsyncWrite
+{
+    let mut session = Session::new(&keys);
+    let mut msg = keys.send_garbage_terminator.to_vec();
+    msg.extend(session.encrypt_packet(&[], &garbage));
+
+    let terminator = keys.recv_garbage_terminator;
+    write_all(stream, msg)
+        .and_then(|(stream, _)| flush(stream))
+        .map_err(Error::from)
+        .and_then(move |stream| recv_garbage(stream, terminator))
+        .map(move |(stream, garbage, rest)| {
+            session.recv_aad = garbage;
+            Bip324Stream::encrypted(stream, network, session, rest)
+        })
+}
+
+// Returns peer's garbage and bytes following its terminator.
+fn recv_garbage<S>(
+    stream: S,
+    terminator: [u8; GARBAGE_TERMINATOR_LEN],
+) -> impl Future<Item = (S, Vec<u8>, Vec<u8>), Error = Error>
+where S: AsyncRead
+{
+    loop_fn((stream, Vec::new()), move |(stream, mut received)| {
+        read_some(stream)
+            .map_err(Error::from)
+            .and_then(move |(stream, chunk)| {
+                if chunk.is_empty() {
+                    info!("Socket is closed during v2 handshake");
+                    return Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)));
+                }
+                received.extend_from_slice(&chunk);
+
+                let pos = received.windows(GARBAGE_TERMINATOR_LEN).position(|w| w == terminator);
+                match pos {
+                    Some(pos) if pos <= MAX_GARBAGE_LEN => {
+                        let rest = received.split_off(pos + GARBAGE_TERMINATOR_LEN);
+                        received.truncate(pos);
+                        Ok(Loop::Break((stream, received, rest)))
+                    },
+                    _ if received.len() >= MAX_GARBAGE_LEN + GARBAGE_TERMINATOR_LEN => {
+                        info!("Garbage terminator is not found");
+                        return Err(Error::from(ConnectionError::MisbehavePeer));
+                    },
+                    _ => Ok(Loop::Continue((stream, received))),
+                }
+            })
+    })
+}
+
+// Read bytes which are available now. Empty bytes mean EOF.
+fn read_some<S>(stream: S) -> impl Future<Item = (S, Vec<u8>), Error = io::Error>
+where S: AsyncRead
+{
+    let mut stream = Some(stream);
+    poll_fn(move || {
+        let mut chunk = [0u8; 4096];
+        let n = match stream.as_mut().unwrap().poll_read(&mut chunk)? {
+            Async::Ready(n) => n,
+            Async::NotReady => return Ok(Async::NotReady),
+        };
+        Ok(Async::Ready((stream.take().unwrap(), chunk[..n].to_vec())))
+    })
+}
+
+fn derive_keys(shared_secret: &[u8; 32], network: Network, initiating: bool) -> SessionKeys
+{
+    let mut salt = b"bitcoin_v2_shared_secret".to_vec();
+    salt.extend(serialize(&network.magic()).unwrap());
+    let mut prk = [0u8; 32];
+    hkdf_extract(Sha256::new(), &salt, shared_secret, &mut prk);
+
+    let expand = |label: &str| {
+        let mut okm = [0u8; 32];
+        hkdf_expand(Sha256::new(), &prk, label.as_bytes(), &mut okm);
+        okm
+    };
+    let initiator_length = expand("initiator_L");
+    let initiator_packet = expand("initiator_P");
+    let responder_length = expand("responder_L");
+    let responder_packet = expand("responder_P");
+    let terminators = expand("garbage_terminators");
+
+    let mut initiator_terminator = [0u8; GARBAGE_TERMINATOR_LEN];
+    let mut responder_terminator = [0u8; GARBAGE_TERMINATOR_LEN];
+    initiator_terminator.copy_from_slice(&terminators[..GARBAGE_TERMINATOR_LEN]);
+    responder_terminator.copy_from_slice(&terminators[GARBAGE_TERMINATOR_LEN..]);
+
+    let session_id = expand("session_id");
+    if initiating {
+        SessionKeys {
+            send_length: initiator_length,
+            send_packet: initiator_packet,
+            recv_length: responder_length,
+            recv_packet: responder_packet,
+            send_garbage_terminator: initiator_terminator,
+            recv_garbage_terminator: responder_terminator,
+            session_id,
+        }
+    } else {
+        SessionKeys {
+            send_length: responder_length,
+            send_packet: responder_packet,
+            recv_length: initiator_length,
+            recv_packet: initiator_packet,
+            send_garbage_terminator: responder_terminator,
+            recv_garbage_terminator: initiator_terminator,
+            session_id,
+        }
+    }
+}
+
+fn random_garbage() -> Vec<u8>
+{
+    let mut rng = thread_rng();
+    // Avoid `next_u64`, which reads unaligned memory of `ThreadRng` after odd number of `next_u32`.
+    let len = rng.gen_range(0u32, MAX_GARBAGE_LEN as u32 + 1);
+    let mut garbage = vec![0u8; len as usize];
+    rng.fill(&mut garbage[..]);
+    garbage
+}
+
+fn encode_contents(command: &str, payload: &[u8]) -> Vec<u8>
+{
+    let mut contents = Vec::with_capacity(1 + COMMAND_SIZE + payload.len());
+    match SHORT_IDS.iter().position(|c| *c == command) {
+        Some(idx) => contents.push(idx as u8 + 1),
+        None => {
+            contents.push(0);
+            let mut command_bytes = [0u8; COMMAND_SIZE];
+            command_bytes[..command.len()].copy_from_slice(command.as_bytes());
+            contents.extend_from_slice(&command_bytes);
+        },
+    }
+    contents.extend_from_slice(payload);
+    contents
+}
+
+fn decode_contents(contents: &[u8]) -> io::Result<(String, &[u8])>
+{
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid message type");
+    match contents.first() {
+        None => Err(invalid()),
+        Some(0) if contents.len() > COMMAND_SIZE => {
+            let command: Vec<u8> = contents[1..1 + COMMAND_SIZE].iter().cloned().take_while(|b| *b != 0).collect();
+            let command = String::from_utf8(command).map_err(|_| invalid())?;
+            Ok((command, &contents[1 + COMMAND_SIZE..]))
+        },
+        Some(0) => Err(invalid()),
+        Some(id) => {
+            let command = SHORT_IDS.get(*id as usize - 1).ok_or_else(invalid)?;
+            Ok((command.to_string(), &contents[1..]))
+        },
+    }
+}
+
+#[cfg(test)]
+#[cfg(unix)]
+mod tests
+{
+    use super::*;
+    use std::{collections::HashSet, net::SocketAddr, time::Duration};
+    use bitcoin::network::message::NetworkMessage;
+    use tokio::{net::UnixStream, runtime::current_thread::Runtime};
+    use connection::{message::BtcMessage, socket::{HandshakeConfig, HandshakedSocket, Socket, VersionPolicy}};
+
+    fn handshake_config(nonce: u64) -> HandshakeConfig
+    {
+        HandshakeConfig {
+            start_height: 0,
+            services: NODE_P2P_V2,
+            relay: false,
+            nonce,
+            local_nonces: HashSet::new(),
+            policy: VersionPolicy::inbound(),
+            timeout: Duration::from_secs(10),
+            wtxid_relay: false,
+            addr_v2: false,
+        }
+    }
+
+    fn sockets() -> (Socket<UnixStream>, Socket<UnixStream>)
+    {
+        let (a, b) = UnixStream::pair().unwrap();
+        let addr: SocketAddr = "10.0.0.1:8333".parse().unwrap();
+        (Socket::new(a, Network::Regtest, addr, addr), Socket::new(b, Network::Regtest, addr, addr))
+    }
+
+    fn ping_pong<S>(rt: &mut Runtime, initiator: HandshakedSocket<S>, responder: HandshakedSocket<S>) -> Vec<BtcMessage>
+    where S: AsyncRead + AsyncWrite
+    {
+        // `feefilter` has a short ID, but `sendheaders` does not.
+        let f = initiator
+            .send_msg(NetworkMessage::Ping(42))
+            .and_then(|initiator| initiator.send_msg(BtcMessage::SendHeaders))
+            .join(responder.recv_msg())
+            .and_then(|(initiator, (ping, responder))| {
+                responder
+                    .recv_msg()
+                    .and_then(|(send_headers, responder)| {
+                        responder.send_msg(NetworkMessage::Pong(42)).map(|_| send_headers)
+                    })
+                    .join(initiator.recv_msg())
+                    .map(move |(send_headers, (pong, _))| vec![ping, send_headers, pong])
+            });
+        rt.block_on(f).unwrap()
+    }
+
+    #[test]
+    fn exchange_msgs_over_v2_transport()
+    {
+        let (a, b) = sockets();
+        let timeout = Duration::from_secs(10);
+        let initiator = a.begin_v2_transport(timeout).and_then(|s| s.begin_handshake(handshake_config(1)));
+        let responder = b.accept_v2_transport(timeout).and_then(|s| s.reply_handshake(handshake_config(2)));
+        let mut rt = Runtime::new().unwrap();
+        let (initiator, responder) = rt.block_on(initiator.join(responder)).unwrap();
+
+        let msgs = ping_pong(&mut rt, initiator, responder);
+        assert_eq!(msgs[0], BtcMessage::Network(NetworkMessage::Ping(42)));
+        assert_eq!(msgs[1], BtcMessage::SendHeaders);
+        assert_eq!(msgs[2], BtcMessage::Network(NetworkMessage::Pong(42)));
+    }
+
+    #[test]
+    fn reject_too_large_payload_before_whole_packet()
+    {
+        let key = |b: u8| [b; 32];
+        let keys = |send: u8, recv: u8| SessionKeys {
+            send_length: key(send),
+            send_packet: key(send + 1),
+            recv_length: key(recv),
+            recv_packet: key(recv + 1),
+            send_garbage_terminator: [0; GARBAGE_TERMINATOR_LEN],
+            recv_garbage_terminator: [0; GARBAGE_TERMINATOR_LEN],
+            session_id: [0; 32],
+        };
+        let mut sender = Session::new(&keys(1, 3));
+        let receiver = Session::new(&keys(3, 1));
+
+        // Version packet, and then `ping` whose payload is 1 byte longer than its limit.
+        let mut bytes = sender.encrypt_packet(&[], &[]);
+        let version_len = bytes.len();
+        let mut contents = vec![SHORT_IDS.iter().position(|c| *c == "ping").unwrap() as u8 + 1];
+        contents.extend_from_slice(&[0u8; 9]);
+        bytes.extend(sender.encrypt_packet(&contents, &[]));
+        // Only the length, the header and the command arrive.
+        bytes.truncate(version_len + LENGTH_FIELD_LEN + 2);
+
+        let mut stream = Bip324Stream::encrypted(io::Cursor::new(bytes), Network::Regtest, receiver, Vec::new());
+        let err = stream.read(&mut [0u8; 32]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn accept_v1_peer()
+    {
+        let (a, b) = sockets();
+        let initiator = a.into_v1_transport().begin_handshake(handshake_config(1));
+        let responder = b
+            .accept_v2_transport(Duration::from_secs(10))
+            .and_then(|s| s.reply_handshake(handshake_config(2)));
+        let mut rt = Runtime::new().unwrap();
+        let (initiator, responder) = rt.block_on(initiator.join(responder)).unwrap();
+
+        let msgs = ping_pong(&mut rt, initiator, responder);
+        assert_eq!(msgs[2], BtcMessage::Network(NetworkMessage::Pong(42)));
+    }
+}
@@ -0,0 +1,313 @@
+use std::{cmp, f64::consts::LN_2, io::Cursor};
+
+use bitcoin::blockdata::block::BlockHeader;
+use bitcoin::network::{encodable::ConsensusDecodable, serialize::{serialize, RawDecoder}};
+use bitcoin::util::hash::Sha256dHash;
+use failure::Error;
+
+use connection::error::ConnectionError;
+
+/// Peers reject `filterload` whose filter is larger than this (BIP37).
+pub const MAX_BLOOM_FILTER_SIZE: usize = 36_000;
+
+/// Peers reject `filterload` with more hash functions than this (BIP37).
+pub const MAX_HASH_FUNCS: u32 = 50;
+
+/// Peers reject `filteradd` whose element is larger than this, i.e. the maximum size of a script push.
+pub const MAX_FILTERADD_SIZE: usize = 520;
+
+/// Peer does not update the filter on matches.
+pub const BLOOM_UPDATE_NONE: u8 = 0;
+
+/// Peer adds outpoints of matched outputs to the filter, so that spending transactions match too.
+pub const BLOOM_UPDATE_ALL: u8 = 1;
+
+/// Same as `BLOOM_UPDATE_ALL`, but only for pay-to-pubkey and multisig outputs.
+pub const BLOOM_UPDATE_P2PUBKEY_ONLY: u8 = 2;
+
+// Same as Bitcoin Core, whose block can not have more transactions than this.
+const MAX_MERKLE_BLOCK_TXS: u32 = 4_000_000 / 240;
+
+/// A bloom filter of BIP37, which is a payload of `filterload` message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BloomFilter
+{
+    pub content: Vec<u8>,
+    pub hash_funcs: u32,
+    pub tweak: u32,
+    pub flags: u8,
+}
+
+impl BloomFilter
+{
+    /// An empty filter sized so that `elements` elements match with about `fp_rate` false positive rate.
+    /// The size is limited by `MAX_BLOOM_FILTER_SIZE` and `MAX_HASH_FUNCS`.
+    pub fn new(elements: usize, fp_rate: f64, tweak: u32, flags: u8) -> BloomFilter
+    {
+        let elements = cmp::max(elements, 1) as f64;
+        let size = (-1.0 / (LN_2 * LN_2) * elements * fp_rate.ln() / 8.0) as usize;
+        let size = cmp::max(cmp::min(size, MAX_BLOOM_FILTER_SIZE), 1);
+        let hash_funcs = (size as f64 * 8.0 / elements * LN_2) as u32;
+        BloomFilter {
+            content: vec![0; size],
+            hash_funcs: cmp::max(cmp::min(hash_funcs, MAX_HASH_FUNCS), 1),
+            tweak,
+            flags,
+        }
+    }
+
+    pub fn insert(&mut self, data: &[u8])
+    {
+        for n in 0..self.hash_funcs {
+            let index = self.bit_index(n, data);
+            self.content[index / 8] |= 1 << (index % 8);
+        }
+    }
+
+    pub fn contains(&self, data: &[u8]) -> bool
+    {
+        (0..self.hash_funcs).all(|n| {
+            let index = self.bit_index(n, data);
+            self.content[index / 8] & 1 << (index % 8) != 0
+        })
+    }
+
+    fn bit_index(&self, n: u32, data: &[u8]) -> usize
+    {
+        let seed = n.wrapping_mul(0xfba4_c795).wrapping_add(self.tweak);
+        murmur3(seed, data) as usize % (self.content.len() * 8)
+    }
+}
+
+/// A payload of `merkleblock` message (BIP37).
+/// A block header and a partial merkle tree which proves transactions matching the filter are in the block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleBlock
+{
+    pub header: BlockHeader,
+    pub total_txs: u32,
+    pub hashes: Vec<Sha256dHash>,
+    pub flags: Vec<u8>,
+}
+
+impl MerkleBlock
+{
+    /// Traverse the partial merkle tree. Returns the merkle root and txids of matched transactions in order.
+    /// Returns `None` if the tree is malformed.
+    /// Caller must compare the merkle root with that of the header which it trusts.
+    pub fn extract_matches(&self) -> Option<(Sha256dHash, Vec<Sha256dHash>)>
+    {
+        if self.total_txs == 0 || self.total_txs > MAX_MERKLE_BLOCK_TXS {
+            return None;
+        }
+        if self.hashes.len() > self.total_txs as usize || self.hashes.len() > self.flags.len() * 8 {
+            return None;
+        }
+        let mut height = 0;
+        while self.tree_width(height) > 1 {
+            height += 1;
+        }
+
+        let mut traversal = Traversal {
+            bits_used: 0,
+            hashes_used: 0,
+            matches: Vec::new(),
+        };
+        let root = self.traverse(height, 0, &mut traversal)?;
+        // All flag bits and hashes must be consumed.
+        if (traversal.bits_used + 7) / 8 != self.flags.len() || traversal.hashes_used != self.hashes.len() {
+            return None;
+        }
+        Some((root, traversal.matches))
+    }
+
+    fn tree_width(&self, height: u32) -> u32
+    {
+        ((self.total_txs as u64 + (1 << height) - 1) >> height) as u32
+    }
+
+    fn traverse(&self, height: u32, pos: u32, traversal: &mut Traversal) -> Option<Sha256dHash>
+    {
+        let flag_byte = self.flags.get(traversal.bits_used / 8)?;
+        let parent_of_match = flag_byte >> (traversal.bits_used % 8) & 1 == 1;
+        traversal.bits_used += 1;
+
+        if height == 0 || !parent_of_match {
+            let hash = *self.hashes.get(traversal.hashes_used)?;
+            traversal.hashes_used += 1;
+            if height == 0 && parent_of_match {
+                traversal.matches.push(hash);
+            }
+            return Some(hash);
+        }
+
+        let left = self.traverse(height - 1, pos * 2, traversal)?;
+        let right = if pos * 2 + 1 < self.tree_width(height - 1) {
+            let right = self.traverse(height - 1, pos * 2 + 1, traversal)?;
+            // Identical siblings would let another transaction list have the same root (CVE-2012-2459).
+            if right == left {
+                return None;
+            }
+            right
+        } else {
+            left
+        };
+        let mut data = left[..].to_vec();
+        data.extend_from_slice(&right[..]);
+        Some(Sha256dHash::from_data(&data))
+    }
+}
+
+struct Traversal
+{
+    bits_used: usize,
+    hashes_used: usize,
+    matches: Vec<Sha256dHash>,
+}
+
+/// MurmurHash3 (x86, 32 bit), which BIP37 filters are built on.
+fn murmur3(seed: u32, data: &[u8]) -> u32
+{
+    const C1: u32 = 0xcc9e_2d51;
+    const C2: u32 = 0x1b87_3593;
+
+    let mut h = seed;
+    let mut chunks = data.chunks_exact(4);
+    for chunk in chunks.by_ref() {
+        let k = u32::from(chunk[0]) | u32::from(chunk[1]) << 8 | u32::from(chunk[2]) << 16 | u32::from(chunk[3]) << 24;
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+        h = h.rotate_left(13).wrapping_mul(5).wrapping_add(0xe654_6b64);
+    }
+    let tail = chunks.remainder();
+    if !tail.is_empty() {
+        let k = tail.iter().rev().fold(0u32, |acc, b| acc << 8 | u32::from(*b));
+        h ^= k.wrapping_mul(C1).rotate_left(15).wrapping_mul(C2);
+    }
+
+    h ^= data.len() as u32;
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x85eb_ca6b);
+    h ^= h >> 13;
+    h = h.wrapping_mul(0xc2b2_ae35);
+    h ^ h >> 16
+}
+
+/// Decode a payload of `filterload` message.
+pub fn decode_filterload(src: &[u8]) -> Result<BloomFilter, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+    let filter = BloomFilter {
+        content: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        hash_funcs: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        tweak: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        flags: ConsensusDecodable::consensus_decode(&mut decoder)?,
+    };
+    if filter.content.len() > MAX_BLOOM_FILTER_SIZE || filter.hash_funcs > MAX_HASH_FUNCS {
+        info!("Too large bloom filter : {} bytes, {} functions", filter.content.len(), filter.hash_funcs);
+        return Err(Error::from(ConnectionError::MisbehavePeer));
+    }
+    Ok(filter)
+}
+
+/// Encode a payload of `filterload` message.
+pub fn encode_filterload(filter: &BloomFilter) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&filter.content).unwrap();
+    buf.extend_from_slice(&serialize(&filter.hash_funcs).unwrap());
+    buf.extend_from_slice(&serialize(&filter.tweak).unwrap());
+    buf.extend_from_slice(&serialize(&filter.flags).unwrap());
+    buf
+}
+
+/// Decode a payload of `merkleblock` message.
+pub fn decode_merkleblock(src: &[u8]) -> Result<MerkleBlock, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+    Ok(MerkleBlock {
+        header: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        total_txs: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        hashes: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        flags: ConsensusDecodable::consensus_decode(&mut decoder)?,
+    })
+}
+
+/// Encode a payload of `merkleblock` message.
+pub fn encode_merkleblock(merkle_block: &MerkleBlock) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&merkle_block.header).unwrap();
+    buf.extend_from_slice(&serialize(&merkle_block.total_txs).unwrap());
+    buf.extend_from_slice(&serialize(&merkle_block.hashes).unwrap());
+    buf.extend_from_slice(&serialize(&merkle_block.flags).unwrap());
+    buf
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use bitcoin::util::misc::hex_bytes;
+
+    #[test]
+    fn murmur3_vectors()
+    {
+        // Vectors of Bitcoin Core's hash_tests.
+        assert_eq!(murmur3(0, &[]), 0);
+        assert_eq!(murmur3(0xfba4_c795, &[]), 0x6a39_6f08);
+        assert_eq!(murmur3(0xffff_ffff, &[]), 0x81f1_6f39);
+        assert_eq!(murmur3(0, &[0x00]), 0x514e_28b7);
+        assert_eq!(murmur3(0xfba4_c795, &[0x00]), 0xea3f_0b17);
+        assert_eq!(murmur3(0, &[0x00, 0x11, 0x22]), 0x8eb5_1c3d);
+        assert_eq!(murmur3(0, &[0x00, 0x11, 0x22, 0x33]), 0xb447_1bf8);
+    }
+
+    #[test]
+    fn bloom_filter_matches_core()
+    {
+        // A vector of Bitcoin Core's bloom_tests.
+        let mut filter = BloomFilter::new(3, 0.01, 0, BLOOM_UPDATE_ALL);
+        let elements: Vec<_> = [
+            "99108ad8ed9bb6274d3980bab5a85c048f0950c8",
+            "b5a2c786d9ef4658287ced5914b37a1b4aa32eee",
+            "b9300670b4c5366e95b2699e8b18bc75e5f729c5",
+        ].iter()
+            .map(|s| hex_bytes(s).unwrap())
+            .collect();
+        filter.insert(&elements[0]);
+        assert!(filter.contains(&elements[0]));
+        assert!(!filter.contains(&hex_bytes("19108ad8ed9bb6274d3980bab5a85c048f0950c8").unwrap()));
+        filter.insert(&elements[1]);
+        filter.insert(&elements[2]);
+        assert_eq!(encode_filterload(&filter), hex_bytes("03614e9b050000000000000001").unwrap());
+        assert_eq!(decode_filterload(&encode_filterload(&filter)).unwrap(), filter);
+    }
+
+    #[test]
+    fn extract_matches_of_partial_merkle_tree()
+    {
+        let txids: Vec<_> = (0..3u8).map(|i| Sha256dHash::from_data(&[i])).collect();
+        let concat = |a: &Sha256dHash, b: &Sha256dHash| {
+            let mut data = a[..].to_vec();
+            data.extend_from_slice(&b[..]);
+            Sha256dHash::from_data(&data)
+        };
+        let right = concat(&txids[2], &txids[2]);
+        let root = concat(&concat(&txids[0], &txids[1]), &right);
+
+        // Only the second transaction matches.
+        let mut merkle_block = MerkleBlock {
+            header: genesis_block(Network::Testnet).header,
+            total_txs: 3,
+            hashes: vec![txids[0], txids[1], right],
+            flags: vec![0b01011],
+        };
+        assert_eq!(merkle_block.extract_matches(), Some((root, vec![txids[1]])));
+        assert_eq!(decode_merkleblock(&encode_merkleblock(&merkle_block)).unwrap(), merkle_block);
+
+        merkle_block.hashes.push(txids[2]);
+        assert_eq!(merkle_block.extract_matches(), None);
+    }
+}
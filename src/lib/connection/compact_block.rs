@@ -0,0 +1,434 @@
+use std::{collections::HashMap, io::Cursor};
+
+use bitcoin::blockdata::{block::{Block, BlockHeader}, transaction::Transaction};
+use bitcoin::network::{encodable::{ConsensusDecodable, VarInt}, serialize::{serialize, RawDecoder}};
+use bitcoin::util::hash::{bitcoin_merkle_root, Sha256dHash};
+use bitcoin::BitcoinHash;
+use crypto::{digest::Digest, sha2::Sha256};
+use failure::Error;
+
+use connection::error::ConnectionError;
+use util::siphash24;
+
+/// Version of compact blocks which we support. Short IDs are computed from wtxids.
+/// Version 1 is not supported since it cannot relay witness.
+pub const COMPACT_BLOCK_VERSION: u64 = 2;
+
+/// Transaction indexes of a compact block must fit in 16 bits like Bitcoin Core.
+pub const MAX_COMPACT_BLOCK_TXS: usize = 0xffff;
+
+const SHORT_ID_SIZE: usize = 6;
+
+/// Transactions which we know but which are not in any block yet, i.e. a mempool.
+/// Compact blocks are reconstructed from them.
+pub trait TxPool
+{
+    /// Transactions with witness.
+    fn transactions(&self) -> Vec<Transaction>;
+}
+
+impl TxPool for Vec<Transaction>
+{
+    fn transactions(&self) -> Vec<Transaction>
+    {
+        self.clone()
+    }
+}
+
+/// A payload of `cmpctblock` message (BIP152).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderAndShortIds
+{
+    pub header: BlockHeader,
+    pub nonce: u64,
+    /// Lower 48 bits are used.
+    pub short_ids: Vec<u64>,
+    /// Sorted by index.
+    pub prefilled_txs: Vec<PrefilledTransaction>,
+}
+
+/// A transaction which is sent in `cmpctblock` as it is, e.g. coinbase.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrefilledTransaction
+{
+    /// Absolute index in the block. It is differentially encoded on the wire.
+    pub index: usize,
+    pub tx: Transaction,
+}
+
+/// A payload of `getblocktxn` message (BIP152).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTxnRequest
+{
+    pub block_hash: Sha256dHash,
+    /// Sorted absolute indexes. They are differentially encoded on the wire.
+    pub indexes: Vec<usize>,
+}
+
+/// A payload of `blocktxn` message (BIP152).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockTxn
+{
+    pub block_hash: Sha256dHash,
+    pub txs: Vec<Transaction>,
+}
+
+/// A block being reconstructed from `cmpctblock`.
+/// Missing transactions are requested by `getblocktxn`.
+#[derive(Debug)]
+pub struct PartialBlock
+{
+    header: BlockHeader,
+    txs: Vec<Option<Transaction>>,
+}
+
+impl HeaderAndShortIds
+{
+    /// Only coinbase is prefilled, same as Bitcoin Core.
+    pub fn from_block(block: &Block, nonce: u64) -> HeaderAndShortIds
+    {
+        let (k0, k1) = short_id_keys(&block.header, nonce);
+        let prefilled_txs = block
+            .txdata
+            .first()
+            .map(|tx| PrefilledTransaction { index: 0, tx: tx.clone() })
+            .into_iter()
+            .collect();
+        let short_ids = block.txdata.iter().skip(1).map(|tx| short_id(k0, k1, &tx.bitcoin_hash())).collect();
+        HeaderAndShortIds {
+            header: block.header,
+            nonce,
+            short_ids,
+            prefilled_txs,
+        }
+    }
+
+    pub fn tx_count(&self) -> usize
+    {
+        self.short_ids.len() + self.prefilled_txs.len()
+    }
+}
+
+impl BlockTxnRequest
+{
+    /// Answer this request from the block.
+    /// Returns `None` if an index is out of the block.
+    pub fn answer(&self, block: &Block) -> Option<BlockTxn>
+    {
+        let txs = self.indexes.iter().map(|i| block.txdata.get(*i).cloned()).collect::<Option<_>>()?;
+        Some(BlockTxn {
+            block_hash: self.block_hash,
+            txs,
+        })
+    }
+}
+
+impl PartialBlock
+{
+    /// Fill transactions from prefilled ones and the pool.
+    /// Returns `None` if short IDs collide within the block, and then the full block is needed.
+    pub fn new(cmpct: &HeaderAndShortIds, pool: &[Transaction]) -> Option<PartialBlock>
+    {
+        let mut txs: Vec<Option<Transaction>> = vec![None; cmpct.tx_count()];
+        for prefilled in cmpct.prefilled_txs.iter() {
+            txs[prefilled.index] = Some(prefilled.tx.clone());
+        }
+
+        // Short IDs fill the rest of slots in order.
+        let mut slots = HashMap::with_capacity(cmpct.short_ids.len());
+        let empty_slots = (0..txs.len()).filter(|i| txs[*i].is_none());
+        for (short_id, slot) in cmpct.short_ids.iter().zip(empty_slots) {
+            if slots.insert(*short_id, slot).is_some() {
+                info!("Short IDs collide in compact block {}", cmpct.header.bitcoin_hash());
+                return None;
+            }
+        }
+
+        // If several transactions in the pool have the same short ID, we cannot tell which one is in the block.
+        let (k0, k1) = short_id_keys(&cmpct.header, cmpct.nonce);
+        let mut ambiguous = Vec::new();
+        for tx in pool {
+            let slot = match slots.get(&short_id(k0, k1, &tx.bitcoin_hash())) {
+                None => continue,
+                Some(slot) => *slot,
+            };
+            if txs[slot].is_some() {
+                ambiguous.push(slot);
+            } else {
+                txs[slot] = Some(tx.clone());
+            }
+        }
+        for slot in ambiguous {
+            txs[slot] = None;
+        }
+
+        Some(PartialBlock {
+            header: cmpct.header,
+            txs,
+        })
+    }
+
+    pub fn block_hash(&self) -> Sha256dHash
+    {
+        self.header.bitcoin_hash()
+    }
+
+    /// Indexes of transactions which we have to request by `getblocktxn`.
+    pub fn missing_indexes(&self) -> Vec<usize>
+    {
+        (0..self.txs.len()).filter(|i| self.txs[*i].is_none()).collect()
+    }
+
+    /// Fill missing transactions with ones of `blocktxn`.
+    /// Returns `Err` if the number of transactions does not match.
+    pub fn fill(&mut self, txs: Vec<Transaction>) -> Result<(), ()>
+    {
+        let missing = self.missing_indexes();
+        if missing.len() != txs.len() {
+            return Err(());
+        }
+        for (i, tx) in missing.into_iter().zip(txs) {
+            self.txs[i] = Some(tx);
+        }
+        Ok(())
+    }
+
+    /// Returns `None` if some transactions are still missing or the merkle root does not match.
+    /// The latter happens when a short ID matches a wrong transaction in our pool.
+    pub fn into_block(self) -> Option<Block>
+    {
+        let txdata: Vec<Transaction> = self.txs.into_iter().collect::<Option<_>>()?;
+        if bitcoin_merkle_root(txdata.iter().map(|tx| tx.txid()).collect()) != self.header.merkle_root {
+            return None;
+        }
+        Some(Block {
+            header: self.header,
+            txdata,
+        })
+    }
+}
+
+/// SipHash keys of short IDs, which are derived from the block header and nonce.
+fn short_id_keys(header: &BlockHeader, nonce: u64) -> (u64, u64)
+{
+    let mut hasher = Sha256::new();
+    hasher.input(&serialize(header).unwrap());
+    hasher.input(&serialize(&nonce).unwrap());
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+    (le_u64(&hash[..8]), le_u64(&hash[8..16]))
+}
+
+fn short_id(k0: u64, k1: u64, wtxid: &Sha256dHash) -> u64
+{
+    siphash24(k0, k1, &wtxid[..]) & 0xffff_ffff_ffff
+}
+
+fn le_u64(bytes: &[u8]) -> u64
+{
+    bytes.iter().rev().fold(0, |acc, b| acc << 8 | *b as u64)
+}
+
+/// Decode a payload of `cmpctblock` message.
+pub fn decode_cmpctblock(src: &[u8]) -> Result<HeaderAndShortIds, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+
+    let header = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    let nonce = ConsensusDecodable::consensus_decode(&mut decoder)?;
+
+    let VarInt(short_ids_len) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    if short_ids_len > MAX_COMPACT_BLOCK_TXS as u64 {
+        info!("Too many short IDs : {}", short_ids_len);
+        return Err(Error::from(ConnectionError::MisbehavePeer));
+    }
+    let mut short_ids = Vec::with_capacity(short_ids_len as usize);
+    for _ in 0..short_ids_len {
+        // 6 bytes little endian
+        let low: u32 = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        let high: u16 = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        short_ids.push((high as u64) << 32 | low as u64);
+    }
+
+    let VarInt(prefilled_len) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    if short_ids_len + prefilled_len > MAX_COMPACT_BLOCK_TXS as u64 {
+        info!("Too many transactions in compact block : {}", short_ids_len + prefilled_len);
+        return Err(Error::from(ConnectionError::MisbehavePeer));
+    }
+    let mut prefilled_txs = Vec::with_capacity(prefilled_len as usize);
+    let mut next_index = 0;
+    for _ in 0..prefilled_len {
+        let VarInt(diff) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        let index = next_index + diff;
+        if index >= short_ids_len + prefilled_len {
+            info!("Prefilled transaction index is out of block : {}", index);
+            return Err(Error::from(ConnectionError::MisbehavePeer));
+        }
+        let tx = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        prefilled_txs.push(PrefilledTransaction { index: index as usize, tx });
+        next_index = index + 1;
+    }
+
+    Ok(HeaderAndShortIds {
+        header,
+        nonce,
+        short_ids,
+        prefilled_txs,
+    })
+}
+
+/// Encode a payload of `cmpctblock` message.
+pub fn encode_cmpctblock(cmpct: &HeaderAndShortIds) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&cmpct.header).unwrap();
+    buf.extend_from_slice(&serialize(&cmpct.nonce).unwrap());
+    buf.extend_from_slice(&serialize(&VarInt(cmpct.short_ids.len() as u64)).unwrap());
+    for short_id in cmpct.short_ids.iter() {
+        buf.extend_from_slice(&serialize(short_id).unwrap()[..SHORT_ID_SIZE]);
+    }
+    buf.extend_from_slice(&serialize(&VarInt(cmpct.prefilled_txs.len() as u64)).unwrap());
+    let mut next_index = 0;
+    for prefilled in cmpct.prefilled_txs.iter() {
+        buf.extend_from_slice(&serialize(&VarInt((prefilled.index - next_index) as u64)).unwrap());
+        buf.extend_from_slice(&serialize(&prefilled.tx).unwrap());
+        next_index = prefilled.index + 1;
+    }
+    buf
+}
+
+/// Decode a payload of `getblocktxn` message.
+pub fn decode_getblocktxn(src: &[u8]) -> Result<BlockTxnRequest, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+
+    let block_hash = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    let VarInt(len) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    if len > MAX_COMPACT_BLOCK_TXS as u64 {
+        info!("Too many indexes in getblocktxn : {}", len);
+        return Err(Error::from(ConnectionError::MisbehavePeer));
+    }
+    let mut indexes = Vec::with_capacity(len as usize);
+    let mut next_index = 0;
+    for _ in 0..len {
+        let VarInt(diff) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        let index = next_index + diff;
+        if index >= MAX_COMPACT_BLOCK_TXS as u64 {
+            info!("Too large index in getblocktxn : {}", index);
+            return Err(Error::from(ConnectionError::MisbehavePeer));
+        }
+        indexes.push(index as usize);
+        next_index = index + 1;
+    }
+
+    Ok(BlockTxnRequest { block_hash, indexes })
+}
+
+/// Encode a payload of `getblocktxn` message.
+pub fn encode_getblocktxn(req: &BlockTxnRequest) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&req.block_hash).unwrap();
+    buf.extend_from_slice(&serialize(&VarInt(req.indexes.len() as u64)).unwrap());
+    let mut next_index = 0;
+    for index in req.indexes.iter() {
+        buf.extend_from_slice(&serialize(&VarInt((index - next_index) as u64)).unwrap());
+        next_index = index + 1;
+    }
+    buf
+}
+
+/// Decode a payload of `blocktxn` message.
+pub fn decode_blocktxn(src: &[u8]) -> Result<BlockTxn, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+    Ok(BlockTxn {
+        block_hash: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        txs: ConsensusDecodable::consensus_decode(&mut decoder)?,
+    })
+}
+
+/// Encode a payload of `blocktxn` message.
+pub fn encode_blocktxn(txn: &BlockTxn) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&txn.block_hash).unwrap();
+    buf.extend_from_slice(&serialize(&txn.txs).unwrap());
+    buf
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::{script::Script, transaction::{OutPoint, TxIn, TxOut}};
+
+    fn block(tx_count: u32) -> Block
+    {
+        let txdata: Vec<_> = (0..tx_count)
+            .map(|i| {
+                Transaction {
+                    version: 2,
+                    lock_time: i,
+                    input: vec![TxIn {
+                        previous_output: OutPoint::default(),
+                        script_sig: Script::new(),
+                        sequence: 0xffff_ffff,
+                        witness: vec![vec![i as u8]],
+                    }],
+                    output: vec![TxOut {
+                        value: 50,
+                        script_pubkey: Script::new(),
+                    }],
+                }
+            })
+            .collect();
+        let header = BlockHeader {
+            version: 1,
+            prev_blockhash: Default::default(),
+            merkle_root: bitcoin_merkle_root(txdata.iter().map(|tx| tx.txid()).collect()),
+            time: 0,
+            bits: 0,
+            nonce: 0,
+        };
+        Block { header, txdata }
+    }
+
+    #[test]
+    fn cmpctblock_roundtrip()
+    {
+        let mut cmpct = HeaderAndShortIds::from_block(&block(5), 42);
+        cmpct.prefilled_txs.push(PrefilledTransaction {
+            index: 3,
+            tx: block(5).txdata[3].clone(),
+        });
+        cmpct.short_ids.pop();
+        assert_eq!(decode_cmpctblock(&encode_cmpctblock(&cmpct)).unwrap(), cmpct);
+
+        let req = BlockTxnRequest {
+            block_hash: cmpct.header.bitcoin_hash(),
+            indexes: vec![1, 2, 5, 300],
+        };
+        assert_eq!(decode_getblocktxn(&encode_getblocktxn(&req)).unwrap(), req);
+    }
+
+    #[test]
+    fn reconstruct_block_from_pool_and_blocktxn()
+    {
+        let block = block(6);
+        let cmpct = HeaderAndShortIds::from_block(&block, 7);
+        let pool = vec![block.txdata[1].clone(), block.txdata[4].clone(), self::block(8).txdata[7].clone()];
+
+        let mut partial = PartialBlock::new(&cmpct, &pool).unwrap();
+        assert_eq!(partial.missing_indexes(), vec![2, 3, 5]);
+
+        let req = BlockTxnRequest {
+            block_hash: partial.block_hash(),
+            indexes: partial.missing_indexes(),
+        };
+        let txn = req.answer(&block).unwrap();
+        assert!(partial.fill(txn.txs[1..].to_vec()).is_err());
+        partial.fill(txn.txs).unwrap();
+        assert_eq!(partial.into_block(), Some(block));
+    }
+}
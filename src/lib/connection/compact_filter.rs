@@ -0,0 +1,224 @@
+use std::io::Cursor;
+
+use bitcoin::network::{encodable::{ConsensusDecodable, VarInt}, serialize::{serialize, RawDecoder}};
+use bitcoin::util::hash::Sha256dHash;
+use failure::Error;
+
+use blockchain::{filter_header, BlockFilter};
+use connection::error::ConnectionError;
+
+/// Maximum number of blocks which a `getcfilters` can request.
+pub const MAX_GETCFILTERS_SIZE: u32 = 1_000;
+
+/// Maximum number of blocks which a `getcfheaders` can request.
+pub const MAX_GETCFHEADERS_SIZE: u32 = 2_000;
+
+/// `cfcheckpt` has a filter header of each block at this interval of heights.
+pub const CFCHECKPT_INTERVAL: u32 = 1_000;
+
+/// A payload of `getcfilters` or `getcfheaders` message (BIP157).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetCFilters
+{
+    pub filter_type: u8,
+    pub start_height: u32,
+    pub stop_hash: Sha256dHash,
+}
+
+/// A payload of `getcfcheckpt` message (BIP157).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GetCFCheckpt
+{
+    pub filter_type: u8,
+    pub stop_hash: Sha256dHash,
+}
+
+/// A payload of `cfilter` message (BIP157).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFilter
+{
+    pub filter_type: u8,
+    pub block_hash: Sha256dHash,
+    pub filter: BlockFilter,
+}
+
+/// A payload of `cfheaders` message (BIP157).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFHeaders
+{
+    pub filter_type: u8,
+    pub stop_hash: Sha256dHash,
+    /// Filter header of the block before the first one.
+    pub prev_filter_header: Sha256dHash,
+    pub filter_hashes: Vec<Sha256dHash>,
+}
+
+/// A payload of `cfcheckpt` message (BIP157).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CFCheckpt
+{
+    pub filter_type: u8,
+    pub stop_hash: Sha256dHash,
+    /// Filter headers at heights of `CFCHECKPT_INTERVAL`, 2 * `CFCHECKPT_INTERVAL` and so on.
+    pub filter_headers: Vec<Sha256dHash>,
+}
+
+impl CFHeaders
+{
+    /// Chain filter hashes into filter headers.
+    pub fn filter_headers(&self) -> Vec<Sha256dHash>
+    {
+        let mut prev = self.prev_filter_header;
+        self.filter_hashes
+            .iter()
+            .map(|filter_hash| {
+                prev = filter_header(filter_hash, &prev);
+                prev
+            })
+            .collect()
+    }
+}
+
+/// Decode a payload of `getcfilters` or `getcfheaders` message.
+pub fn decode_getcfilters(src: &[u8]) -> Result<GetCFilters, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+    Ok(GetCFilters {
+        filter_type: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        start_height: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        stop_hash: ConsensusDecodable::consensus_decode(&mut decoder)?,
+    })
+}
+
+/// Encode a payload of `getcfilters` or `getcfheaders` message.
+pub fn encode_getcfilters(req: &GetCFilters) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&req.filter_type).unwrap();
+    buf.extend_from_slice(&serialize(&req.start_height).unwrap());
+    buf.extend_from_slice(&serialize(&req.stop_hash).unwrap());
+    buf
+}
+
+/// Decode a payload of `getcfcheckpt` message.
+pub fn decode_getcfcheckpt(src: &[u8]) -> Result<GetCFCheckpt, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+    Ok(GetCFCheckpt {
+        filter_type: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        stop_hash: ConsensusDecodable::consensus_decode(&mut decoder)?,
+    })
+}
+
+/// Encode a payload of `getcfcheckpt` message.
+pub fn encode_getcfcheckpt(req: &GetCFCheckpt) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&req.filter_type).unwrap();
+    buf.extend_from_slice(&serialize(&req.stop_hash).unwrap());
+    buf
+}
+
+/// Decode a payload of `cfilter` message.
+pub fn decode_cfilter(src: &[u8]) -> Result<CFilter, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+    Ok(CFilter {
+        filter_type: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        block_hash: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        filter: BlockFilter {
+            content: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        },
+    })
+}
+
+/// Encode a payload of `cfilter` message.
+pub fn encode_cfilter(cfilter: &CFilter) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&cfilter.filter_type).unwrap();
+    buf.extend_from_slice(&serialize(&cfilter.block_hash).unwrap());
+    buf.extend_from_slice(&serialize(&cfilter.filter.content).unwrap());
+    buf
+}
+
+/// Decode a payload of `cfheaders` message.
+pub fn decode_cfheaders(src: &[u8]) -> Result<CFHeaders, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+
+    let filter_type = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    let stop_hash = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    let prev_filter_header = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    let VarInt(count) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    if count > MAX_GETCFHEADERS_SIZE as u64 {
+        info!("Too many filter hashes : {}", count);
+        return Err(Error::from(ConnectionError::MisbehavePeer));
+    }
+    let mut filter_hashes = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        filter_hashes.push(ConsensusDecodable::consensus_decode(&mut decoder)?);
+    }
+
+    Ok(CFHeaders {
+        filter_type,
+        stop_hash,
+        prev_filter_header,
+        filter_hashes,
+    })
+}
+
+/// Encode a payload of `cfheaders` message.
+pub fn encode_cfheaders(cfheaders: &CFHeaders) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&cfheaders.filter_type).unwrap();
+    buf.extend_from_slice(&serialize(&cfheaders.stop_hash).unwrap());
+    buf.extend_from_slice(&serialize(&cfheaders.prev_filter_header).unwrap());
+    buf.extend_from_slice(&serialize(&cfheaders.filter_hashes).unwrap());
+    buf
+}
+
+/// Decode a payload of `cfcheckpt` message.
+pub fn decode_cfcheckpt(src: &[u8]) -> Result<CFCheckpt, Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+    Ok(CFCheckpt {
+        filter_type: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        stop_hash: ConsensusDecodable::consensus_decode(&mut decoder)?,
+        filter_headers: ConsensusDecodable::consensus_decode(&mut decoder)?,
+    })
+}
+
+/// Encode a payload of `cfcheckpt` message.
+pub fn encode_cfcheckpt(cfcheckpt: &CFCheckpt) -> Vec<u8>
+{
+    // Never fail
+    let mut buf = serialize(&cfcheckpt.filter_type).unwrap();
+    buf.extend_from_slice(&serialize(&cfcheckpt.stop_hash).unwrap());
+    buf.extend_from_slice(&serialize(&cfcheckpt.filter_headers).unwrap());
+    buf
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test]
+    fn cfheaders_roundtrip_and_chain()
+    {
+        let filters: Vec<_> = (0..3u8).map(|i| BlockFilter { content: vec![1, i] }).collect();
+        let prev_filter_header = Sha256dHash::from_data(b"prev");
+        let cfheaders = CFHeaders {
+            filter_type: 0,
+            stop_hash: Sha256dHash::from_data(b"stop"),
+            prev_filter_header,
+            filter_hashes: filters.iter().map(|f| f.filter_hash()).collect(),
+        };
+        assert_eq!(decode_cfheaders(&encode_cfheaders(&cfheaders)).unwrap(), cfheaders);
+
+        let last = filters.iter().fold(prev_filter_header, |prev, f| f.header(&prev));
+        assert_eq!(cfheaders.filter_headers().last(), Some(&last));
+    }
+}
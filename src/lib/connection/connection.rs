@@ -1,40 +1,138 @@
-use std::time::Duration;
+use std::{collections::{HashMap, HashSet, VecDeque}, net::SocketAddr, sync::{Arc, Mutex},
+          time::{Duration, Instant, SystemTime}};
 
-use bitcoin::network::{address::Address, message::NetworkMessage,
-                       message_blockdata::{GetHeadersMessage, InvType, Inventory}};
-use bitcoin::blockdata::block::{Block, LoneBlockHeader};
-use bitcoin::util::hash::Sha256dHash;
+use bitcoin::network::{encodable::VarInt, message::NetworkMessage,
+                       message_blockdata::{GetBlocksMessage, GetHeadersMessage, InvType, Inventory}};
+use bitcoin::blockdata::{block::{Block, BlockHeader, LoneBlockHeader}, transaction::Transaction};
+use bitcoin::util::hash::{bitcoin_merkle_root, Sha256dHash};
 use bitcoin::BitcoinHash;
 
 use futures::{Future, Stream};
-use tokio::{io::WriteHalf, net::TcpStream};
+use tokio::io::{AsyncRead, AsyncWrite};
 use actix::{msgs::StartActor, prelude::*};
 use failure::Error;
+use rand::{FromEntropy, RngCore, XorShiftRng};
 
-use connection::socket::HandshakedSocket;
+use blockchain::{BlockChain, BlockData, BlockStore, FilterStore, BASIC_FILTER_TYPE};
+use connection::{addr::PeerAddr, bloom::{BloomFilter, MerkleBlock, MAX_FILTERADD_SIZE},
+                 compact_block::{BlockTxn, BlockTxnRequest, HeaderAndShortIds, PartialBlock, TxPool,
+                                 COMPACT_BLOCK_VERSION},
+                 compact_filter::{CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFilters, CFCHECKPT_INTERVAL,
+                                  MAX_GETCFHEADERS_SIZE, MAX_GETCFILTERS_SIZE},
+                 connection_pool::ReportMisbehavior, error::ConnectionError, message::BtcMessage,
+                 socket::{ByteCounts, HandshakedSocket, NegotiatedFeatures, PeerVersion, MAX_HEADERS_ENTRIES,
+                          NODE_BLOOM, NODE_COMPACT_FILTERS},
+                 socks5::TargetAddr};
 
 const SEND_TIMEOUT: Duration = Duration::from_secs(2);
 
+/// We send `ping` at this interval unless one is waiting for `pong`.
+const PING_INTERVAL: Duration = Duration::from_secs(2 * 60);
+
+/// Peer which does not answer `ping` within this period is considered dead and disconnected.
+const PING_TIMEOUT: Duration = Duration::from_secs(20 * 60);
+
+/// Peer must answer `getheaders` within this period.
+const HEADERS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Peer must deliver blocks of `getdata` within this period per block.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Requests for blocks wait in a queue while this number of blocks are in flight.
+/// It is same as Bitcoin Core's limit per peer.
+const MAX_BLOCKS_IN_FLIGHT: usize = 16;
+
+/// Each kind of requests is queued up to this number, and more requests fail with `QueueFull` at once.
+/// Pending transaction requests, compact blocks and merkle blocks are limited likewise.
+const MAX_QUEUED_REQUESTS: usize = 256;
+
+/// Peer is disconnected and banned when its misbehavior score reaches this.
+pub const BAN_THRESHOLD: u32 = 100;
+
+/// Score of lesser offenses, which a buggy peer may commit now and then. Peer is banned if it repeats them.
+const MINOR_MISBEHAVIOR_SCORE: u32 = 20;
+
+/// Maximum number of blocks announced in reply to `getblocks`.
+const MAX_BLOCKS_RESULTS: usize = 500;
+
+/// Peer must deliver transactions of `getdata` within this period.
+const TX_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Our transactions are served to peer for this period after announcement.
+const RELAY_TX_EXPIRY: Duration = Duration::from_secs(15 * 60);
+
+/// Peer must answer `getcfilters`, `getcfheaders` and `getcfcheckpt` within this period.
+const FILTERS_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Peers older than this do not understand compact blocks (BIP152).
+const SHORT_IDS_BLOCKS_VERSION: u32 = 70014;
+
+/// Peers older than this do not understand `sendheaders` (BIP130).
+const SENDHEADERS_VERSION: u32 = 70012;
+
+/// Deadlines of requests to peer and intervals of periodic tasks.
+/// Each default is the constant of the same name, e.g. `PING_INTERVAL`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestTimeouts
+{
+    pub ping_interval: Duration,
+    pub ping: Duration,
+    pub headers: Duration,
+    /// Per block.
+    pub block: Duration,
+    pub tx: Duration,
+    pub filters: Duration,
+    pub relay_tx_expiry: Duration,
+}
+
+impl Default for RequestTimeouts
+{
+    fn default() -> RequestTimeouts
+    {
+        RequestTimeouts {
+            ping_interval: PING_INTERVAL,
+            ping: PING_TIMEOUT,
+            headers: HEADERS_TIMEOUT,
+            block: BLOCK_TIMEOUT,
+            tx: TX_TIMEOUT,
+            filters: FILTERS_TIMEOUT,
+            relay_tx_expiry: RELAY_TX_EXPIRY,
+        }
+    }
+}
+
 #[derive(Message, Debug)]
-pub struct P2PMessage(NetworkMessage);
+pub struct P2PMessage(BtcMessage);
+
+#[derive(Message)]
+/// Replace `RequestTimeouts`. They apply to requests made after this.
+pub struct SetRequestTimeouts(pub RequestTimeouts);
 
 #[derive(Message)]
 /// This message corresponds to `getdata` message in bitcoin protocol.
-/// If peer does not have requested block data, peer does not respond anything.
-/// In Bitcoin core implementation, it wait up to two seconds and then disconnect.
+/// Sender receives exactly one `BlockResponse` for each requested hash.
 pub struct GetBlocksRequest
 {
     pub block_hashes: Vec<Sha256dHash>,
     pub addr: Recipient<BlockResponse>,
 }
 
-#[derive(Message)]
+#[derive(Message, Clone)]
 /// A response message to GetBlocksRequest.
-/// Sender probably receive same number of `BlockResponse` with request hashes.
-/// But sometime that number is less (see `GetBlocksRequest` document).
-/// Sender **SHOULD** set timeout.
-/// 2 seconds are recommended.
-pub struct BlockResponse(pub Block);
+pub enum BlockResponse
+{
+    Block(Block),
+
+    /// Peer answers `notfound`.
+    NotFound(Sha256dHash),
+
+    /// Peer does not deliver the block in time.
+    /// Peer may still have it, but we do not wait any more.
+    TimedOut(Sha256dHash),
+
+    /// Too many requests are queued on this connection. Nothing is sent to peer.
+    QueueFull(Sha256dHash),
+}
 
 #[derive(Message)]
 /// This message corresponds to `getheaders` message in bitcoin protocol.
@@ -45,13 +143,34 @@ pub struct GetHeadersRequest
 }
 
 #[derive(Message)]
-/// This message corresponds to `headers` message in bitcoin protocol.
-pub struct HeadersResponse(pub Vec<LoneBlockHeader>);
+/// A response message to GetHeadersRequest.
+pub enum HeadersResponse
+{
+    /// This corresponds to `headers` message in bitcoin protocol.
+    Headers(Vec<LoneBlockHeader>),
+
+    /// Peer does not answer in time.
+    TimedOut,
+
+    /// Too many requests are queued on this connection. Nothing is sent to peer.
+    QueueFull,
+}
 
 #[derive(Message)]
 /// Start to subscribe incoming `inv` message.
 /// Sender may receive a lot of `PublishInv` message.
+/// Subscribing again with the same `addr` replaces its filter.
 pub struct SubscribeInv
+{
+    pub addr: Recipient<PublishInv>,
+
+    /// Only inventories of these types are published. Empty means all types.
+    pub inv_types: Vec<InvType>,
+}
+
+#[derive(Message)]
+/// Stop to subscribe incoming `inv` message.
+pub struct UnsubscribeInv
 {
     pub addr: Recipient<PublishInv>,
 }
@@ -60,6 +179,343 @@ pub struct SubscribeInv
 /// This message corresponds to `inv` message in bitcoin protocol.
 pub struct PublishInv(pub Vec<Inventory>);
 
+#[derive(Message)]
+/// Start to subscribe `headers` message which peer sends without our request.
+/// On the first subscription, we send `sendheaders` (BIP130), so peer announces new blocks by it instead of `inv`.
+pub struct SubscribeHeaders
+{
+    pub addr: Recipient<PublishHeaders>,
+}
+
+#[derive(Message)]
+/// Stop to subscribe unsolicited `headers` message.
+pub struct UnsubscribeHeaders
+{
+    pub addr: Recipient<PublishHeaders>,
+}
+
+#[derive(Message)]
+/// Headers which peer announces. They may not connect to our chain, e.g. if we missed an announcement.
+/// Subscriber should report invalid ones by `Misbehave`.
+pub struct PublishHeaders(pub Vec<LoneBlockHeader>);
+
+#[derive(Message)]
+/// This message corresponds to `getdata` message of transactions in bitcoin protocol.
+/// Sender receives exactly one `TxResponse` for each requested txid.
+/// Peer usually serves only transactions in its mempool.
+pub struct GetTxRequest
+{
+    pub txids: Vec<Sha256dHash>,
+    pub addr: Recipient<TxResponse>,
+}
+
+#[derive(Message, Clone)]
+/// A response message to GetTxRequest.
+pub enum TxResponse
+{
+    Tx(Transaction),
+
+    /// Peer answers `notfound`.
+    NotFound(Sha256dHash),
+
+    /// Peer does not deliver the transaction in time.
+    TimedOut(Sha256dHash),
+
+    /// Too many requests are queued on this connection. Nothing is sent to peer.
+    QueueFull(Sha256dHash),
+}
+
+#[derive(Message)]
+/// Start to subscribe transactions which peer sends without our request.
+pub struct SubscribeTx
+{
+    pub addr: Recipient<PublishTx>,
+}
+
+#[derive(Message)]
+/// This message corresponds to `tx` message in bitcoin protocol.
+pub struct PublishTx(pub Transaction);
+
+#[derive(Message)]
+/// Announce our transaction to peer by `inv` and serve it when peer sends `getdata`.
+pub struct SendTransaction(pub Transaction);
+
+#[derive(Message)]
+/// Relay blocks by `cmpctblock` (BIP152), reconstructing them from transactions of `tx_pool`.
+/// In high-bandwidth mode, peer sends new blocks as `cmpctblock` without announcement, and they are
+/// published to the subscriber of `SubscribeBlock`. In low-bandwidth mode, use `GetCmpctBlockRequest`.
+pub struct UseCompactBlocks
+{
+    pub tx_pool: Arc<Mutex<dyn TxPool + Send>>,
+    pub high_bandwidth: bool,
+}
+
+#[derive(Message)]
+/// Request a block by `cmpctblock`, and missing transactions by `getblocktxn`.
+/// If compact blocks are not negotiated or reconstruction fails, the full block is requested instead.
+/// Sender receives exactly one `BlockResponse`.
+pub struct GetCmpctBlockRequest
+{
+    pub block_hash: Sha256dHash,
+    pub addr: Recipient<BlockResponse>,
+}
+
+#[derive(Message)]
+/// Start to subscribe blocks which nobody waits, e.g. `cmpctblock` in high-bandwidth mode.
+pub struct SubscribeBlock
+{
+    pub addr: Recipient<PublishBlock>,
+}
+
+#[derive(Message)]
+/// A block which peer sends without our request.
+pub struct PublishBlock(pub Block);
+
+#[derive(Message)]
+/// This message corresponds to `getcfilters` message of basic filters (BIP157).
+/// Up to `MAX_GETCFILTERS_SIZE` blocks from `start_height` to `stop_hash` can be requested.
+pub struct GetFiltersRequest
+{
+    pub start_height: u32,
+    pub stop_hash: Sha256dHash,
+    pub addr: Recipient<FiltersResponse>,
+}
+
+#[derive(Message)]
+/// A response message to GetFiltersRequest.
+pub enum FiltersResponse
+{
+    /// `cfilter` messages in order of height.
+    Filters(Vec<CFilter>),
+
+    /// Peer does not advertise `NODE_COMPACT_FILTERS`, so nothing is requested.
+    Unsupported,
+
+    TimedOut,
+    /// Too many requests are queued on this connection. Nothing is sent to peer.
+    QueueFull,
+}
+
+#[derive(Message)]
+/// This message corresponds to `getcfheaders` message of basic filters (BIP157).
+/// Up to `MAX_GETCFHEADERS_SIZE` blocks from `start_height` to `stop_hash` can be requested.
+pub struct GetFilterHeadersRequest
+{
+    pub start_height: u32,
+    pub stop_hash: Sha256dHash,
+    pub addr: Recipient<FilterHeadersResponse>,
+}
+
+#[derive(Message)]
+/// A response message to GetFilterHeadersRequest.
+pub enum FilterHeadersResponse
+{
+    Headers(CFHeaders),
+
+    /// Peer does not advertise `NODE_COMPACT_FILTERS`, so nothing is requested.
+    Unsupported,
+
+    TimedOut,
+    /// Too many requests are queued on this connection. Nothing is sent to peer.
+    QueueFull,
+}
+
+#[derive(Message)]
+/// This message corresponds to `getcfcheckpt` message of basic filters (BIP157).
+pub struct GetFilterCheckptRequest
+{
+    pub stop_hash: Sha256dHash,
+    pub addr: Recipient<FilterCheckptResponse>,
+}
+
+#[derive(Message)]
+/// A response message to GetFilterCheckptRequest.
+pub enum FilterCheckptResponse
+{
+    Checkpoint(CFCheckpt),
+
+    /// Peer does not advertise `NODE_COMPACT_FILTERS`, so nothing is requested.
+    Unsupported,
+
+    TimedOut,
+    /// Too many requests are queued on this connection. Nothing is sent to peer.
+    QueueFull,
+}
+
+#[derive(Message)]
+/// This message corresponds to `filterload` message (BIP37).
+/// Peer relays only transactions which match the filter, and merkle blocks are filtered by it.
+/// It is not sent to peer which does not advertise `NODE_BLOOM`, since such peer disconnects us.
+pub struct LoadBloomFilter(pub BloomFilter);
+
+#[derive(Message)]
+/// This message corresponds to `filteradd` message (BIP37), e.g. to watch a new script.
+/// Elements longer than `MAX_FILTERADD_SIZE` are not sent.
+pub struct AddToBloomFilter(pub Vec<u8>);
+
+#[derive(Message)]
+/// This message corresponds to `filterclear` message (BIP37).
+pub struct ClearBloomFilter();
+
+#[derive(Message)]
+/// This message corresponds to `getdata` message of merkle blocks (BIP37).
+/// A filter must be loaded by `LoadBloomFilter` beforehand.
+/// Sender receives exactly one `MerkleBlockResponse` for each requested hash.
+pub struct GetMerkleBlocksRequest
+{
+    /// Hashes of headers in our `BlockChain`, which merkle blocks are verified against.
+    pub block_hashes: Vec<Sha256dHash>,
+    pub addr: Recipient<MerkleBlockResponse>,
+}
+
+#[derive(Message, Clone)]
+/// A response message to GetMerkleBlocksRequest.
+pub enum MerkleBlockResponse
+{
+    /// Transactions which match the filter, proven to be in the block by its partial merkle tree.
+    /// Peer may omit some of `txids` from `txs` if it believes we already have them.
+    Block
+    {
+        header: BlockHeader,
+        txids: Vec<Sha256dHash>,
+        txs: Vec<Transaction>,
+    },
+
+    /// Peer answers `notfound`.
+    NotFound(Sha256dHash),
+
+    /// Peer does not deliver the merkle block in time.
+    TimedOut(Sha256dHash),
+
+    /// Peer does not advertise `NODE_BLOOM`, so nothing is requested.
+    Unsupported(Sha256dHash),
+
+    /// Too many requests are queued on this connection. Nothing is sent to peer.
+    QueueFull(Sha256dHash),
+}
+
+#[derive(Message)]
+/// Answer `getheaders`, `getblocks`, `getdata` and BIP157 requests of peer from given chain.
+/// Until it is set, such requests are ignored and `getdata` of blocks gets `notfound`.
+pub struct ServeChain
+{
+    pub blockchain: Arc<Mutex<BlockChain>>,
+
+    /// Headers-only node does not have it, and then serves only headers.
+    pub store: Option<Arc<Mutex<dyn BlockStore + Send>>>,
+
+    /// Node which does not index filters ignores `getcfilters`, `getcfheaders` and `getcfcheckpt`.
+    pub filters: Option<Arc<Mutex<dyn FilterStore + Send>>>,
+}
+
+/// Answers of BIP157 requests. `None` if we do not serve filters, the request is invalid
+/// or filters are not indexed yet.
+impl ServeChain
+{
+    fn cfilters(&self, req: &GetCFilters) -> Option<Vec<CFilter>>
+    {
+        let (store, block_hashes) = self.filter_block_hashes(req, MAX_GETCFILTERS_SIZE)?;
+        let store = store.lock().unwrap();
+        block_hashes
+            .into_iter()
+            .map(|block_hash| {
+                Some(CFilter {
+                    filter_type: req.filter_type,
+                    filter: store.get_filter(&block_hash)?,
+                    block_hash,
+                })
+            })
+            .collect()
+    }
+
+    fn cfheaders(&self, req: &GetCFilters) -> Option<CFHeaders>
+    {
+        let (store, block_hashes) = self.filter_block_hashes(req, MAX_GETCFHEADERS_SIZE)?;
+        // The previous block may be before the start of the chain, so find it by hash.
+        let prev_block_hash = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let active_chain = blockchain.active_chain();
+            let prev_block_hash = active_chain.get_block(req.start_height)?.header.prev_blockhash;
+            prev_block_hash
+        };
+        let store = store.lock().unwrap();
+        let prev_filter_header = if prev_block_hash == Sha256dHash::default() {
+            Sha256dHash::default()
+        } else {
+            store.get_filter_header(&prev_block_hash)?
+        };
+        let filter_hashes = block_hashes
+            .iter()
+            .map(|hash| store.get_filter(hash).map(|f| f.filter_hash()))
+            .collect::<Option<_>>()?;
+        Some(CFHeaders {
+            filter_type: req.filter_type,
+            stop_hash: req.stop_hash,
+            prev_filter_header,
+            filter_hashes,
+        })
+    }
+
+    fn cfcheckpt(&self, req: &GetCFCheckpt) -> Option<CFCheckpt>
+    {
+        let store = self.filters.as_ref()?;
+        if req.filter_type != BASIC_FILTER_TYPE {
+            return None;
+        }
+        let blockchain = self.blockchain.lock().unwrap();
+        let active_chain = blockchain.active_chain();
+        let stop_height = active_chain.get_block_by_hash(&req.stop_hash)?.height();
+        let store = store.lock().unwrap();
+        let filter_headers = (1..=stop_height / CFCHECKPT_INTERVAL)
+            .map(|i| store.get_filter_header(&active_chain.get_block(i * CFCHECKPT_INTERVAL)?.bitcoin_hash()))
+            .collect::<Option<_>>()?;
+        Some(CFCheckpt {
+            filter_type: req.filter_type,
+            stop_hash: req.stop_hash,
+            filter_headers,
+        })
+    }
+
+    /// Returns the filter store and hashes of blocks from `start_height` to `stop_hash` in the active chain.
+    fn filter_block_hashes(
+        &self,
+        req: &GetCFilters,
+        max_size: u32,
+    ) -> Option<(Arc<Mutex<dyn FilterStore + Send>>, Vec<Sha256dHash>)>
+    {
+        let store = self.filters.clone()?;
+        if req.filter_type != BASIC_FILTER_TYPE {
+            return None;
+        }
+        let blockchain = self.blockchain.lock().unwrap();
+        let active_chain = blockchain.active_chain();
+        let stop_height = active_chain.get_block_by_hash(&req.stop_hash)?.height();
+        if req.start_height > stop_height || stop_height - req.start_height >= max_size {
+            return None;
+        }
+        let block_hashes = (req.start_height..=stop_height)
+            .map(|height| active_chain.get_block(height).map(|b| b.bitcoin_hash()))
+            .collect::<Option<_>>()?;
+        Some((store, block_hashes))
+    }
+}
+
+#[derive(Message)]
+/// Start to subscribe incoming messages whose command we do not understand.
+/// Without subscriber, such messages are just discarded.
+pub struct SubscribeUnknown
+{
+    pub addr: Recipient<PublishUnknown>,
+}
+
+#[derive(Message)]
+/// A message whose command we do not understand, e.g. `feefilter`.
+pub struct PublishUnknown
+{
+    pub command: String,
+    pub payload: Vec<u8>,
+}
+
 #[derive(Message)]
 /// This message corresponds to `getaddr` message in bitcoin protocol.
 pub struct GetAddrsRequest
@@ -68,78 +524,226 @@ pub struct GetAddrsRequest
 }
 
 #[derive(Message)]
-pub struct AddrsResponse(pub Vec<(u32, Address)>);
+/// Addresses of `addr` or `addrv2` message.
+pub struct AddrsResponse(pub Vec<PeerAddr>);
+
+#[derive(Message)]
+#[rtype(result = "Option<Duration>")]
+/// Query round-trip time of the latest `ping`.
+/// Returns `None` if peer has not answered any `ping` yet.
+pub struct GetLatency();
+
+#[derive(Message)]
+#[rtype(result = "PeerInfo")]
+/// Query details of the peer and the connection.
+pub struct GetPeerInfo();
+
+/// A response of GetPeerInfo, like `getpeerinfo` RPC of Bitcoin Core.
+#[derive(Debug, Clone)]
+pub struct PeerInfo
+{
+    /// A connection itself knows only the socket address, which is unspecified if we connect to a domain
+    /// (e.g. ".onion" address) through proxy. `GetAllPeerInfo` of `ConnectionPool` fills the domain.
+    pub addr: TargetAddr,
+    pub inbound: bool,
+    pub version: PeerVersion,
+    pub features: NegotiatedFeatures,
+    pub connected_at: SystemTime,
+    /// Round-trip time of the latest `ping`.
+    pub latency: Option<Duration>,
+    /// Bytes on the wire, including BIP324 encryption overhead.
+    pub bytes_sent: u64,
+    pub bytes_recv: u64,
+    pub misbehavior_score: u32,
+}
 
 #[derive(Message)]
 /// Force to gracefully shutdown connection.
 pub struct Disconnect();
 
+#[derive(Message)]
+/// Report misbehavior of peer which others find, e.g. an invalid block header.
+/// Scores are accumulated, and peer is banned when the total reaches `BAN_THRESHOLD`.
+/// `ConnectionPool` keeps the total of each address, so it adds up even if peer reconnects.
+pub struct Misbehave
+{
+    pub score: u32,
+    pub reason: String,
+}
+
+#[derive(Message)]
+/// Start to subscribe misbehavior of the peer.
+/// `Connection` sends `ReportMisbehavior` to the subscriber on each offense, so that the subscriber can keep the
+/// score after the connection is gone.
+pub struct SubscribeMisbehavior
+{
+    pub addr: Recipient<ReportMisbehavior>,
+}
+
 /// # Note
-/// The behavior of `Connection` follows bitcoin protocol.
-/// e.g. after GetBlocksRequest is sent, if connecting peer couldn't find requested block peer does
-/// not response anything.
-/// So `Connection` is also response nothing.
+/// Several actors can share one `Connection`.
+/// Requests of blocks are pipelined up to `MAX_BLOCKS_IN_FLIGHT` blocks, and others are queued.
+/// Requests of headers are processed one by one since a `headers` message does not tell which
+/// request it answers. So are requests of compact filters.
 pub struct Connection
 {
     // it should not be None except during waiting to complete sending
-    write_socket: Option<HandshakedSocket<WriteHalf<TcpStream>>>,
+    write_socket: Option<HandshakedSocket<Box<dyn AsyncWrite>>>,
     socket_stream_handle: SpawnHandle,
+    // Peer may announce some features after handshake, so we keep track of them here.
+    features: NegotiatedFeatures,
+    peer_version: PeerVersion,
+    peer_addr: SocketAddr,
+    inbound: bool,
+    connected_at: SystemTime,
+    byte_counts: ByteCounts,
 
-    waiting_blocks: Option<WaitingBlocks>,
+    timeouts: RequestTimeouts,
+    ping_handle: Option<SpawnHandle>,
+    // Nonce and sent time of a `ping` waiting for `pong`.
+    waiting_pong: Option<(u64, Instant)>,
+    latency: Option<Duration>,
+    rng: XorShiftRng,
+
+    waiting_blocks: Vec<WaitingBlocks>,
+    queued_blocks: VecDeque<GetBlocksRequest>,
+    next_request_id: u64,
     waiting_headers: Option<WaitingHeaders>,
-    subscribe_invs: Option<Recipient<PublishInv>>,
-    waiting_addrs: Option<Recipient<AddrsResponse>>,
+    queued_headers: VecDeque<GetHeadersRequest>,
+    waiting_filters: Option<WaitingFilters>,
+    queued_filters: VecDeque<FilterRequest>,
+    waiting_txs: Vec<WaitingTxs>,
+    waiting_merkle_blocks: Vec<WaitingMerkleBlocks>,
+    // A verified `merkleblock` whose matched transactions are arriving.
+    receiving_merkle_block: Option<ReceivingMerkleBlock>,
+    subscribe_txs: Option<Recipient<PublishTx>>,
+    // Transactions which we announced and will serve on request.
+    relay_txs: HashMap<Sha256dHash, Transaction>,
+    // Set when we use compact blocks.
+    tx_pool: Option<Arc<Mutex<dyn TxPool + Send>>>,
+    waiting_cmpct_blocks: HashMap<Sha256dHash, WaitingCmpctBlock>,
+    subscribe_blocks: Option<Recipient<PublishBlock>>,
+    subscribe_invs: Vec<InvSubscriber>,
+    subscribe_headers: Vec<Recipient<PublishHeaders>>,
+    // `sendheaders` is sent once someone subscribes headers, since peer stops announcing blocks by `inv` after it.
+    sent_send_headers: bool,
+    subscribe_unknowns: Option<Recipient<PublishUnknown>>,
+    // All of them receive the next `addr` message.
+    waiting_addrs: Vec<Recipient<AddrsResponse>>,
+    serve_chain: Option<ServeChain>,
+
+    misbehavior_score: u32,
+    subscribe_misbehavior: Option<Recipient<ReportMisbehavior>>,
 }
 
 impl Actor for Connection
 {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>)
+    {
+        self.send_ping(ctx);
+        self.schedule_ping(ctx);
+    }
 }
 
 impl Connection
 {
-    pub fn start_actor(socket: HandshakedSocket<TcpStream>) -> Addr<Self>
+    pub fn start_actor<S>(socket: HandshakedSocket<S>) -> Addr<Self>
+    where S: AsyncRead + AsyncWrite + 'static
     {
         <Connection as Actor>::create(move |ctx| Connection::create(socket, ctx))
     }
 
-    pub fn start_actor_on(
-        socket: HandshakedSocket<TcpStream>,
-        arbiter: Addr<Arbiter>,
-    ) -> Result<Addr<Self>, MailboxError>
+    pub fn start_actor_on<S>(socket: HandshakedSocket<S>, arbiter: Addr<Arbiter>) -> Result<Addr<Self>, MailboxError>
+    where S: AsyncRead + AsyncWrite + Send + 'static
     {
         let start_actor = StartActor::new(move |ctx| Connection::create(socket, ctx));
         arbiter.send(start_actor).wait()
     }
 
-    pub fn create(socket: HandshakedSocket<TcpStream>, ctx: &mut Context<Self>) -> Connection
+    pub fn create<S>(socket: HandshakedSocket<S>, ctx: &mut Context<Self>) -> Connection
+    where S: AsyncRead + AsyncWrite + 'static
+    {
+        // Sockets which count bytes below the transport layer already (e.g. by `ConnectionPool`) are left as they are.
+        match socket.byte_counts().cloned() {
+            Some(byte_counts) => Connection::create_counted(socket, byte_counts, ctx),
+            None => {
+                let socket = socket.count_bytes();
+                let byte_counts = socket.byte_counts().cloned().unwrap_or_default();
+                Connection::create_counted(socket, byte_counts, ctx)
+            },
+        }
+    }
+
+    fn create_counted<S>(socket: HandshakedSocket<S>, byte_counts: ByteCounts, ctx: &mut Context<Self>) -> Connection
+    where S: AsyncRead + AsyncWrite + 'static
     {
         let (read_socket, write_socket) = socket.split();
 
         let msg_stream = read_socket.recv_msg_stream().map(|m| P2PMessage(m));
         let socket_stream_handle = ctx.add_stream(msg_stream);
 
-        Connection::new(write_socket, socket_stream_handle)
+        // Erase a type of underlying stream so that `Connection` does not depend on it.
+        let write_socket = write_socket.map_stream(|w| Box::new(w) as Box<dyn AsyncWrite>);
+
+        Connection::new(write_socket, socket_stream_handle, byte_counts)
     }
 
-    fn new(write_socket: HandshakedSocket<WriteHalf<TcpStream>>, socket_stream_handle: SpawnHandle) -> Connection
+    fn new(
+        write_socket: HandshakedSocket<Box<dyn AsyncWrite>>,
+        socket_stream_handle: SpawnHandle,
+        byte_counts: ByteCounts,
+    ) -> Connection
     {
         Connection {
+            features: write_socket.features().clone(),
+            peer_version: write_socket.peer_version().clone(),
+            peer_addr: write_socket.peer_addr(),
+            inbound: write_socket.is_inbound(),
+            connected_at: SystemTime::now(),
+            byte_counts,
             write_socket: Some(write_socket),
             socket_stream_handle,
 
-            waiting_blocks: None,
+            timeouts: RequestTimeouts::default(),
+            ping_handle: None,
+            waiting_pong: None,
+            latency: None,
+            rng: XorShiftRng::from_entropy(),
+
+            waiting_blocks: Vec::new(),
+            queued_blocks: VecDeque::new(),
+            next_request_id: 0,
             waiting_headers: None,
-            subscribe_invs: None,
-            waiting_addrs: None,
+            queued_headers: VecDeque::new(),
+            waiting_filters: None,
+            queued_filters: VecDeque::new(),
+            waiting_txs: Vec::new(),
+            waiting_merkle_blocks: Vec::new(),
+            receiving_merkle_block: None,
+            subscribe_txs: None,
+            relay_txs: HashMap::new(),
+            tx_pool: None,
+            waiting_cmpct_blocks: HashMap::new(),
+            subscribe_blocks: None,
+            subscribe_invs: Vec::new(),
+            subscribe_headers: Vec::new(),
+            sent_send_headers: false,
+            subscribe_unknowns: None,
+            waiting_addrs: Vec::new(),
+            serve_chain: None,
+
+            misbehavior_score: 0,
+            subscribe_misbehavior: None,
         }
     }
 
-    fn send_p2p_msg(&mut self, msg: NetworkMessage, ctx: &mut Context<Self>)
+    fn send_p2p_msg<M: Into<BtcMessage>>(&mut self, msg: M, ctx: &mut Context<Self>)
     {
         let write_socket = self.write_socket.take().expect("BUG!!");
         let f = write_socket
-            .send_msg(msg)
+            .send_msg(msg.into())
             .into_actor(self)
             .map(|socket, actor, _ctx| {
                 actor.write_socket = Some(socket);
@@ -153,6 +757,36 @@ impl Connection
     }
 }
 
+impl Handler<GetLatency> for Connection
+{
+    type Result = Option<Duration>;
+
+    fn handle(&mut self, _msg: GetLatency, _ctx: &mut Context<Self>) -> Option<Duration>
+    {
+        self.latency
+    }
+}
+
+impl Handler<GetPeerInfo> for Connection
+{
+    type Result = MessageResult<GetPeerInfo>;
+
+    fn handle(&mut self, _msg: GetPeerInfo, _ctx: &mut Context<Self>) -> MessageResult<GetPeerInfo>
+    {
+        MessageResult(PeerInfo {
+            addr: TargetAddr::Ip(self.peer_addr),
+            inbound: self.inbound,
+            version: self.peer_version.clone(),
+            features: self.features.clone(),
+            connected_at: self.connected_at,
+            latency: self.latency,
+            bytes_sent: self.byte_counts.sent(),
+            bytes_recv: self.byte_counts.recv(),
+            misbehavior_score: self.misbehavior_score,
+        })
+    }
+}
+
 impl Handler<Disconnect> for Connection
 {
     type Result = ();
@@ -173,194 +807,2263 @@ impl StreamHandler<P2PMessage, Error> for Connection
     fn handle(&mut self, msg: P2PMessage, ctx: &mut Self::Context)
     {
         use self::NetworkMessage::*;
+        // Transactions of a merkle block follow it, so any other message means peer sends no more of them.
+        match msg.0 {
+            BtcMessage::Network(Tx(_)) => {},
+            _ => self.finish_merkle_block(ctx),
+        }
         match msg.0 {
-            Addr(addrs) => self.handle_addr_msg(addrs, ctx),
-            Inv(invs) => self.handle_invs_msg(invs, ctx),
-            Block(block) => self.handle_block_msg(block, ctx),
-            Headers(headers) => self.handle_headers_msg(headers, ctx),
-            Ping(nonce) => self.handle_ping_msg(nonce, ctx),
+            BtcMessage::Network(Addr(addrs)) => {
+                let addrs = addrs.iter().map(|(time, addr)| PeerAddr::from_legacy(*time, addr)).collect();
+                self.handle_addr_msg(addrs, ctx);
+            },
+            BtcMessage::AddrV2(addrs) => self.handle_addr_msg(addrs, ctx),
+            BtcMessage::Network(Inv(invs)) => self.handle_invs_msg(invs, ctx),
+            BtcMessage::Network(Block(block)) => self.handle_block_msg(block, ctx),
+            BtcMessage::Network(NotFound(invs)) => self.handle_notfound_msg(invs, ctx),
+            BtcMessage::Network(Tx(tx)) => self.handle_tx_msg(tx, ctx),
+            BtcMessage::Network(GetData(invs)) => self.handle_getdata_msg(invs, ctx),
+            BtcMessage::Network(GetHeaders(msg)) => self.handle_getheaders_msg(msg, ctx),
+            BtcMessage::Network(GetBlocks(msg)) => self.handle_getblocks_msg(msg, ctx),
+            BtcMessage::Network(Headers(headers)) => self.handle_headers_msg(headers, ctx),
+            BtcMessage::Network(Ping(nonce)) => self.handle_ping_msg(nonce, ctx),
+            BtcMessage::Network(Pong(nonce)) => self.handle_pong_msg(nonce),
+            BtcMessage::SendHeaders => self.features.send_headers = true,
+            BtcMessage::SendCmpct { announce, version } => self.handle_sendcmpct_msg(announce, version),
+            BtcMessage::CmpctBlock(cmpct) => self.handle_cmpctblock_msg(cmpct, ctx),
+            BtcMessage::GetBlockTxn(req) => self.handle_getblocktxn_msg(req, ctx),
+            BtcMessage::BlockTxn(txn) => self.handle_blocktxn_msg(txn, ctx),
+            BtcMessage::CFilter(cfilter) => self.handle_cfilter_msg(cfilter, ctx),
+            BtcMessage::CFHeaders(cfheaders) => self.handle_cfheaders_msg(cfheaders, ctx),
+            BtcMessage::CFCheckpt(cfcheckpt) => self.handle_cfcheckpt_msg(cfcheckpt, ctx),
+            BtcMessage::GetCFilters(req) => self.handle_getcfilters_msg(req, ctx),
+            BtcMessage::GetCFHeaders(req) => self.handle_getcfheaders_msg(req, ctx),
+            BtcMessage::GetCFCheckpt(req) => self.handle_getcfcheckpt_msg(req, ctx),
+            BtcMessage::MerkleBlock(merkle_block) => self.handle_merkleblock_msg(merkle_block, ctx),
+            BtcMessage::GetDataCmpct { invs, cmpct_block_hashes } => {
+                self.handle_getdata_msg(invs, ctx);
+                self.handle_getdata_cmpct_msg(cmpct_block_hashes, ctx);
+            },
+            BtcMessage::NotFoundFiltered { invs, filtered_block_hashes } => {
+                self.handle_notfound_msg(invs, ctx);
+                self.handle_notfound_filtered_msg(filtered_block_hashes, ctx);
+            },
+            BtcMessage::Unknown { command, payload } => self.handle_unknown_msg(command, payload, ctx),
             another => {
                 info!("Receive unexpected network msg. {:?}", another);
             },
         }
     }
 
-    fn error(&mut self, err: Error, _ctx: &mut Self::Context) -> Running
+    fn error(&mut self, err: Error, ctx: &mut Self::Context) -> Running
     {
         info!("Catch error on socket : {:?}", err);
+        match err.downcast_ref::<ConnectionError>() {
+            Some(ConnectionError::MisbehavePeer) | Some(ConnectionError::PayloadTooLarge { .. }) => {
+                self.misbehave(BAN_THRESHOLD, "invalid message", ctx);
+            },
+            _ => {},
+        }
         Running::Stop
     }
 }
 
 struct WaitingBlocks
 {
+    id: u64,
     addr: Recipient<BlockResponse>,
     block_hashes: Vec<Sha256dHash>,
+    timeout_handle: SpawnHandle,
+}
+
+/// Blocks of active chain following the fork point with peer, up to `stop_hash` or `max` blocks.
+/// If no block of the locator is in active chain, the fork point is genesis as in Bitcoin Core.
+/// `None` if active chain does not start from genesis either.
+fn blocks_after_fork(
+    blockchain: &BlockChain,
+    locator_hashes: &[Sha256dHash],
+    stop_hash: &Sha256dHash,
+    max: usize,
+) -> Option<Vec<BlockData>>
+{
+    let active_chain = blockchain.active_chain();
+    let fork_height = match active_chain.find_fork_point(locator_hashes) {
+        Some(fork) => fork.height(),
+        None => active_chain.get_block(0)?.height(),
+    };
+    let mut blocks = Vec::new();
+    for height in fork_height + 1.. {
+        let block = match active_chain.get_block(height) {
+            None => break,
+            Some(block) => *block,
+        };
+        blocks.push(block);
+        if blocks.len() == max || block.bitcoin_hash() == *stop_hash {
+            break;
+        }
+    }
+    Some(blocks)
+}
+
+/// Returns true if transactions of the block hash to the merkle root in its header.
+pub(crate) fn has_valid_merkle_root(block: &Block) -> bool
+{
+    bitcoin_merkle_root(block.txdata.iter().map(|tx| tx.txid()).collect()) == block.header.merkle_root
+}
+
+fn strip_witness(tx: &mut Transaction)
+{
+    tx.input.iter_mut().for_each(|input| input.witness.clear());
+}
+
+struct InvSubscriber
+{
+    addr: Recipient<PublishInv>,
+    inv_types: Vec<InvType>,
+}
+
+struct WaitingTxs
+{
+    id: u64,
+    addr: Recipient<TxResponse>,
+    txids: Vec<Sha256dHash>,
+    timeout_handle: SpawnHandle,
+}
+
+struct WaitingMerkleBlocks
+{
+    id: u64,
+    addr: Recipient<MerkleBlockResponse>,
+    block_hashes: Vec<Sha256dHash>,
+    timeout_handle: SpawnHandle,
+}
+
+struct ReceivingMerkleBlock
+{
+    header: BlockHeader,
+    txids: Vec<Sha256dHash>,
+    txs: Vec<Transaction>,
 }
 
 struct WaitingHeaders
 {
     addr: Recipient<HeadersResponse>,
+    locator_hashes: Vec<Sha256dHash>,
+    timeout_handle: SpawnHandle,
 }
 
-impl Connection
+enum FilterRequest
+{
+    Filters(GetFiltersRequest),
+    Headers(GetFilterHeadersRequest),
+    Checkpt(GetFilterCheckptRequest),
+}
+
+struct WaitingFilters
+{
+    req: FilterRequest,
+    // `cfilter` messages which have arrived so far.
+    filters: Vec<CFilter>,
+    timeout_handle: SpawnHandle,
+}
+
+impl FilterRequest
 {
-    fn stop_misbehaving_connection(&mut self, ctx: &mut Context<Self>)
+    fn to_msg(&self) -> BtcMessage
     {
-        info!("Peer misbehaves. Close connection");
-        ctx.stop();
+        match self {
+            FilterRequest::Filters(req) => {
+                BtcMessage::GetCFilters(GetCFilters {
+                    filter_type: BASIC_FILTER_TYPE,
+                    start_height: req.start_height,
+                    stop_hash: req.stop_hash,
+                })
+            },
+            FilterRequest::Headers(req) => {
+                BtcMessage::GetCFHeaders(GetCFilters {
+                    filter_type: BASIC_FILTER_TYPE,
+                    start_height: req.start_height,
+                    stop_hash: req.stop_hash,
+                })
+            },
+            FilterRequest::Checkpt(req) => {
+                BtcMessage::GetCFCheckpt(GetCFCheckpt {
+                    filter_type: BASIC_FILTER_TYPE,
+                    stop_hash: req.stop_hash,
+                })
+            },
+        }
     }
+}
 
-    fn handle_addr_msg(&mut self, addrs: Vec<(u32, Address)>, ctx: &mut Context<Self>)
-    {
-        if let Some(sender) = self.waiting_addrs.take() {
+struct WaitingCmpctBlock
+{
+    // Empty if peer sends `cmpctblock` without our request. Then the block is published.
+    requesters: Vec<Recipient<BlockResponse>>,
+    // Set after `cmpctblock` arrives, while we wait for `blocktxn`.
+    partial: Option<PartialBlock>,
+    timeout_handle: SpawnHandle,
+}
+
+impl Connection
+{
+    fn misbehave(&mut self, score: u32, reason: &str, ctx: &mut Context<Self>)
+    {
+        let ban = add_misbehavior_score(&mut self.misbehavior_score, score);
+        info!("Peer misbehaves : {} (score {})", reason, self.misbehavior_score);
+        if let Some(subscriber) = self.subscribe_misbehavior.as_ref() {
+            let _ = subscriber.do_send(ReportMisbehavior {
+                conn: ctx.address(),
+                score,
+            });
+        }
+        if ban {
+            info!("Ban peer and close connection");
+            ctx.stop();
+        }
+    }
+
+    fn handle_addr_msg(&mut self, addrs: Vec<PeerAddr>, ctx: &mut Context<Self>)
+    {
+        if self.waiting_addrs.is_empty() {
+            debug!("Discard Addr msg");
+        }
+        for sender in ::std::mem::replace(&mut self.waiting_addrs, Vec::new()) {
             let f = sender
-                .send(AddrsResponse(addrs))
+                .send(AddrsResponse(addrs.clone()))
                 .timeout(SEND_TIMEOUT)
                 .map_err(|_e| ())
                 .into_actor(self);
             let _ = ctx.spawn(f);
-        } else {
-            debug!("Discard Addr msg");
         }
     }
 
     fn handle_block_msg(&mut self, block: Block, ctx: &mut Context<Connection>)
     {
-        if let Some(mut waiting) = self.waiting_blocks.take() {
-            let block_hash = block.bitcoin_hash();
-            let maybe_idx = waiting.block_hashes.iter().position(|h| *h == block_hash);
-            match maybe_idx {
-                None => {
-                    self.stop_misbehaving_connection(ctx);
-                    return;
-                },
-                Some(idx) => waiting.block_hashes.remove(idx),
-            };
-            let send_f = waiting.addr.send(BlockResponse(block)).timeout(SEND_TIMEOUT);
-            let f = send_f.into_actor(self).map_err(|e, _actor, _ctx| {
-                debug!("Fail to send msg : {:?}", e);
-            });
-            let _ = ctx.spawn(f);
+        let block_hash = block.bitcoin_hash();
+        // The hash covers only the header, so peer may send other transactions under a valid block hash.
+        if !has_valid_merkle_root(&block) {
+            return self.misbehave(BAN_THRESHOLD, "block with wrong merkle root", ctx);
+        }
+        if self.waiting_blocks.iter().any(|w| w.block_hashes.contains(&block_hash)) {
+            let _ = self.remove_waiting_block(&block_hash, BlockResponse::Block(block), ctx);
+            return;
+        }
 
-            if !waiting.block_hashes.is_empty() {
-                self.waiting_blocks = Some(waiting);
-            }
+        // A block may arrive after its request timed out, so it is not misbehavior.
+        if self.subscribe_blocks.is_some() {
+            self.publish_block(block, ctx);
+        } else {
+            debug!("Discard block which we do not wait : {}", block_hash);
         }
     }
 
-    fn handle_invs_msg(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>)
+    fn publish_block(&mut self, block: Block, ctx: &mut Context<Self>)
     {
-        if let Some(ref subscriber) = self.subscribe_invs.as_ref() {
-            let send_f = subscriber.send(PublishInv(invs)).timeout(SEND_TIMEOUT);
+        if let Some(ref subscriber) = self.subscribe_blocks.as_ref() {
+            let send_f = subscriber.send(PublishBlock(block)).timeout(SEND_TIMEOUT);
             let f = send_f.into_actor(self).map_err(|e, actor, _ctx| {
                 debug!("Fail to send msg : {:?}", e);
-                actor.subscribe_invs = None;
+                actor.subscribe_blocks = None;
             });
             ctx.spawn(f);
-        } else {
-            debug!("Peer sends Inv message but no subscriber is set, so discard it.");
         }
     }
 
-    fn handle_headers_msg(&mut self, headers: Vec<LoneBlockHeader>, ctx: &mut Context<Self>)
+    fn handle_cmpctblock_msg(&mut self, cmpct: HeaderAndShortIds, ctx: &mut Context<Self>)
     {
-        let maybe_waiting_headers = self.waiting_headers.take();
-        match maybe_waiting_headers {
+        let block_hash = cmpct.header.bitcoin_hash();
+        match self.waiting_cmpct_blocks.get(&block_hash) {
+            Some(waiting) if waiting.partial.is_some() => {
+                debug!("Discard duplicate cmpctblock : {}", block_hash);
+                return;
+            },
+            None if self.subscribe_blocks.is_none() => {
+                debug!("Discard cmpctblock which we do not wait : {}", block_hash);
+                return;
+            },
+            _ => {},
+        }
+        let mut pool = match self.tx_pool.as_ref() {
             None => {
-                info!("We don't wait headers but received.");
-                self.stop_misbehaving_connection(ctx);
+                debug!("Discard cmpctblock since we do not use compact blocks");
+                return;
             },
-            Some(waiting_headers) => {
-                let f = waiting_headers
-                    .addr
-                    .send(HeadersResponse(headers))
-                    .map_err(|_e| ())
-                    .into_actor(self);
-                ctx.wait(f);
+            Some(tx_pool) => tx_pool.lock().unwrap().transactions(),
+        };
+        pool.extend(self.relay_txs.values().cloned());
+        // A transaction which we relay is usually in the pool as well. Keep one copy, or it looks ambiguous.
+        let mut wtxids = HashSet::with_capacity(pool.len());
+        pool.retain(|tx| wtxids.insert(tx.bitcoin_hash()));
+
+        let partial = match PartialBlock::new(&cmpct, &pool) {
+            None => return self.request_full_block(block_hash, ctx),
+            Some(partial) => partial,
+        };
+        let missing = partial.missing_indexes();
+        if missing.is_empty() {
+            return self.finish_cmpct_block(block_hash, partial, ctx);
+        }
+
+        debug!("Request {} transactions of compact block {}", missing.len(), block_hash);
+        let req = BlockTxnRequest {
+            block_hash,
+            indexes: missing,
+        };
+        self.send_p2p_msg(BtcMessage::GetBlockTxn(req), ctx);
+        if !self.waiting_cmpct_blocks.contains_key(&block_hash) {
+            let timeout_handle = ctx.run_later(self.timeouts.block, move |actor, ctx| {
+                actor.cmpct_block_timed_out(block_hash, ctx)
+            });
+            let waiting = WaitingCmpctBlock {
+                requesters: Vec::new(),
+                partial: None,
+                timeout_handle,
+            };
+            self.waiting_cmpct_blocks.insert(block_hash, waiting);
+        }
+        self.waiting_cmpct_blocks.get_mut(&block_hash).unwrap().partial = Some(partial);
+    }
+
+    fn handle_blocktxn_msg(&mut self, txn: BlockTxn, ctx: &mut Context<Self>)
+    {
+        let maybe_partial = self.waiting_cmpct_blocks
+            .get_mut(&txn.block_hash)
+            .and_then(|waiting| waiting.partial.take());
+        let mut partial = match maybe_partial {
+            None => {
+                debug!("Discard blocktxn which we do not wait : {}", txn.block_hash);
+                return;
             },
+            Some(partial) => partial,
+        };
+        if partial.fill(txn.txs).is_err() {
+            self.misbehave(MINOR_MISBEHAVIOR_SCORE, "blocktxn with wrong number of transactions", ctx);
+            return;
         }
+        self.finish_cmpct_block(txn.block_hash, partial, ctx);
     }
 
-    fn handle_ping_msg(&mut self, nonce: u64, ctx: &mut Context<Self>)
+    fn finish_cmpct_block(&mut self, block_hash: Sha256dHash, partial: PartialBlock, ctx: &mut Context<Self>)
     {
-        let pong = NetworkMessage::Pong(nonce);
-        self.send_p2p_msg(pong, ctx);
+        let block = match partial.into_block() {
+            None => {
+                info!("Fail to reconstruct compact block {}", block_hash);
+                return self.request_full_block(block_hash, ctx);
+            },
+            Some(block) => block,
+        };
+
+        let requesters = self.remove_waiting_cmpct_block(&block_hash, ctx);
+        if requesters.is_empty() {
+            return self.publish_block(block, ctx);
+        }
+        for addr in requesters {
+            self.send_block_response(&addr, BlockResponse::Block(block.clone()), ctx);
+        }
     }
-}
 
-/* Handle GetBlocksRequest */
+    /// Fall back to `getdata` of the full block.
+    fn request_full_block(&mut self, block_hash: Sha256dHash, ctx: &mut Context<Self>)
+    {
+        let requesters = self.remove_waiting_cmpct_block(&block_hash, ctx);
+        if requesters.is_empty() {
+            // The block is published when it arrives.
+            let inv = Inventory {
+                inv_type: InvType::Block,
+                hash: block_hash,
+            };
+            self.send_p2p_msg(NetworkMessage::GetData(vec![inv]), ctx);
+        }
+        for addr in requesters {
+            self.queued_blocks.push_back(GetBlocksRequest {
+                block_hashes: vec![block_hash],
+                addr,
+            });
+        }
+        self.dispatch_queued_blocks(ctx);
+    }
 
-impl Handler<GetBlocksRequest> for Connection
-{
-    type Result = ();
+    fn remove_waiting_cmpct_block(
+        &mut self,
+        block_hash: &Sha256dHash,
+        ctx: &mut Context<Self>,
+    ) -> Vec<Recipient<BlockResponse>>
+    {
+        match self.waiting_cmpct_blocks.remove(block_hash) {
+            None => Vec::new(),
+            Some(waiting) => {
+                ctx.cancel_future(waiting.timeout_handle);
+                waiting.requesters
+            },
+        }
+    }
 
-    fn handle(&mut self, req: GetBlocksRequest, ctx: &mut Context<Connection>)
+    fn cmpct_block_timed_out(&mut self, block_hash: Sha256dHash, ctx: &mut Context<Self>)
+    {
+        if let Some(waiting) = self.waiting_cmpct_blocks.remove(&block_hash) {
+            info!("Peer does not deliver compact block {} in time", block_hash);
+            for addr in waiting.requesters {
+                self.send_block_response(&addr, BlockResponse::TimedOut(block_hash), ctx);
+            }
+        }
+    }
+
+    fn send_response<M>(&mut self, addr: &Recipient<M>, res: M, ctx: &mut Context<Self>)
+    where M: Message<Result = ()> + Send + 'static
+    {
+        let send_f = addr.send(res).timeout(SEND_TIMEOUT);
+        let f = send_f.into_actor(self).map_err(|e, _actor, _ctx| {
+            debug!("Fail to send msg : {:?}", e);
+        });
+        let _ = ctx.spawn(f);
+    }
+
+    fn queue_filter_request(&mut self, req: FilterRequest, ctx: &mut Context<Self>)
     {
-        if self.waiting_blocks.is_some() {
-            info!("Can not request GetBlockRequest in parallel. A new request is dropped.");
+        if self.peer_version.services & NODE_COMPACT_FILTERS == 0 {
+            match req {
+                FilterRequest::Filters(req) => self.send_response(&req.addr, FiltersResponse::Unsupported, ctx),
+                FilterRequest::Headers(req) => self.send_response(&req.addr, FilterHeadersResponse::Unsupported, ctx),
+                FilterRequest::Checkpt(req) => self.send_response(&req.addr, FilterCheckptResponse::Unsupported, ctx),
+            }
+            return;
+        }
+        if self.queued_filters.len() >= MAX_QUEUED_REQUESTS {
+            info!("Too many filter requests are queued");
+            match req {
+                FilterRequest::Filters(req) => self.send_response(&req.addr, FiltersResponse::QueueFull, ctx),
+                FilterRequest::Headers(req) => self.send_response(&req.addr, FilterHeadersResponse::QueueFull, ctx),
+                FilterRequest::Checkpt(req) => self.send_response(&req.addr, FilterCheckptResponse::QueueFull, ctx),
+            }
             return;
         }
+        self.queued_filters.push_back(req);
+        self.dispatch_queued_filters(ctx);
+    }
 
-        // Send Inv message to peer
-        let invs: Vec<_> = req.block_hashes
-            .iter()
-            .map(|hash| {
-                Inventory {
-                    inv_type: InvType::Block,
-                    hash: *hash,
+    fn queue_blocks_request(&mut self, req: GetBlocksRequest, ctx: &mut Context<Self>)
+    {
+        if self.queued_blocks.len() >= MAX_QUEUED_REQUESTS {
+            info!("Too many block requests are queued");
+            for hash in req.block_hashes {
+                self.send_block_response(&req.addr, BlockResponse::QueueFull(hash), ctx);
+            }
+            return;
+        }
+        self.queued_blocks.push_back(req);
+        self.dispatch_queued_blocks(ctx);
+    }
+
+    fn dispatch_queued_filters(&mut self, ctx: &mut Context<Self>)
+    {
+        if self.waiting_filters.is_some() {
+            return;
+        }
+        if let Some(req) = self.queued_filters.pop_front() {
+            self.send_p2p_msg(req.to_msg(), ctx);
+            let timeout_handle = ctx.run_later(self.timeouts.filters, |actor, ctx| actor.filters_timed_out(ctx));
+            self.waiting_filters = Some(WaitingFilters {
+                req,
+                filters: Vec::new(),
+                timeout_handle,
+            });
+        }
+    }
+
+    fn filters_timed_out(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(waiting) = self.waiting_filters.take() {
+            info!("Peer does not answer filter request in time");
+            match waiting.req {
+                FilterRequest::Filters(req) => self.send_response(&req.addr, FiltersResponse::TimedOut, ctx),
+                FilterRequest::Headers(req) => self.send_response(&req.addr, FilterHeadersResponse::TimedOut, ctx),
+                FilterRequest::Checkpt(req) => self.send_response(&req.addr, FilterCheckptResponse::TimedOut, ctx),
+            }
+            self.dispatch_queued_filters(ctx);
+        }
+    }
+
+    fn handle_cfilter_msg(&mut self, cfilter: CFilter, ctx: &mut Context<Self>)
+    {
+        let is_last = match self.waiting_filters.as_mut() {
+            Some(WaitingFilters {
+                req: FilterRequest::Filters(ref req),
+                ref mut filters,
+                ..
+            }) => {
+                // Extra filters are discarded, and the request times out unless peer sends the last one.
+                if filters.len() == MAX_GETCFILTERS_SIZE as usize {
+                    return self.misbehave(MINOR_MISBEHAVIOR_SCORE, "too many cfilter", ctx);
                 }
-            })
-            .collect();
-        let msg = NetworkMessage::GetData(invs);
-        self.send_p2p_msg(msg, ctx);
+                filters.push(cfilter.clone());
+                cfilter.block_hash == req.stop_hash
+            },
+            _ => {
+                debug!("Discard cfilter which we do not wait : {}", cfilter.block_hash);
+                return;
+            },
+        };
+        if is_last {
+            let waiting = self.waiting_filters.take().unwrap();
+            ctx.cancel_future(waiting.timeout_handle);
+            if let FilterRequest::Filters(req) = waiting.req {
+                self.send_response(&req.addr, FiltersResponse::Filters(waiting.filters), ctx);
+            }
+            self.dispatch_queued_filters(ctx);
+        }
+    }
 
-        let waiting_blocks = WaitingBlocks {
-            addr: req.addr,
-            block_hashes: req.block_hashes,
+    fn handle_cfheaders_msg(&mut self, cfheaders: CFHeaders, ctx: &mut Context<Self>)
+    {
+        let is_waiting = match self.waiting_filters.as_ref() {
+            Some(WaitingFilters {
+                req: FilterRequest::Headers(ref req),
+                ..
+            }) => req.stop_hash == cfheaders.stop_hash,
+            _ => false,
+        };
+        if !is_waiting {
+            debug!("Discard cfheaders which we do not wait : {}", cfheaders.stop_hash);
+            return;
+        }
+
+        let waiting = self.waiting_filters.take().unwrap();
+        ctx.cancel_future(waiting.timeout_handle);
+        if let FilterRequest::Headers(req) = waiting.req {
+            self.send_response(&req.addr, FilterHeadersResponse::Headers(cfheaders), ctx);
+        }
+        self.dispatch_queued_filters(ctx);
+    }
+
+    fn handle_cfcheckpt_msg(&mut self, cfcheckpt: CFCheckpt, ctx: &mut Context<Self>)
+    {
+        let is_waiting = match self.waiting_filters.as_ref() {
+            Some(WaitingFilters {
+                req: FilterRequest::Checkpt(ref req),
+                ..
+            }) => req.stop_hash == cfcheckpt.stop_hash,
+            _ => false,
         };
-        self.waiting_blocks = Some(waiting_blocks);
+        if !is_waiting {
+            debug!("Discard cfcheckpt which we do not wait : {}", cfcheckpt.stop_hash);
+            return;
+        }
+
+        let waiting = self.waiting_filters.take().unwrap();
+        ctx.cancel_future(waiting.timeout_handle);
+        if let FilterRequest::Checkpt(req) = waiting.req {
+            self.send_response(&req.addr, FilterCheckptResponse::Checkpoint(cfcheckpt), ctx);
+        }
+        self.dispatch_queued_filters(ctx);
     }
-}
 
-/* Handle GetHeadersRequest */
+    fn handle_getdata_cmpct_msg(&mut self, block_hashes: Vec<Sha256dHash>, ctx: &mut Context<Self>)
+    {
+        // BIP152 allows `MSG_CMPCT_BLOCK` only in `getdata`, so `notfound` tells the hash as a block.
+        let mut not_found = Vec::new();
+        for hash in block_hashes {
+            let block = match self.stored_block(&hash) {
+                None => {
+                    debug!("Peer requests cmpctblock which we do not have : {}", hash);
+                    not_found.push(Inventory {
+                        inv_type: InvType::Block,
+                        hash,
+                    });
+                    continue;
+                },
+                Some(block) => block,
+            };
+            // Peer which does not understand our version of compact blocks gets the full block.
+            let msg = if self.features.compact_block_version == Some(COMPACT_BLOCK_VERSION) {
+                BtcMessage::CmpctBlock(HeaderAndShortIds::from_block(&block, self.rng.next_u64()))
+            } else {
+                BtcMessage::Network(NetworkMessage::Block(block))
+            };
+            self.send_p2p_msg(msg, ctx);
+        }
+        if !not_found.is_empty() {
+            self.send_p2p_msg(NetworkMessage::NotFound(not_found), ctx);
+        }
+    }
 
-impl Handler<GetHeadersRequest> for Connection
-{
-    type Result = ();
+    fn handle_getblocktxn_msg(&mut self, req: BlockTxnRequest, ctx: &mut Context<Self>)
+    {
+        let block = match self.stored_block(&req.block_hash) {
+            None => {
+                debug!("Peer requests blocktxn which we do not have : {}", req.block_hash);
+                return;
+            },
+            Some(block) => block,
+        };
+        match req.answer(&block) {
+            None => self.misbehave(MINOR_MISBEHAVIOR_SCORE, "getblocktxn with out-of-bounds indexes", ctx),
+            Some(txn) => self.send_p2p_msg(BtcMessage::BlockTxn(txn), ctx),
+        }
+    }
 
-    fn handle(&mut self, req: GetHeadersRequest, ctx: &mut Context<Self>)
+    fn handle_notfound_msg(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>)
+    {
+        // Peer may answer `notfound` to another node's request, so we do not care unknown hashes.
+        for inv in invs {
+            match inv.inv_type {
+                InvType::Block | InvType::WitnessBlock => {
+                    let _ = self.remove_waiting_block(&inv.hash, BlockResponse::NotFound(inv.hash), ctx);
+                },
+                InvType::Transaction | InvType::WitnessTransaction => {
+                    let _ = self.remove_waiting_tx(&inv.hash, TxResponse::NotFound(inv.hash), ctx);
+                },
+                InvType::Error => {},
+            }
+        }
+    }
+
+    // Merkle blocks are requested by `MSG_FILTERED_BLOCK`, so peer answers `notfound` with the same type.
+    fn handle_notfound_filtered_msg(&mut self, block_hashes: Vec<Sha256dHash>, ctx: &mut Context<Self>)
+    {
+        for hash in block_hashes {
+            let _ = self.remove_waiting_merkle_block(&hash, MerkleBlockResponse::NotFound(hash), ctx);
+        }
+    }
+
+    fn handle_tx_msg(&mut self, tx: Transaction, ctx: &mut Context<Self>)
     {
-        if self.waiting_headers.is_some() {
-            info!("Can not request GetHeadersRequest in parallel. A new request is dropped.");
+        let txid = tx.txid();
+        if let Some(receiving) = self.receiving_merkle_block.as_mut() {
+            if receiving.txids.contains(&txid) && receiving.txs.iter().all(|t| t.txid() != txid) {
+                receiving.txs.push(tx);
+                if receiving.txs.len() == receiving.txids.len() {
+                    self.finish_merkle_block(ctx);
+                }
+                return;
+            }
+        }
+        if self.waiting_txs.iter().any(|w| w.txids.contains(&txid)) {
+            let _ = self.remove_waiting_tx(&txid, TxResponse::Tx(tx), ctx);
             return;
         }
 
-        // Send GetHeaders message to peer
-        let getheaders = GetHeadersMessage::new(req.locator_hashes, Sha256dHash::default());
-        let msg = NetworkMessage::GetHeaders(getheaders);
-        self.send_p2p_msg(msg, ctx);
+        if let Some(ref subscriber) = self.subscribe_txs.as_ref() {
+            let send_f = subscriber.send(PublishTx(tx)).timeout(SEND_TIMEOUT);
+            let f = send_f.into_actor(self).map_err(|e, actor, _ctx| {
+                debug!("Fail to send msg : {:?}", e);
+                actor.subscribe_txs = None;
+            });
+            ctx.spawn(f);
+        } else {
+            debug!("Peer sends Tx message but no subscriber is set, so discard it.");
+        }
+    }
 
-        let waiting_headers = WaitingHeaders { addr: req.addr };
-        self.waiting_headers = Some(waiting_headers);
+    fn handle_getdata_msg(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>)
+    {
+        let mut not_found = Vec::new();
+        for inv in invs {
+            // Peer which does not understand segwit requests data without witness.
+            let msg = match inv.inv_type {
+                InvType::Transaction | InvType::WitnessTransaction => {
+                    self.relay_txs.get(&inv.hash).cloned().map(|mut tx| {
+                        if inv.inv_type == InvType::Transaction {
+                            strip_witness(&mut tx);
+                        }
+                        NetworkMessage::Tx(tx)
+                    })
+                },
+                InvType::Block | InvType::WitnessBlock => {
+                    self.stored_block(&inv.hash).map(|mut block| {
+                        if inv.inv_type == InvType::Block {
+                            block.txdata.iter_mut().for_each(strip_witness);
+                        }
+                        NetworkMessage::Block(block)
+                    })
+                },
+                InvType::Error => None,
+            };
+            match msg {
+                Some(msg) => self.send_p2p_msg(msg, ctx),
+                None => not_found.push(inv),
+            }
+        }
+        if !not_found.is_empty() {
+            self.send_p2p_msg(NetworkMessage::NotFound(not_found), ctx);
+        }
     }
-}
 
-/* Handle GetAddrsRequest */
+    fn stored_block(&self, hash: &Sha256dHash) -> Option<Block>
+    {
+        let store = self.serve_chain.as_ref()?.store.as_ref()?;
+        let block = store.lock().unwrap().get_block(hash);
+        block
+    }
 
-impl Handler<GetAddrsRequest> for Connection
-{
-    type Result = ();
+    fn handle_getheaders_msg(&mut self, msg: GetHeadersMessage, ctx: &mut Context<Self>)
+    {
+        let blocks = match self.serve_chain.as_ref() {
+            None => {
+                debug!("Discard getheaders since we do not serve chain");
+                return;
+            },
+            Some(serve_chain) => {
+                let blockchain = serve_chain.blockchain.lock().unwrap();
+                let max = MAX_HEADERS_ENTRIES as usize;
+                blocks_after_fork(&blockchain, &msg.locator_hashes, &msg.stop_hash, max)
+            },
+        };
+        let blocks = match blocks {
+            None => {
+                debug!("Discard getheaders since neither the locator nor genesis is known");
+                return;
+            },
+            Some(blocks) => blocks,
+        };
+        let headers = blocks
+            .into_iter()
+            .map(|b| {
+                LoneBlockHeader {
+                    header: b.header,
+                    tx_count: VarInt(0),
+                }
+            })
+            .collect();
+        self.send_p2p_msg(NetworkMessage::Headers(headers), ctx);
+    }
 
-    fn handle(&mut self, req: GetAddrsRequest, ctx: &mut Context<Self>)
+    fn handle_getblocks_msg(&mut self, msg: GetBlocksMessage, ctx: &mut Context<Self>)
     {
-        if self.waiting_addrs.is_some() {
-            info!("Can not request GetAddrsRequest in parallel. A new request is dropped.");
+        let blocks = match self.serve_chain.as_ref() {
+            None => {
+                debug!("Discard getblocks since we do not serve chain");
+                return;
+            },
+            Some(serve_chain) => {
+                let blockchain = serve_chain.blockchain.lock().unwrap();
+                blocks_after_fork(&blockchain, &msg.locator_hashes, &msg.stop_hash, MAX_BLOCKS_RESULTS)
+            },
+        };
+        // Unlike `getheaders`, an empty answer is just omitted.
+        let blocks = blocks.unwrap_or_default();
+        if blocks.is_empty() {
+            return;
         }
+        let invs = blocks
+            .into_iter()
+            .map(|b| {
+                Inventory {
+                    inv_type: InvType::Block,
+                    hash: b.bitcoin_hash(),
+                }
+            })
+            .collect();
+        self.send_p2p_msg(NetworkMessage::Inv(invs), ctx);
+    }
 
-        let msg = NetworkMessage::GetAddr;
-        self.send_p2p_msg(msg, ctx);
+    fn handle_getcfilters_msg(&mut self, req: GetCFilters, ctx: &mut Context<Self>)
+    {
+        match self.serve_chain.as_ref().and_then(|chain| chain.cfilters(&req)) {
+            None => debug!("Discard getcfilters which we cannot answer : {:?}", req),
+            Some(cfilters) => {
+                for cfilter in cfilters {
+                    self.send_p2p_msg(BtcMessage::CFilter(cfilter), ctx);
+                }
+            },
+        }
+    }
+
+    fn handle_getcfheaders_msg(&mut self, req: GetCFilters, ctx: &mut Context<Self>)
+    {
+        match self.serve_chain.as_ref().and_then(|chain| chain.cfheaders(&req)) {
+            None => debug!("Discard getcfheaders which we cannot answer : {:?}", req),
+            Some(cfheaders) => self.send_p2p_msg(BtcMessage::CFHeaders(cfheaders), ctx),
+        }
+    }
+
+    fn handle_getcfcheckpt_msg(&mut self, req: GetCFCheckpt, ctx: &mut Context<Self>)
+    {
+        match self.serve_chain.as_ref().and_then(|chain| chain.cfcheckpt(&req)) {
+            None => debug!("Discard getcfcheckpt which we cannot answer : {:?}", req),
+            Some(cfcheckpt) => self.send_p2p_msg(BtcMessage::CFCheckpt(cfcheckpt), ctx),
+        }
+    }
+
+    /// Send the response to all requesters of the transaction.
+    /// Returns `None` if we do not wait the transaction.
+    fn remove_waiting_tx(&mut self, txid: &Sha256dHash, res: TxResponse, ctx: &mut Context<Self>) -> Option<()>
+    {
+        let mut found = false;
+        for mut waiting in ::std::mem::replace(&mut self.waiting_txs, Vec::new()) {
+            if let Some(idx) = waiting.txids.iter().position(|h| h == txid) {
+                found = true;
+                waiting.txids.remove(idx);
+                self.send_tx_response(&waiting.addr, res.clone(), ctx);
+            }
+            if waiting.txids.is_empty() {
+                ctx.cancel_future(waiting.timeout_handle);
+            } else {
+                self.waiting_txs.push(waiting);
+            }
+        }
+        if found {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn send_tx_response(&mut self, addr: &Recipient<TxResponse>, res: TxResponse, ctx: &mut Context<Self>)
+    {
+        let send_f = addr.send(res).timeout(SEND_TIMEOUT);
+        let f = send_f.into_actor(self).map_err(|e, _actor, _ctx| {
+            debug!("Fail to send msg : {:?}", e);
+        });
+        let _ = ctx.spawn(f);
+    }
+
+    fn handle_merkleblock_msg(&mut self, merkle_block: MerkleBlock, ctx: &mut Context<Self>)
+    {
+        let block_hash = merkle_block.header.bitcoin_hash();
+        if !self.waiting_merkle_blocks.iter().any(|w| w.block_hashes.contains(&block_hash)) {
+            debug!("Discard merkleblock which we do not wait : {}", block_hash);
+            return;
+        }
+        // The header hashes to a block hash which requester takes from `BlockChain`, so we can trust its root.
+        let txids = match merkle_block.extract_matches() {
+            Some((root, txids)) if root == merkle_block.header.merkle_root => txids,
+            _ => return self.misbehave(BAN_THRESHOLD, "invalid partial merkle tree", ctx),
+        };
+        let complete = txids.is_empty();
+        self.receiving_merkle_block = Some(ReceivingMerkleBlock {
+            header: merkle_block.header,
+            txids,
+            txs: Vec::new(),
+        });
+        if complete {
+            self.finish_merkle_block(ctx);
+        }
+    }
 
-        self.waiting_addrs = Some(req.addr);
+    fn finish_merkle_block(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(receiving) = self.receiving_merkle_block.take() {
+            let block_hash = receiving.header.bitcoin_hash();
+            let res = MerkleBlockResponse::Block {
+                header: receiving.header,
+                txids: receiving.txids,
+                txs: receiving.txs,
+            };
+            let _ = self.remove_waiting_merkle_block(&block_hash, res, ctx);
+        }
+    }
+
+    /// Send the response to all requesters of the merkle block.
+    /// Returns `None` if we do not wait the merkle block.
+    fn remove_waiting_merkle_block(
+        &mut self,
+        hash: &Sha256dHash,
+        res: MerkleBlockResponse,
+        ctx: &mut Context<Self>,
+    ) -> Option<()>
+    {
+        let mut found = false;
+        for mut waiting in ::std::mem::replace(&mut self.waiting_merkle_blocks, Vec::new()) {
+            if let Some(idx) = waiting.block_hashes.iter().position(|h| h == hash) {
+                found = true;
+                waiting.block_hashes.remove(idx);
+                self.send_response(&waiting.addr, res.clone(), ctx);
+            }
+            if waiting.block_hashes.is_empty() {
+                ctx.cancel_future(waiting.timeout_handle);
+            } else {
+                self.waiting_merkle_blocks.push(waiting);
+            }
+        }
+        if found {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    fn merkle_blocks_timed_out(&mut self, id: u64, ctx: &mut Context<Self>)
+    {
+        if let Some(idx) = self.waiting_merkle_blocks.iter().position(|w| w.id == id) {
+            let waiting = self.waiting_merkle_blocks.remove(idx);
+            info!("Peer does not deliver {} merkle blocks in time", waiting.block_hashes.len());
+            // Drop the merkle block whose transactions are still arriving, unless another request waits it.
+            let receiving_hash = self.receiving_merkle_block.as_ref().map(|r| r.header.bitcoin_hash());
+            if let Some(hash) = receiving_hash {
+                let still_waited = self.waiting_merkle_blocks.iter().any(|w| w.block_hashes.contains(&hash));
+                if waiting.block_hashes.contains(&hash) && !still_waited {
+                    self.receiving_merkle_block = None;
+                }
+            }
+            for hash in waiting.block_hashes {
+                self.send_response(&waiting.addr, MerkleBlockResponse::TimedOut(hash), ctx);
+            }
+        }
+    }
+
+    fn txs_timed_out(&mut self, id: u64, ctx: &mut Context<Self>)
+    {
+        if let Some(idx) = self.waiting_txs.iter().position(|w| w.id == id) {
+            let waiting = self.waiting_txs.remove(idx);
+            info!("Peer does not deliver {} transactions in time", waiting.txids.len());
+            for txid in waiting.txids {
+                self.send_tx_response(&waiting.addr, TxResponse::TimedOut(txid), ctx);
+            }
+        }
+    }
+
+    /// Send the response to all requesters of the block.
+    /// Returns `None` if we do not wait the block.
+    fn remove_waiting_block(&mut self, hash: &Sha256dHash, res: BlockResponse, ctx: &mut Context<Self>) -> Option<()>
+    {
+        let mut found = false;
+        for mut waiting in ::std::mem::replace(&mut self.waiting_blocks, Vec::new()) {
+            if let Some(idx) = waiting.block_hashes.iter().position(|h| h == hash) {
+                found = true;
+                waiting.block_hashes.remove(idx);
+                self.send_block_response(&waiting.addr, res.clone(), ctx);
+            }
+            if waiting.block_hashes.is_empty() {
+                ctx.cancel_future(waiting.timeout_handle);
+            } else {
+                self.waiting_blocks.push(waiting);
+            }
+        }
+
+        if !found {
+            return None;
+        }
+        self.dispatch_queued_blocks(ctx);
+        Some(())
+    }
+
+    fn blocks_in_flight(&self) -> usize
+    {
+        self.waiting_blocks.iter().map(|w| w.block_hashes.len()).sum()
+    }
+
+    fn dispatch_queued_blocks(&mut self, ctx: &mut Context<Self>)
+    {
+        loop {
+            let in_flight = self.blocks_in_flight();
+            // A request larger than the limit is sent alone.
+            let can_send = match self.queued_blocks.front() {
+                Some(req) => in_flight == 0 || in_flight + req.block_hashes.len() <= MAX_BLOCKS_IN_FLIGHT,
+                None => false,
+            };
+            if !can_send {
+                return;
+            }
+            let req = self.queued_blocks.pop_front().unwrap();
+            self.send_blocks_request(req, ctx);
+        }
+    }
+
+    fn send_blocks_request(&mut self, req: GetBlocksRequest, ctx: &mut Context<Self>)
+    {
+        let invs: Vec<_> = req.block_hashes
+            .iter()
+            .map(|hash| {
+                Inventory {
+                    inv_type: InvType::Block,
+                    hash: *hash,
+                }
+            })
+            .collect();
+        let msg = NetworkMessage::GetData(invs);
+        self.send_p2p_msg(msg, ctx);
+
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        let timeout = self.timeouts.block * req.block_hashes.len() as u32;
+        let timeout_handle = ctx.run_later(timeout, move |actor, ctx| actor.blocks_timed_out(id, ctx));
+        let waiting_blocks = WaitingBlocks {
+            id,
+            addr: req.addr,
+            block_hashes: req.block_hashes,
+            timeout_handle,
+        };
+        self.waiting_blocks.push(waiting_blocks);
+    }
+
+    fn send_headers_request(&mut self, req: GetHeadersRequest, ctx: &mut Context<Self>)
+    {
+        let getheaders = GetHeadersMessage::new(req.locator_hashes.clone(), Sha256dHash::default());
+        let msg = NetworkMessage::GetHeaders(getheaders);
+        self.send_p2p_msg(msg, ctx);
+
+        let timeout_handle = ctx.run_later(self.timeouts.headers, |actor, ctx| actor.headers_timed_out(ctx));
+        let waiting_headers = WaitingHeaders {
+            addr: req.addr,
+            locator_hashes: req.locator_hashes,
+            timeout_handle,
+        };
+        self.waiting_headers = Some(waiting_headers);
+    }
+
+    fn dispatch_queued_headers(&mut self, ctx: &mut Context<Self>)
+    {
+        if self.waiting_headers.is_none() {
+            if let Some(req) = self.queued_headers.pop_front() {
+                self.send_headers_request(req, ctx);
+            }
+        }
+    }
+
+    fn send_block_response(&mut self, addr: &Recipient<BlockResponse>, res: BlockResponse, ctx: &mut Context<Self>)
+    {
+        let send_f = addr.send(res).timeout(SEND_TIMEOUT);
+        let f = send_f.into_actor(self).map_err(|e, _actor, _ctx| {
+            debug!("Fail to send msg : {:?}", e);
+        });
+        let _ = ctx.spawn(f);
+    }
+
+    fn blocks_timed_out(&mut self, id: u64, ctx: &mut Context<Self>)
+    {
+        if let Some(idx) = self.waiting_blocks.iter().position(|w| w.id == id) {
+            let waiting = self.waiting_blocks.remove(idx);
+            info!("Peer does not deliver {} blocks in time", waiting.block_hashes.len());
+            for hash in waiting.block_hashes {
+                self.send_block_response(&waiting.addr, BlockResponse::TimedOut(hash), ctx);
+            }
+            self.dispatch_queued_blocks(ctx);
+        }
+    }
+
+    fn headers_timed_out(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(waiting) = self.waiting_headers.take() {
+            info!("Peer does not answer getheaders in time");
+            let f = waiting
+                .addr
+                .send(HeadersResponse::TimedOut)
+                .timeout(SEND_TIMEOUT)
+                .map_err(|_e| ())
+                .into_actor(self);
+            let _ = ctx.spawn(f);
+            self.dispatch_queued_headers(ctx);
+        }
+    }
+
+    fn handle_invs_msg(&mut self, invs: Vec<Inventory>, ctx: &mut Context<Self>)
+    {
+        if self.subscribe_invs.is_empty() {
+            debug!("Peer sends Inv message but no subscriber is set, so discard it.");
+        }
+
+        let mut sends = Vec::new();
+        for subscriber in self.subscribe_invs.iter() {
+            let filtered: Vec<_> = invs
+                .iter()
+                .filter(|inv| subscriber.inv_types.is_empty() || subscriber.inv_types.contains(&inv.inv_type))
+                .cloned()
+                .collect();
+            if !filtered.is_empty() {
+                sends.push((subscriber.addr.clone(), filtered));
+            }
+        }
+
+        for (addr, filtered) in sends {
+            let send_f = addr.send(PublishInv(filtered)).timeout(SEND_TIMEOUT);
+            let f = send_f.into_actor(self).map_err(move |e, actor, _ctx| {
+                debug!("Fail to send msg : {:?}", e);
+                // A slow subscriber just misses this message, but a dropped one is removed.
+                if let MailboxError::Closed = e {
+                    actor.subscribe_invs.retain(|s| s.addr != addr);
+                }
+            });
+            ctx.spawn(f);
+        }
+    }
+
+    fn handle_headers_msg(&mut self, headers: Vec<LoneBlockHeader>, ctx: &mut Context<Self>)
+    {
+        let is_answer = self
+            .waiting_headers
+            .as_ref()
+            .map_or(false, |waiting| is_getheaders_answer(&headers, &waiting.locator_hashes));
+        if !is_answer {
+            return self.publish_headers(headers, ctx);
+        }
+
+        let waiting_headers = self.waiting_headers.take().unwrap();
+        ctx.cancel_future(waiting_headers.timeout_handle);
+        let f = waiting_headers
+            .addr
+            .send(HeadersResponse::Headers(headers))
+            .map_err(|_e| ())
+            .into_actor(self);
+        ctx.wait(f);
+        self.dispatch_queued_headers(ctx);
+    }
+
+    fn publish_headers(&mut self, headers: Vec<LoneBlockHeader>, ctx: &mut Context<Self>)
+    {
+        if self.subscribe_headers.is_empty() {
+            debug!("Peer sends unsolicited headers but no subscriber is set, so discard it.");
+        }
+        for addr in self.subscribe_headers.clone() {
+            let send_f = addr.send(PublishHeaders(headers.clone())).timeout(SEND_TIMEOUT);
+            let f = send_f.into_actor(self).map_err(move |e, actor, _ctx| {
+                debug!("Fail to send msg : {:?}", e);
+                if let MailboxError::Closed = e {
+                    actor.subscribe_headers.retain(|s| *s != addr);
+                }
+            });
+            ctx.spawn(f);
+        }
+    }
+
+    fn handle_ping_msg(&mut self, nonce: u64, ctx: &mut Context<Self>)
+    {
+        let pong = NetworkMessage::Pong(nonce);
+        self.send_p2p_msg(pong, ctx);
+    }
+
+    fn handle_pong_msg(&mut self, nonce: u64)
+    {
+        match self.waiting_pong {
+            Some((expected, sent_at)) if expected == nonce => {
+                self.latency = Some(sent_at.elapsed());
+                self.waiting_pong = None;
+            },
+            // Like Bitcoin Core, a wrong nonce is just ignored and we keep waiting.
+            _ => debug!("Receive pong with unexpected nonce : {}", nonce),
+        }
+    }
+
+    fn schedule_ping(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some(handle) = self.ping_handle.take() {
+            ctx.cancel_future(handle);
+        }
+        let handle = ctx.run_interval(self.timeouts.ping_interval, |actor, ctx| actor.send_ping(ctx));
+        self.ping_handle = Some(handle);
+    }
+
+    fn send_ping(&mut self, ctx: &mut Context<Self>)
+    {
+        if let Some((_, sent_at)) = self.waiting_pong {
+            if sent_at.elapsed() > self.timeouts.ping {
+                info!("Peer does not answer ping. Close connection");
+                ctx.stop();
+            }
+            return;
+        }
+
+        let nonce = self.rng.next_u64();
+        self.waiting_pong = Some((nonce, Instant::now()));
+        self.send_p2p_msg(NetworkMessage::Ping(nonce), ctx);
+    }
+
+    fn handle_sendcmpct_msg(&mut self, announce: bool, version: u64)
+    {
+        // Peer may announce several versions in order of preference. Keep the first one.
+        if self.features.compact_block_version.is_none() {
+            self.features.compact_block_version = Some(version);
+        }
+        if self.features.compact_block_version == Some(version) {
+            self.features.compact_block_announce = announce;
+        }
+    }
+
+    fn handle_unknown_msg(&mut self, command: String, payload: Vec<u8>, ctx: &mut Context<Self>)
+    {
+        debug!("Receive unknown network msg : {} ({} bytes)", command, payload.len());
+        if let Some(ref subscriber) = self.subscribe_unknowns.as_ref() {
+            let send_f = subscriber.send(PublishUnknown { command, payload }).timeout(SEND_TIMEOUT);
+            let f = send_f.into_actor(self).map_err(|e, actor, _ctx| {
+                debug!("Fail to send msg : {:?}", e);
+                actor.subscribe_unknowns = None;
+            });
+            ctx.spawn(f);
+        }
+    }
+}
+
+/* Handle SetRequestTimeouts */
+
+impl Handler<SetRequestTimeouts> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SetRequestTimeouts, ctx: &mut Context<Self>)
+    {
+        self.timeouts = msg.0;
+        self.schedule_ping(ctx);
+    }
+}
+
+/* Handle UseCompactBlocks */
+
+impl Handler<UseCompactBlocks> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: UseCompactBlocks, ctx: &mut Context<Self>)
+    {
+        if self.features.version < SHORT_IDS_BLOCKS_VERSION {
+            info!("Peer does not support compact blocks");
+            return;
+        }
+        self.tx_pool = Some(msg.tx_pool);
+        let sendcmpct = BtcMessage::SendCmpct {
+            announce: msg.high_bandwidth,
+            version: COMPACT_BLOCK_VERSION,
+        };
+        self.send_p2p_msg(sendcmpct, ctx);
+    }
+}
+
+impl Handler<GetCmpctBlockRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetCmpctBlockRequest, ctx: &mut Context<Self>)
+    {
+        let negotiated = self.features.compact_block_version == Some(COMPACT_BLOCK_VERSION);
+        if self.tx_pool.is_none() || !negotiated {
+            let req = GetBlocksRequest {
+                block_hashes: vec![req.block_hash],
+                addr: req.addr,
+            };
+            return self.queue_blocks_request(req, ctx);
+        }
+
+        // Requesters of the same block share a `cmpctblock`.
+        if let Some(waiting) = self.waiting_cmpct_blocks.get_mut(&req.block_hash) {
+            waiting.requesters.push(req.addr);
+            return;
+        }
+        if self.waiting_cmpct_blocks.len() >= MAX_QUEUED_REQUESTS {
+            info!("Too many compact blocks are waited");
+            return self.send_block_response(&req.addr, BlockResponse::QueueFull(req.block_hash), ctx);
+        }
+        let msg = BtcMessage::GetDataCmpct {
+            invs: Vec::new(),
+            cmpct_block_hashes: vec![req.block_hash],
+        };
+        self.send_p2p_msg(msg, ctx);
+
+        let block_hash = req.block_hash;
+        let timeout_handle = ctx.run_later(self.timeouts.block, move |actor, ctx| {
+            actor.cmpct_block_timed_out(block_hash, ctx)
+        });
+        let waiting = WaitingCmpctBlock {
+            requesters: vec![req.addr],
+            partial: None,
+            timeout_handle,
+        };
+        self.waiting_cmpct_blocks.insert(block_hash, waiting);
+    }
+}
+
+impl Handler<SubscribeBlock> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeBlock, _ctx: &mut Context<Self>)
+    {
+        self.subscribe_blocks = Some(msg.addr);
+    }
+}
+
+/* Handle GetFiltersRequest */
+
+impl Handler<GetFiltersRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetFiltersRequest, ctx: &mut Context<Self>)
+    {
+        self.queue_filter_request(FilterRequest::Filters(req), ctx);
+    }
+}
+
+impl Handler<GetFilterHeadersRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetFilterHeadersRequest, ctx: &mut Context<Self>)
+    {
+        self.queue_filter_request(FilterRequest::Headers(req), ctx);
+    }
+}
+
+impl Handler<GetFilterCheckptRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetFilterCheckptRequest, ctx: &mut Context<Self>)
+    {
+        self.queue_filter_request(FilterRequest::Checkpt(req), ctx);
+    }
+}
+
+/* Handle LoadBloomFilter */
+
+impl Handler<LoadBloomFilter> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: LoadBloomFilter, ctx: &mut Context<Self>)
+    {
+        if self.peer_version.services & NODE_BLOOM == 0 {
+            info!("Do not load bloom filter since peer does not support it");
+            return;
+        }
+        self.send_p2p_msg(BtcMessage::FilterLoad(msg.0), ctx);
+    }
+}
+
+impl Handler<AddToBloomFilter> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: AddToBloomFilter, ctx: &mut Context<Self>)
+    {
+        if self.peer_version.services & NODE_BLOOM == 0 || msg.0.len() > MAX_FILTERADD_SIZE {
+            info!("Do not add {} bytes element to bloom filter", msg.0.len());
+            return;
+        }
+        self.send_p2p_msg(BtcMessage::FilterAdd(msg.0), ctx);
+    }
+}
+
+impl Handler<ClearBloomFilter> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, _msg: ClearBloomFilter, ctx: &mut Context<Self>)
+    {
+        if self.peer_version.services & NODE_BLOOM != 0 {
+            self.send_p2p_msg(BtcMessage::FilterClear, ctx);
+        }
+    }
+}
+
+/* Handle GetMerkleBlocksRequest */
+
+impl Handler<GetMerkleBlocksRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetMerkleBlocksRequest, ctx: &mut Context<Self>)
+    {
+        if self.peer_version.services & NODE_BLOOM == 0 {
+            for hash in req.block_hashes {
+                self.send_response(&req.addr, MerkleBlockResponse::Unsupported(hash), ctx);
+            }
+            return;
+        }
+        if req.block_hashes.is_empty() {
+            return;
+        }
+        if self.waiting_merkle_blocks.len() >= MAX_QUEUED_REQUESTS {
+            info!("Too many merkle block requests are waited");
+            for hash in req.block_hashes {
+                self.send_response(&req.addr, MerkleBlockResponse::QueueFull(hash), ctx);
+            }
+            return;
+        }
+        self.send_p2p_msg(BtcMessage::GetDataFiltered(req.block_hashes.clone()), ctx);
+
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        let timeout = self.timeouts.block * req.block_hashes.len() as u32;
+        let timeout_handle = ctx.run_later(timeout, move |actor, ctx| actor.merkle_blocks_timed_out(id, ctx));
+        self.waiting_merkle_blocks.push(WaitingMerkleBlocks {
+            id,
+            addr: req.addr,
+            block_hashes: req.block_hashes,
+            timeout_handle,
+        });
+    }
+}
+
+/* Handle SubscribeInv */
+
+impl Handler<SubscribeInv> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeInv, _ctx: &mut Context<Self>)
+    {
+        self.subscribe_invs.retain(|s| s.addr != msg.addr);
+        self.subscribe_invs.push(InvSubscriber {
+            addr: msg.addr,
+            inv_types: msg.inv_types,
+        });
+    }
+}
+
+impl Handler<UnsubscribeInv> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: UnsubscribeInv, _ctx: &mut Context<Self>)
+    {
+        self.subscribe_invs.retain(|s| s.addr != msg.addr);
+    }
+}
+
+/* Handle SubscribeHeaders */
+
+impl Handler<SubscribeHeaders> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeHeaders, ctx: &mut Context<Self>)
+    {
+        self.subscribe_headers.retain(|s| *s != msg.addr);
+        self.subscribe_headers.push(msg.addr);
+        if !self.sent_send_headers && self.features.version >= SENDHEADERS_VERSION {
+            self.sent_send_headers = true;
+            self.send_p2p_msg(BtcMessage::SendHeaders, ctx);
+        }
+    }
+}
+
+impl Handler<UnsubscribeHeaders> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: UnsubscribeHeaders, _ctx: &mut Context<Self>)
+    {
+        self.subscribe_headers.retain(|s| *s != msg.addr);
+    }
+}
+
+/* Handle Misbehave */
+
+impl Handler<Misbehave> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: Misbehave, ctx: &mut Context<Self>)
+    {
+        self.misbehave(msg.score, &msg.reason, ctx);
+    }
+}
+
+impl Handler<SubscribeMisbehavior> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeMisbehavior, _ctx: &mut Context<Self>)
+    {
+        self.subscribe_misbehavior = Some(msg.addr);
+    }
+}
+
+/* Handle ServeChain */
+
+impl Handler<ServeChain> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: ServeChain, _ctx: &mut Context<Self>)
+    {
+        self.serve_chain = Some(msg);
+    }
+}
+
+/* Handle SubscribeUnknown */
+
+impl Handler<SubscribeUnknown> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeUnknown, _ctx: &mut Context<Self>)
+    {
+        self.subscribe_unknowns = Some(msg.addr);
+    }
+}
+
+/* Handle GetBlocksRequest */
+
+impl Handler<GetBlocksRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetBlocksRequest, ctx: &mut Context<Connection>)
+    {
+        self.queue_blocks_request(req, ctx);
+    }
+}
+
+/* Handle GetHeadersRequest */
+
+impl Handler<GetHeadersRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetHeadersRequest, ctx: &mut Context<Self>)
+    {
+        if self.queued_headers.len() >= MAX_QUEUED_REQUESTS {
+            info!("Too many headers requests are queued");
+            return self.send_response(&req.addr, HeadersResponse::QueueFull, ctx);
+        }
+        self.queued_headers.push_back(req);
+        self.dispatch_queued_headers(ctx);
+    }
+}
+
+/* Handle GetAddrsRequest */
+
+impl Handler<GetAddrsRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetAddrsRequest, ctx: &mut Context<Self>)
+    {
+        // Requesters share a response of single `getaddr`.
+        if self.waiting_addrs.is_empty() {
+            let msg = NetworkMessage::GetAddr;
+            self.send_p2p_msg(msg, ctx);
+        }
+        self.waiting_addrs.push(req.addr);
+    }
+}
+
+/* Handle GetTxRequest */
+
+impl Handler<GetTxRequest> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, req: GetTxRequest, ctx: &mut Context<Self>)
+    {
+        if self.waiting_txs.len() >= MAX_QUEUED_REQUESTS {
+            info!("Too many transaction requests are waited");
+            for txid in req.txids {
+                self.send_tx_response(&req.addr, TxResponse::QueueFull(txid), ctx);
+            }
+            return;
+        }
+        let invs: Vec<_> = req.txids
+            .iter()
+            .map(|txid| {
+                Inventory {
+                    inv_type: InvType::WitnessTransaction,
+                    hash: *txid,
+                }
+            })
+            .collect();
+        self.send_p2p_msg(NetworkMessage::GetData(invs), ctx);
+
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        let timeout_handle = ctx.run_later(self.timeouts.tx, move |actor, ctx| actor.txs_timed_out(id, ctx));
+        let waiting_txs = WaitingTxs {
+            id,
+            addr: req.addr,
+            txids: req.txids,
+            timeout_handle,
+        };
+        self.waiting_txs.push(waiting_txs);
+    }
+}
+
+/* Handle SubscribeTx */
+
+impl Handler<SubscribeTx> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SubscribeTx, _ctx: &mut Context<Self>)
+    {
+        self.subscribe_txs = Some(msg.addr);
+    }
+}
+
+/* Handle SendTransaction */
+
+impl Handler<SendTransaction> for Connection
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: SendTransaction, ctx: &mut Context<Self>)
+    {
+        let txid = msg.0.txid();
+        let inv = Inventory {
+            inv_type: InvType::Transaction,
+            hash: txid,
+        };
+        self.send_p2p_msg(NetworkMessage::Inv(vec![inv]), ctx);
+
+        self.relay_txs.insert(txid, msg.0);
+        ctx.run_later(self.timeouts.relay_tx_expiry, move |actor, _ctx| {
+            actor.relay_txs.remove(&txid);
+        });
+    }
+}
+
+/// An answer to `getheaders` is a chain of at most `MAX_HEADERS_ENTRIES` headers which connects to the fork point,
+/// i.e. a block of the locator. An announcement (BIP130) usually follows the peer's tip instead.
+/// An empty `headers` is always an answer.
+fn is_getheaders_answer(headers: &[LoneBlockHeader], locator_hashes: &[Sha256dHash]) -> bool
+{
+    let first = match headers.first() {
+        None => return true,
+        Some(first) => first,
+    };
+    let connected = headers
+        .windows(2)
+        .all(|pair| pair[1].header.prev_blockhash == pair[0].header.bitcoin_hash());
+    headers.len() <= MAX_HEADERS_ENTRIES as usize && connected && locator_hashes.contains(&first.header.prev_blockhash)
+}
+
+// Accumulate `score` into `total`, and return true if the peer should be banned.
+pub(crate) fn add_misbehavior_score(total: &mut u32, score: u32) -> bool
+{
+    *total = total.saturating_add(score);
+    *total >= BAN_THRESHOLD
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use blockchain::{BlockFilter, FilterIndex, MemoryFilterStore, MemoryUndoStore};
+    use futures::{
+        future::{loop_fn, Loop},
+        stream,
+    };
+    use tokio::{net::UnixStream, timer::Delay};
+    use connection::socket::{HandshakeConfig, Socket, VersionPolicy};
+
+    // Remote peer, which tests play over a bare socket.
+    type Peer = HandshakedSocket<UnixStream>;
+
+    fn handshake_config(nonce: u64) -> HandshakeConfig
+    {
+        HandshakeConfig {
+            start_height: 0,
+            services: 0,
+            relay: true,
+            nonce,
+            local_nonces: HashSet::new(),
+            policy: VersionPolicy::inbound(),
+            timeout: Duration::from_secs(10),
+            wtxid_relay: false,
+            addr_v2: false,
+        }
+    }
+
+    // Run `test` on a new actor system, with a `Connection` whose remote peer is played by `test`.
+    fn with_connection<F, T>(timeouts: RequestTimeouts, test: F)
+    where
+        F: FnOnce(Addr<Connection>, Peer) -> T + 'static,
+        T: Future<Item = (), Error = Error>,
+    {
+        let mut sys = System::new("test");
+        let (ours, theirs) = UnixStream::pair().unwrap();
+        let our_addr = "10.0.0.1:8333".parse().unwrap();
+        let their_addr = "10.0.0.2:8333".parse().unwrap();
+        let ours = Socket::new(ours, Network::Regtest, our_addr, their_addr).begin_handshake(handshake_config(1));
+        // Peer serves merkle blocks too.
+        let their_config = HandshakeConfig {
+            services: NODE_BLOOM,
+            ..handshake_config(2)
+        };
+        let theirs = Socket::new(theirs, Network::Regtest, their_addr, our_addr).reply_handshake(their_config);
+        let f = ours.join(theirs).and_then(move |(ours, theirs)| {
+            let conn = Connection::start_actor(ours);
+            conn.do_send(SetRequestTimeouts(timeouts));
+            test(conn, theirs)
+        });
+        sys.block_on(f).unwrap();
+    }
+
+    // Receive messages until `pick` takes one. The others (e.g. `ping`) are skipped.
+    fn recv_until<T, F>(peer: Peer, pick: F) -> impl Future<Item = (T, Peer), Error = Error>
+    where F: FnMut(BtcMessage) -> Option<T>
+    {
+        loop_fn((peer, pick), |(peer, mut pick)| {
+            peer.recv_msg().map(move |(msg, peer)| match pick(msg) {
+                Some(picked) => Loop::Break((picked, peer)),
+                None => Loop::Continue((peer, pick)),
+            })
+        })
+    }
+
+    fn sleep(duration: Duration) -> impl Future<Item = (), Error = Error>
+    {
+        Delay::new(Instant::now() + duration).map_err(Error::from)
+    }
+
+    fn millis(ms: u64) -> Duration
+    {
+        Duration::from_millis(ms)
+    }
+
+    fn hash_of(n: u8) -> Sha256dHash
+    {
+        Sha256dHash::from(&[n; 32][..])
+    }
+
+    // Keeps responses which `Connection` sends to it.
+    struct Collector<M>
+    {
+        received: Arc<Mutex<Vec<M>>>,
+    }
+
+    impl<M: 'static> Actor for Collector<M>
+    {
+        type Context = Context<Self>;
+    }
+
+    impl<M: Message<Result = ()> + 'static> Handler<M> for Collector<M>
+    {
+        type Result = ();
+
+        fn handle(&mut self, msg: M, _ctx: &mut Context<Self>)
+        {
+            self.received.lock().unwrap().push(msg);
+        }
+    }
+
+    fn collector<M: Message<Result = ()> + Send + 'static>() -> (Recipient<M>, Arc<Mutex<Vec<M>>>)
+    {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let collector = Collector {
+            received: received.clone(),
+        };
+        (collector.start().recipient(), received)
+    }
+
+    fn getdata_hashes(msg: BtcMessage) -> Option<Vec<Sha256dHash>>
+    {
+        match msg {
+            BtcMessage::Network(NetworkMessage::GetData(invs)) => Some(invs.iter().map(|inv| inv.hash).collect()),
+            _ => None,
+        }
+    }
+
+    // Blocks on `prev` which have only a coinbase.
+    fn next_blocks(prev: &Block, n: u32) -> Vec<Block>
+    {
+        let mut prev_hash = prev.bitcoin_hash();
+        (0..n)
+            .map(|nonce| {
+                let mut block = prev.clone();
+                block.header.prev_blockhash = prev_hash;
+                block.header.nonce = nonce;
+                prev_hash = block.bitcoin_hash();
+                block
+            })
+            .collect()
+    }
+
+    // Index `blocks` on `start`, whose filter header is already in `filters`.
+    fn serve_chain(start: BlockData, blocks: &[Block], filters: Arc<Mutex<MemoryFilterStore>>) -> ServeChain
+    {
+        let mut blockchain = BlockChain::with_start(start);
+        let mut index = FilterIndex::new(filters.clone(), Arc::new(Mutex::new(MemoryUndoStore::new())));
+        for block in blocks {
+            blockchain.try_add(block.header).unwrap();
+            index.connect_block(block, Vec::new()).unwrap();
+        }
+        ServeChain {
+            blockchain: Arc::new(Mutex::new(blockchain)),
+            store: None,
+            filters: Some(filters),
+        }
+    }
+
+    fn filter_header_of(filters: &Arc<Mutex<MemoryFilterStore>>, block: &Block) -> Sha256dHash
+    {
+        filters.lock().unwrap().get_filter_header(&block.bitcoin_hash()).unwrap()
+    }
+
+    #[test]
+    fn serve_filters_from_middle_of_chain()
+    {
+        let genesis = genesis_block(Network::Regtest);
+        let blocks = next_blocks(&genesis, 3);
+        let filters = Arc::new(Mutex::new(MemoryFilterStore::new()));
+        FilterIndex::new(filters.clone(), Arc::new(Mutex::new(MemoryUndoStore::new())))
+            .connect_block(&genesis, Vec::new())
+            .unwrap();
+        let chain = serve_chain(BlockData::genesis(Network::Regtest), &blocks, filters.clone());
+
+        let req = GetCFilters {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height: 2,
+            stop_hash: blocks[2].bitcoin_hash(),
+        };
+        let cfheaders = chain.cfheaders(&req).unwrap();
+        assert_eq!(cfheaders.prev_filter_header, filter_header_of(&filters, &blocks[0]));
+        assert_eq!(
+            cfheaders.filter_headers(),
+            vec![filter_header_of(&filters, &blocks[1]), filter_header_of(&filters, &blocks[2])]
+        );
+
+        let cfilters = chain.cfilters(&req).unwrap();
+        let block_hashes: Vec<_> = cfilters.iter().map(|cfilter| cfilter.block_hash).collect();
+        assert_eq!(block_hashes, vec![blocks[1].bitcoin_hash(), blocks[2].bitcoin_hash()]);
+    }
+
+    #[test]
+    fn serve_filters_of_chain_with_start()
+    {
+        // A chain starts at height 999, and we know the filter header of the start block.
+        let mut start = genesis_block(Network::Regtest);
+        start.header.prev_blockhash = Sha256dHash::from_data(b"998");
+        let start_filter_header = Sha256dHash::from_data(b"filter header of 999");
+        let filters = Arc::new(Mutex::new(MemoryFilterStore::new()));
+        filters
+            .lock()
+            .unwrap()
+            .put_filter(start.bitcoin_hash(), BlockFilter::basic(&start, &[]), start_filter_header)
+            .unwrap();
+
+        let blocks = next_blocks(&start, 2);
+        let chain = serve_chain(BlockData::new(start.header, 999), &blocks, filters.clone());
+
+        let cfcheckpt = chain.cfcheckpt(&GetCFCheckpt {
+            filter_type: BASIC_FILTER_TYPE,
+            stop_hash: blocks[1].bitcoin_hash(),
+        });
+        assert_eq!(cfcheckpt.unwrap().filter_headers, vec![filter_header_of(&filters, &blocks[0])]);
+
+        let req = GetCFilters {
+            filter_type: BASIC_FILTER_TYPE,
+            start_height: 1000,
+            stop_hash: blocks[1].bitcoin_hash(),
+        };
+        assert_eq!(chain.cfheaders(&req).unwrap().prev_filter_header, start_filter_header);
+
+        // Filter header of the block before the start is unknown.
+        let req = GetCFilters {
+            start_height: 999,
+            ..req
+        };
+        assert!(chain.cfheaders(&req).is_none());
+        assert_eq!(chain.cfilters(&req).unwrap().len(), 3);
+    }
+
+    fn lone_headers(blocks: &[Block]) -> Vec<LoneBlockHeader>
+    {
+        blocks
+            .iter()
+            .map(|block| LoneBlockHeader {
+                header: block.header,
+                tx_count: VarInt(0),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn tell_getheaders_answer_from_announcement()
+    {
+        let genesis = genesis_block(Network::Regtest);
+        let blocks = next_blocks(&genesis, 4);
+        // We know up to blocks[1], while peer knows up to blocks[3].
+        let locator_hashes = vec![blocks[1].bitcoin_hash(), blocks[0].bitcoin_hash(), genesis.bitcoin_hash()];
+
+        assert!(is_getheaders_answer(&[], &locator_hashes));
+        assert!(is_getheaders_answer(&lone_headers(&blocks[2..]), &locator_hashes));
+        // Peer does not know blocks[1] and answers from the fork point.
+        assert!(is_getheaders_answer(&lone_headers(&blocks[1..]), &locator_hashes));
+
+        // A new tip which does not connect to the locator is an announcement.
+        assert!(!is_getheaders_answer(&lone_headers(&blocks[3..]), &locator_hashes));
+        // Headers which are not a chain can not be an answer.
+        let gapped = lone_headers(&[blocks[2].clone(), blocks[0].clone()]);
+        assert!(!is_getheaders_answer(&gapped, &locator_hashes));
+        let too_many = lone_headers(&next_blocks(&blocks[1], MAX_HEADERS_ENTRIES + 1));
+        assert!(!is_getheaders_answer(&too_many, &locator_hashes));
+        assert!(is_getheaders_answer(&too_many[..MAX_HEADERS_ENTRIES as usize], &locator_hashes));
+    }
+
+    #[test]
+    fn ban_when_accumulated_score_reaches_threshold()
+    {
+        let mut total = 0;
+        assert!(!add_misbehavior_score(&mut total, 40));
+        assert!(!add_misbehavior_score(&mut total, 59));
+        assert_eq!(total, 99);
+        assert!(add_misbehavior_score(&mut total, 1));
+        assert!(add_misbehavior_score(&mut total, u32::max_value()));
+        assert_eq!(total, u32::max_value());
+    }
+
+    #[test]
+    fn match_pong_by_nonce_and_drop_silent_peer()
+    {
+        let timeouts = RequestTimeouts {
+            ping_interval: millis(50),
+            ping: millis(200),
+            ..RequestTimeouts::default()
+        };
+        with_connection(timeouts, |conn, peer| {
+            let (conn1, conn2) = (conn.clone(), conn.clone());
+            let ping = |msg| match msg {
+                BtcMessage::Network(NetworkMessage::Ping(nonce)) => Some(nonce),
+                _ => None,
+            };
+            recv_until(peer, ping)
+                .and_then(|(nonce, peer)| {
+                    // A wrong nonce is ignored.
+                    let pong = NetworkMessage::Pong(nonce.wrapping_add(1));
+                    peer.send_msg(pong)
+                        .and_then(move |peer| latency_after_a_while(&conn1).map(move |l| (l, nonce, peer)))
+                })
+                .and_then(|(latency, nonce, peer)| {
+                    assert_eq!(latency, None);
+                    peer.send_msg(NetworkMessage::Pong(nonce))
+                        .and_then(move |peer| latency_after_a_while(&conn2).map(|l| (l, peer)))
+                })
+                .and_then(|(latency, peer)| {
+                    assert!(latency.is_some());
+                    // Stop answering pings. The connection is closed after the ping timeout.
+                    recv_until(peer, |_| None::<()>).then(|res| {
+                        assert!(res.is_err());
+                        sleep(millis(20))
+                    })
+                })
+                .map(move |_| assert!(!conn.connected()))
+        });
+    }
+
+    // Let the connection process messages which are already sent to it.
+    fn latency_after_a_while(conn: &Addr<Connection>) -> impl Future<Item = Option<Duration>, Error = Error>
+    {
+        let conn = conn.clone();
+        sleep(millis(20)).and_then(move |_| conn.send(GetPeerInfo()).map(|info| info.latency).map_err(Error::from))
+    }
+
+    #[test]
+    fn time_out_requests_to_silent_peer()
+    {
+        let timeouts = RequestTimeouts {
+            headers: millis(100),
+            block: millis(100),
+            ..RequestTimeouts::default()
+        };
+        with_connection(timeouts, |conn, peer| {
+            let (headers_addr, headers) = collector::<HeadersResponse>();
+            let (blocks_addr, blocks) = collector::<BlockResponse>();
+            conn.do_send(GetHeadersRequest {
+                locator_hashes: vec![hash_of(0)],
+                addr: headers_addr,
+            });
+            conn.do_send(GetBlocksRequest {
+                block_hashes: vec![hash_of(1)],
+                addr: blocks_addr,
+            });
+            recv_until(peer, getdata_hashes)
+                .and_then(|(hashes, peer)| {
+                    assert_eq!(hashes, vec![hash_of(1)]);
+                    // Keep the peer connected, but never answer.
+                    sleep(millis(300)).map(move |_| peer)
+                })
+                .map(move |_peer| {
+                    match headers.lock().unwrap()[..] {
+                        [HeadersResponse::TimedOut] => (),
+                        _ => panic!("getheaders does not time out"),
+                    }
+                    match blocks.lock().unwrap()[..] {
+                        [BlockResponse::TimedOut(hash)] => assert_eq!(hash, hash_of(1)),
+                        _ => panic!("getdata does not time out"),
+                    }
+                })
+        });
+    }
+
+    #[test]
+    fn end_block_request_on_notfound()
+    {
+        with_connection(RequestTimeouts::default(), |conn, peer| {
+            let (blocks_addr, blocks) = collector::<BlockResponse>();
+            conn.do_send(GetBlocksRequest {
+                block_hashes: vec![hash_of(1), hash_of(2)],
+                addr: blocks_addr,
+            });
+            recv_until(peer, getdata_hashes)
+                .and_then(|(hashes, peer)| {
+                    assert_eq!(hashes, vec![hash_of(1), hash_of(2)]);
+                    let inv = Inventory {
+                        inv_type: InvType::Block,
+                        hash: hash_of(2),
+                    };
+                    peer.send_msg(NetworkMessage::NotFound(vec![inv]))
+                })
+                .and_then(|peer| sleep(millis(50)).map(move |_| peer))
+                .map(move |_peer| match blocks.lock().unwrap()[..] {
+                    // The other block is still waited.
+                    [BlockResponse::NotFound(hash)] => assert_eq!(hash, hash_of(2)),
+                    _ => panic!("notfound does not end the block request"),
+                })
+        });
+    }
+
+    fn answer_getheaders(peer: Peer, locator: Sha256dHash) -> impl Future<Item = Peer, Error = Error>
+    {
+        let getheaders_locator = |msg| match msg {
+            BtcMessage::Network(NetworkMessage::GetHeaders(getheaders)) => Some(getheaders.locator_hashes),
+            _ => None,
+        };
+        recv_until(peer, getheaders_locator).and_then(move |(locator_hashes, peer)| {
+            assert_eq!(locator_hashes, vec![locator]);
+            peer.send_msg(NetworkMessage::Headers(Vec::new()))
+        })
+    }
+
+    #[test]
+    fn send_queued_requests_in_order_and_refuse_overflow()
+    {
+        let locator = |i: u32| {
+            let mut bytes = [0u8; 32];
+            bytes[..4].copy_from_slice(&i.to_le_bytes());
+            Sha256dHash::from(&bytes[..])
+        };
+        with_connection(RequestTimeouts::default(), move |conn, peer| {
+            let (headers_addr, headers) = collector::<HeadersResponse>();
+            let (overflow_addr, overflow) = collector::<HeadersResponse>();
+            // One request is in flight, and the others fill the queue. Send them one by one, since actix refuses to
+            // handle too many messages in a single poll.
+            let overflow_conn = conn.clone();
+            stream::iter_ok(0..(MAX_QUEUED_REQUESTS as u32 + 1))
+                .for_each(move |i| {
+                    let req = GetHeadersRequest {
+                        locator_hashes: vec![locator(i)],
+                        addr: headers_addr.clone(),
+                    };
+                    conn.send(req).map_err(Error::from)
+                })
+                .and_then(move |_| {
+                    let req = GetHeadersRequest {
+                        locator_hashes: vec![locator(MAX_QUEUED_REQUESTS as u32 + 1)],
+                        addr: overflow_addr,
+                    };
+                    overflow_conn.send(req).map_err(Error::from)
+                })
+                .and_then(move |_| answer_getheaders(peer, locator(0)))
+                .and_then(move |peer| answer_getheaders(peer, locator(1)))
+                .and_then(move |peer| answer_getheaders(peer, locator(2)))
+                .and_then(|peer| sleep(millis(50)).map(move |_| peer))
+                .map(move |_peer| {
+                    match overflow.lock().unwrap()[..] {
+                        [HeadersResponse::QueueFull] => (),
+                        _ => panic!("the request beyond the queue is not refused"),
+                    }
+                    let headers = headers.lock().unwrap();
+                    assert_eq!(headers.len(), 3);
+                    assert!(headers.iter().all(|res| match res {
+                        HeadersResponse::Headers(headers) => headers.is_empty(),
+                        _ => false,
+                    }));
+                })
+        });
+    }
+
+    #[test]
+    fn serve_broadcast_tx_until_expiry()
+    {
+        let timeouts = RequestTimeouts {
+            relay_tx_expiry: millis(200),
+            ..RequestTimeouts::default()
+        };
+        let tx = genesis_block(Network::Regtest).txdata[0].clone();
+        let txid = tx.txid();
+        let inv = Inventory {
+            inv_type: InvType::WitnessTransaction,
+            hash: txid,
+        };
+        let getdata = NetworkMessage::GetData(vec![inv.clone()]);
+        let getdata_after_expiry = getdata.clone();
+        with_connection(timeouts, move |conn, peer| {
+            conn.do_send(SendTransaction(tx.clone()));
+            let announced = |msg| match msg {
+                BtcMessage::Network(NetworkMessage::Inv(invs)) => Some(invs),
+                _ => None,
+            };
+            let served = |msg| match msg {
+                BtcMessage::Network(NetworkMessage::Tx(tx)) => Some(tx),
+                _ => None,
+            };
+            let not_found = |msg| match msg {
+                BtcMessage::Network(NetworkMessage::NotFound(invs)) => Some(invs),
+                _ => None,
+            };
+            recv_until(peer, announced)
+                .and_then(move |(invs, peer)| {
+                    assert_eq!(invs.len(), 1);
+                    assert_eq!(invs[0].hash, txid);
+                    peer.send_msg(getdata)
+                })
+                .and_then(move |peer| recv_until(peer, served))
+                .and_then(move |(served_tx, peer)| {
+                    assert_eq!(served_tx, tx);
+                    sleep(millis(300)).and_then(move |_| peer.send_msg(getdata_after_expiry))
+                })
+                .and_then(move |peer| recv_until(peer, not_found))
+                .map(move |(invs, _peer)| assert_eq!(invs, vec![inv]))
+        });
+    }
+
+    #[test]
+    fn publish_inv_to_subscribers_by_type()
+    {
+        let block = |n| {
+            Inventory {
+                inv_type: InvType::Block,
+                hash: hash_of(n),
+            }
+        };
+        let tx = || {
+            Inventory {
+                inv_type: InvType::Transaction,
+                hash: hash_of(2),
+            }
+        };
+        with_connection(RequestTimeouts::default(), move |conn, peer| {
+            let (blocks_addr, blocks) = collector::<PublishInv>();
+            let (all_addr, all) = collector::<PublishInv>();
+            let subscribe_blocks = conn.send(SubscribeInv {
+                addr: blocks_addr,
+                inv_types: vec![InvType::Block],
+            });
+            let subscribe_all = conn.send(SubscribeInv {
+                addr: all_addr.clone(),
+                inv_types: Vec::new(),
+            });
+            let unsubscribe_conn = conn.clone();
+            let published = |received: &Arc<Mutex<Vec<PublishInv>>>| {
+                received.lock().unwrap().iter().map(|publish| publish.0.clone()).collect::<Vec<_>>()
+            };
+            subscribe_blocks
+                .join(subscribe_all)
+                .map_err(Error::from)
+                .and_then(move |_| peer.send_msg(NetworkMessage::Inv(vec![block(1), tx()])))
+                .and_then(|peer| sleep(millis(50)).map(move |_| peer))
+                .and_then(move |peer| {
+                    assert_eq!(published(&blocks), vec![vec![block(1)]]);
+                    assert_eq!(published(&all), vec![vec![block(1), tx()]]);
+                    unsubscribe_conn
+                        .send(UnsubscribeInv { addr: all_addr })
+                        .map_err(Error::from)
+                        .and_then(move |_| peer.send_msg(NetworkMessage::Inv(vec![block(3)])))
+                        .and_then(|peer| sleep(millis(50)).map(move |_| peer))
+                        .map(move |_peer| {
+                            assert_eq!(published(&blocks), vec![vec![block(1)], vec![block(3)]]);
+                            assert_eq!(published(&all).len(), 1);
+                        })
+                })
+        });
+    }
+
+    #[test]
+    fn answer_unknown_locator_from_genesis()
+    {
+        let genesis = genesis_block(Network::Regtest);
+        let blocks = next_blocks(&genesis, 3);
+        let mut blockchain = BlockChain::new(Network::Regtest);
+        for block in &blocks {
+            blockchain.try_add(block.header).unwrap();
+        }
+        let unknown = vec![hash_of(1)];
+        let max = MAX_HEADERS_ENTRIES as usize;
+        let answer = blocks_after_fork(&blockchain, &unknown, &Sha256dHash::default(), max);
+        let hashes: Vec<_> = answer.unwrap().iter().map(|b| b.bitcoin_hash()).collect();
+        assert_eq!(hashes, blocks.iter().map(|b| b.bitcoin_hash()).collect::<Vec<_>>());
+
+        // Without genesis, there is nothing to answer from.
+        let blockchain = BlockChain::with_start(BlockData::new(blocks[0].header, 1));
+        assert!(blocks_after_fork(&blockchain, &unknown, &Sha256dHash::default(), max).is_none());
+    }
+
+    #[test]
+    fn answer_notfound_to_unknown_cmpctblock()
+    {
+        with_connection(RequestTimeouts::default(), |_conn, peer| {
+            let getdata = BtcMessage::GetDataCmpct {
+                invs: Vec::new(),
+                cmpct_block_hashes: vec![hash_of(1)],
+            };
+            let not_found = |msg| match msg {
+                BtcMessage::Network(NetworkMessage::NotFound(invs)) => Some(invs),
+                _ => None,
+            };
+            peer.send_msg(getdata)
+                .and_then(move |peer| recv_until(peer, not_found))
+                .map(|(invs, _peer)| {
+                    let inv = Inventory {
+                        inv_type: InvType::Block,
+                        hash: hash_of(1),
+                    };
+                    assert_eq!(invs, vec![inv]);
+                })
+        });
+    }
+
+    #[test]
+    fn reject_block_with_wrong_merkle_root()
+    {
+        let mut block = genesis_block(Network::Regtest);
+        let block_hash = block.bitcoin_hash();
+        let mut other_tx = block.txdata[0].clone();
+        other_tx.lock_time = 1;
+        block.txdata.push(other_tx);
+        with_connection(RequestTimeouts::default(), move |conn, peer| {
+            let (blocks_addr, blocks) = collector::<BlockResponse>();
+            conn.do_send(GetBlocksRequest {
+                block_hashes: vec![block_hash],
+                addr: blocks_addr,
+            });
+            recv_until(peer, getdata_hashes)
+                .and_then(move |(_hashes, peer)| peer.send_msg(NetworkMessage::Block(block)))
+                .and_then(|peer| sleep(millis(50)).map(move |_| peer))
+                .and_then(move |_peer| conn.send(GetPeerInfo()).then(Ok))
+                .map(move |info| {
+                    assert!(info.is_err(), "peer is not banned");
+                    assert!(blocks.lock().unwrap().is_empty());
+                })
+        });
+    }
+
+    #[test]
+    fn refuse_merkle_block_requests_beyond_limit()
+    {
+        with_connection(RequestTimeouts::default(), |conn, peer| {
+            let (merkle_addr, merkle) = collector::<MerkleBlockResponse>();
+            let (overflow_addr, overflow) = collector::<MerkleBlockResponse>();
+            let overflow_conn = conn.clone();
+            // Send them one by one, since actix refuses to handle too many messages in a single poll.
+            stream::iter_ok(0..MAX_QUEUED_REQUESTS)
+                .for_each(move |i| {
+                    let req = GetMerkleBlocksRequest {
+                        block_hashes: vec![hash_of(i as u8)],
+                        addr: merkle_addr.clone(),
+                    };
+                    conn.send(req).map_err(Error::from)
+                })
+                .and_then(move |_| {
+                    let req = GetMerkleBlocksRequest {
+                        block_hashes: vec![hash_of(1), hash_of(2)],
+                        addr: overflow_addr,
+                    };
+                    overflow_conn.send(req).map_err(Error::from)
+                })
+                .and_then(|_| sleep(millis(50)))
+                .map(move |_| {
+                    // Peer stays connected, but answers nothing.
+                    drop(peer);
+                    assert!(merkle.lock().unwrap().is_empty());
+                    match overflow.lock().unwrap()[..] {
+                        [MerkleBlockResponse::QueueFull(first), MerkleBlockResponse::QueueFull(second)] => {
+                            assert_eq!((first, second), (hash_of(1), hash_of(2)));
+                        },
+                        _ => panic!("merkle block requests beyond the limit are not refused"),
+                    }
+                })
+        });
     }
 }
@@ -1,15 +1,24 @@
-use std::{collections::HashSet, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex}, time::Duration};
+use std::{io, collections::{HashMap, HashSet}, net::{IpAddr, SocketAddr}, sync::{Arc, Mutex},
+          time::{Duration, Instant}};
 use actix::prelude::*;
 use trust_dns_resolver::{ResolverFuture, config::{ResolverConfig, ResolverOpts}, error::ResolveError};
-use futures::Future;
+use futures::{Future, IntoFuture};
+use tokio::net::{TcpListener, TcpStream};
 use bitcoin::network::constants::Network;
+use failure::Error;
 
 use rand::{FromEntropy, RngCore, XorShiftRng, seq::sample_iter};
 
-use blockchain::BlockChain;
-use connection::{socket::Socket, {AddrsResponse, Connection, Disconnect, GetAddrsRequest}};
+use blockchain::{BlockChain, BlockStore, FilterStore};
+use connection::{addr::NetAddr, bip324::{Bip324Stream, NODE_P2P_V2}, compact_block::TxPool, error::ConnectionError,
+                 socket::{CountingStream, HandshakeConfig, Socket, Timeouts, VersionPolicy, NODE_COMPACT_FILTERS},
+                 socks5::{Proxy, TargetAddr},
+                 {AddrsResponse, Connection, Disconnect, GetAddrsRequest, GetPeerInfo, PeerInfo, ServeChain,
+                  SubscribeMisbehavior, UseCompactBlocks, add_misbehavior_score}};
 
 pub const DEFAULT_WATER_LINE: usize = 8;
+/// Same as Bitcoin Core's default, which allows 125 connections including 8 outbound ones.
+pub const DEFAULT_MAX_INBOUND: usize = 117;
 pub const ADDR_POOL_SIZE: usize = 64;
 
 pub const BITCOIN_DNS_SEEDS: [&'static str; 6] = [
@@ -28,25 +37,52 @@ pub const TESTNET_DNS_SEEDS: [&'static str; 4] = [
     "testnet-seed.bitcoin.schildbach.de",
 ];
 
+/// Misbehaving peers can not connect to us and we do not connect to them for this period.
+pub const DEFAULT_BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
 pub const BITCOIN_PORT: u16 = 8333;
 pub const TESTNET_PORT: u16 = 18333;
 
+type TransportFuture = Box<dyn Future<Item = Socket<Bip324Stream<CountingStream<TcpStream>>>, Error = Error>>;
+
 pub struct ConnectionPool
 {
-    connection_pool: HashSet<Addr<Connection>>,
-    water_line: usize, // The number of connections it needs to keep
-    addr_pool: Vec<SocketAddr>,
+    // Each outbound connection is mapped to the address which we connected to.
+    connection_pool: HashMap<Addr<Connection>, TargetAddr>,
+    water_line: usize, // The number of outbound connections it needs to keep
+    // Inbound connections are kept apart, so that peers dialing in never replace our outbound ones.
+    inbound_pool: HashMap<Addr<Connection>, TargetAddr>,
+    max_inbound: usize,
+    // Tor and I2P addresses are here only if we have a proxy.
+    addr_pool: Vec<TargetAddr>,
+    // Nonces of `version` messages which our outbound handshakes are using.
+    local_nonces: HashSet<u64>,
+    // Banned addresses, whose ports are ignored, and their expiry.
+    banned: HashMap<TargetAddr, Instant>,
+    ban_duration: Duration,
+    // Misbehavior scores of addresses keyed likewise, and when they are forgotten.
+    misbehavior_scores: HashMap<TargetAddr, (u32, Instant)>,
 
     rng: XorShiftRng,
 
     network: Network,
     services: u64,
     relay: bool,
+    outbound_policy: VersionPolicy,
+    inbound_policy: VersionPolicy,
+    proxy: Option<Proxy>,
+    timeouts: Timeouts,
+    v2_transport: bool,
     blockchain: Arc<Mutex<BlockChain>>,
+    block_store: Option<Arc<Mutex<dyn BlockStore + Send>>>,
+    filter_store: Option<Arc<Mutex<dyn FilterStore + Send>>>,
+    tx_pool: Option<Arc<Mutex<dyn TxPool + Send>>>,
 }
 
 #[derive(Message)]
 #[rtype(result = "Vec<Addr<Connection>>")]
+/// Pick up to `num` outbound connections at random.
+/// Inbound peers are never returned, since they are not required to serve headers, blocks or filters.
 pub struct GetConnections
 {
     pub num: usize,
@@ -54,11 +90,35 @@ pub struct GetConnections
 }
 
 #[derive(Message)]
+#[rtype(result = "Result<SocketAddr, Error>")]
+/// Start to accept inbound connections on given address.
+/// Returns the address we actually listen on, which is useful if port 0 is specified.
+pub struct Listen
+{
+    pub addr: SocketAddr,
+}
+
+#[derive(Message)]
+#[rtype(result = "Result<Vec<PeerInfo>, ()>")]
+/// Collect `PeerInfo` of all connections. Connections which fail to answer are omitted.
+pub struct GetAllPeerInfo();
+
+#[derive(Message)]
+/// Disconnect the peer and refuse its address for a while.
 pub struct BanConnection
 {
     pub conn: Addr<Connection>,
 }
 
+#[derive(Message)]
+/// `Connection` reports each misbehavior of the peer with this. Scores are added up for the peer's address,
+/// and the peer is banned when the total reaches `BAN_THRESHOLD`.
+pub struct ReportMisbehavior
+{
+    pub conn: Addr<Connection>,
+    pub score: u32,
+}
+
 impl Actor for ConnectionPool
 {
     type Context = Context<Self>;
@@ -77,35 +137,182 @@ impl ConnectionPool
     pub fn new(network: Network, services: u64, relay: bool, blockchain: Arc<Mutex<BlockChain>>) -> ConnectionPool
     {
         ConnectionPool {
-            connection_pool: HashSet::new(),
+            connection_pool: HashMap::new(),
             water_line: DEFAULT_WATER_LINE,
+            inbound_pool: HashMap::new(),
+            max_inbound: DEFAULT_MAX_INBOUND,
             addr_pool: Vec::new(),
+            local_nonces: HashSet::new(),
+            banned: HashMap::new(),
+            ban_duration: DEFAULT_BAN_DURATION,
+            misbehavior_scores: HashMap::new(),
 
             rng: XorShiftRng::from_entropy(),
 
             network,
             services,
             relay,
+            outbound_policy: VersionPolicy::default(),
+            inbound_policy: VersionPolicy::inbound(),
+            proxy: None,
+            timeouts: Timeouts::default(),
+            v2_transport: false,
             blockchain,
+            block_store: None,
+            filter_store: None,
+            tx_pool: None,
+        }
+    }
+
+    /// Replace requirements on `version` message of peers which we connect to.
+    /// Peers which do not satisfy it are disconnected during handshake.
+    pub fn with_outbound_policy(mut self, policy: VersionPolicy) -> ConnectionPool
+    {
+        self.outbound_policy = policy;
+        self
+    }
+
+    /// Replace requirements on `version` message of peers which connect to us.
+    /// By default, they are not required to advertise any services.
+    pub fn with_inbound_policy(mut self, policy: VersionPolicy) -> ConnectionPool
+    {
+        self.inbound_policy = policy;
+        self
+    }
+
+    /// Make all outbound connections go through SOCKS5 proxy.
+    pub fn with_proxy(mut self, proxy: Proxy) -> ConnectionPool
+    {
+        self.proxy = Some(proxy);
+        self
+    }
+
+    /// Replace deadlines of connecting, handshake and waiting for next message.
+    /// Connections which exceed them are dropped so that we can try another peer.
+    pub fn with_timeouts(mut self, timeouts: Timeouts) -> ConnectionPool
+    {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Encrypt connections with BIP324 v2 transport and advertise `NODE_P2P_V2`.
+    /// Outbound connections fall back to v1 if a peer does not support it.
+    pub fn with_v2_transport(mut self) -> ConnectionPool
+    {
+        self.v2_transport = true;
+        self.services |= NODE_P2P_V2;
+        self
+    }
+
+    /// Replace how many inbound connections are accepted at most. Further peers are disconnected at once.
+    pub fn with_max_inbound(mut self, max_inbound: usize) -> ConnectionPool
+    {
+        self.max_inbound = max_inbound;
+        self
+    }
+
+    /// Replace how long misbehaving peers are banned.
+    pub fn with_ban_duration(mut self, duration: Duration) -> ConnectionPool
+    {
+        self.ban_duration = duration;
+        self
+    }
+
+    /// Serve blocks in the store to peers. Otherwise we serve only headers.
+    pub fn with_block_store(mut self, store: Arc<Mutex<dyn BlockStore + Send>>) -> ConnectionPool
+    {
+        self.block_store = Some(store);
+        self
+    }
+
+    /// Serve BIP158 filters in the store to peers, and advertise `NODE_COMPACT_FILTERS`.
+    /// Filters are put to the store by `FilterIndex` as blocks are connected.
+    pub fn with_filter_store(mut self, store: Arc<Mutex<dyn FilterStore + Send>>) -> ConnectionPool
+    {
+        self.filter_store = Some(store);
+        self.services |= NODE_COMPACT_FILTERS;
+        self
+    }
+
+    /// Receive blocks by compact blocks (BIP152) in low-bandwidth mode, reconstructing them from the pool.
+    pub fn with_tx_pool(mut self, tx_pool: Arc<Mutex<dyn TxPool + Send>>) -> ConnectionPool
+    {
+        self.tx_pool = Some(tx_pool);
+        self
+    }
+
+    fn start_height(&self) -> i32
+    {
+        let lock = self.blockchain.lock().unwrap();
+        let active_chain = lock.active_chain();
+        let start_height = active_chain.latest_block().height();
+        start_height as i32
+    }
+
+    fn handshake_config(&mut self, policy: VersionPolicy) -> HandshakeConfig
+    {
+        HandshakeConfig {
+            start_height: self.start_height(),
+            services: self.services,
+            relay: self.relay,
+            nonce: self.rng.next_u64(),
+            local_nonces: self.local_nonces.clone(),
+            policy,
+            timeout: self.timeouts.handshake,
+            // We can not handle inventories of wtxid yet.
+            wtxid_relay: false,
+            addr_v2: true,
         }
     }
 
-    fn add_connection(&mut self, addr: &SocketAddr, ctx: &mut Context<Self>)
+    fn serve_chain(&self) -> ServeChain
     {
-        let f = Socket::connect(addr, self.network)
+        ServeChain {
+            blockchain: self.blockchain.clone(),
+            store: self.block_store.clone(),
+            filters: self.filter_store.clone(),
+        }
+    }
+
+    fn add_connection(&mut self, addr: &TargetAddr, v2: bool, ctx: &mut Context<Self>)
+    {
+        let policy = self.outbound_policy.clone();
+        let config = self.handshake_config(policy);
+        let nonce = config.nonce;
+        self.local_nonces.insert(nonce);
+
+        let (network, timeout) = (self.network, self.timeouts.connect);
+        let connect_f: Box<dyn Future<Item = Socket<TcpStream>, Error = Error>> = match (&self.proxy, addr) {
+            (Some(proxy), addr) => Box::new(Socket::connect_via_proxy(proxy, addr, network, timeout)),
+            (None, TargetAddr::Ip(addr)) => Box::new(Socket::connect(addr, network, timeout)),
+            (None, TargetAddr::Domain(..)) => unreachable!("Only a proxy can resolve {:?}", addr),
+        };
+
+        let handshake_timeout = self.timeouts.handshake;
+        let transport_f = connect_f.and_then(move |socket| -> TransportFuture {
+            let socket = socket.count_bytes();
+            if v2 {
+                // A v1 peer may neither reply nor close the connection, waiting for the rest of a version message.
+                // So time out is regarded as lack of v2 support as well.
+                Box::new(socket.begin_v2_transport(handshake_timeout).map_err(|e| {
+                    match e.downcast_ref::<ConnectionError>() {
+                        Some(ConnectionError::HandshakeTimeout) => Error::from(ConnectionError::V2Unsupported),
+                        _ => e,
+                    }
+                }))
+            } else {
+                Box::new(Ok(socket.into_v1_transport()).into_future())
+            }
+        });
+
+        let (target, retry_addr) = (addr.clone(), addr.clone());
+        let f = transport_f
             .into_actor(self)
-            .and_then(|socket, actor, _ctx| {
-                let start_height = {
-                    let lock = actor.blockchain.lock().unwrap();
-                    let active_chain = lock.active_chain();
-                    let start_height = active_chain.latest_block().height();
-                    start_height
-                };
-                socket
-                    .begin_handshake(start_height as i32, actor.services, actor.relay)
-                    .into_actor(actor)
-            })
-            .map(|socket, actor, ctx| {
+            .and_then(move |socket, actor, _ctx| socket.begin_handshake(config).into_actor(actor))
+            .map(move |mut socket, actor, ctx| {
+                actor.local_nonces.remove(&nonce);
+                socket.set_read_timeout(actor.timeouts.read);
+
                 let conn = Connection::start_actor(socket);
 
                 // Try send a GetAddrsRequest
@@ -113,20 +320,148 @@ impl ConnectionPool
                 let req = GetAddrsRequest { addr: me };
                 conn.do_send(req);
 
-                let _ = actor.connection_pool.insert(conn);
+                actor.register_connection(conn, target, false, ctx);
             })
-            .map_err(|err, _actor, _ctx| {
+            .map_err(move |err, actor, ctx| {
+                actor.local_nonces.remove(&nonce);
                 info!("Fail to establish connection : {:?}", err);
+                if let Some(ConnectionError::V2Unsupported) = err.downcast_ref::<ConnectionError>() {
+                    // Peer closed the connection or did not reply seeing our key. Retry with a fresh v1 connection.
+                    actor.add_connection(&retry_addr, false, ctx);
+                }
+            });
+        ctx.spawn(f);
+    }
+
+    fn accept_connection(&mut self, stream: TcpStream, ctx: &mut Context<Self>)
+    {
+        let target = match stream.peer_addr() {
+            Ok(addr) => TargetAddr::Ip(addr),
+            Err(e) => {
+                info!("Fail to get peer address of inbound socket : {:?}", e);
+                return;
+            },
+        };
+        if self.is_banned(&target) {
+            info!("Reject inbound connection from banned peer {:?}", target);
+            return;
+        }
+        if !self.has_room_for_inbound() {
+            info!("Reject inbound connection from {:?} since we have enough inbound peers", target);
+            return;
+        }
+
+        let policy = self.inbound_policy.clone();
+        let config = self.handshake_config(policy);
+        let (v2, handshake_timeout) = (self.v2_transport, self.timeouts.handshake);
+        let f = Socket::from_tcp(stream, self.network)
+            .map(|socket| socket.count_bytes())
+            .into_future()
+            .and_then(move |socket| -> TransportFuture {
+                if v2 {
+                    Box::new(socket.accept_v2_transport(handshake_timeout))
+                } else {
+                    Box::new(Ok(socket.into_v1_transport()).into_future())
+                }
+            })
+            .and_then(move |socket| socket.reply_handshake(config))
+            .into_actor(self)
+            .map(|mut socket, actor, ctx| {
+                // Other peers may have completed their handshakes meanwhile.
+                if !actor.has_room_for_inbound() {
+                    info!("Drop inbound connection from {:?} since we have enough inbound peers", target);
+                    return;
+                }
+                socket.set_read_timeout(actor.timeouts.read);
+                let conn = Connection::start_actor(socket);
+                actor.register_connection(conn, target, true, ctx);
+            })
+            .map_err(|err, _actor, _ctx| {
+                info!("Fail to accept connection : {:?}", err);
             });
         ctx.spawn(f);
     }
 
+    fn register_connection(&mut self, conn: Addr<Connection>, addr: TargetAddr, inbound: bool, ctx: &mut Context<Self>)
+    {
+        conn.do_send(self.serve_chain());
+        conn.do_send(SubscribeMisbehavior {
+            addr: ctx.address().recipient(),
+        });
+        if let Some(tx_pool) = self.tx_pool.as_ref() {
+            conn.do_send(UseCompactBlocks {
+                tx_pool: tx_pool.clone(),
+                high_bandwidth: false,
+            });
+        }
+        if inbound {
+            let _ = self.inbound_pool.insert(conn, addr);
+        } else {
+            let _ = self.connection_pool.insert(conn, addr);
+        }
+    }
+
+    // Tor addresses need a proxy, and I2P addresses need one which reaches I2P. CJDNS is not supported.
+    fn is_reachable(&self, addr: &NetAddr) -> bool
+    {
+        match addr {
+            NetAddr::Ipv4(_) | NetAddr::Ipv6(_) => true,
+            NetAddr::TorV3(_) => self.proxy.is_some(),
+            NetAddr::I2p(_) => self.proxy.as_ref().map_or(false, |proxy| proxy.i2p),
+            NetAddr::Cjdns(_) => false,
+        }
+    }
+
+    fn is_banned(&self, addr: &TargetAddr) -> bool
+    {
+        ban_key(addr)
+            .and_then(|key| self.banned.get(&key))
+            .map_or(false, |expiry| *expiry > Instant::now())
+    }
+
+    fn ban(&mut self, addr: &TargetAddr)
+    {
+        match ban_key(addr) {
+            Some(key) => {
+                info!("Ban {:?} for {:?}", addr, self.ban_duration);
+                self.banned.insert(key, Instant::now() + self.ban_duration);
+            },
+            None => info!("Do not ban local peer {:?}", addr),
+        }
+    }
+
+    // Add `score` to the total of `addr`, and return true if it is banned.
+    // Scores are forgotten after `ban_duration` without offenses.
+    fn add_misbehavior(&mut self, addr: &TargetAddr, score: u32) -> bool
+    {
+        let key = match ban_key(addr) {
+            None => return false,
+            Some(key) => key,
+        };
+        let expiry = Instant::now() + self.ban_duration;
+        let ban = {
+            let entry = self.misbehavior_scores.entry(key.clone()).or_insert((0, expiry));
+            entry.1 = expiry;
+            add_misbehavior_score(&mut entry.0, score)
+        };
+        if ban {
+            self.misbehavior_scores.remove(&key);
+            self.ban(addr);
+        }
+        ban
+    }
+
     // This function is called regulerly.
     // So even if connection_pool gets empty, it does not invoke recovery process immediately.
     fn health_check(&mut self, ctx: &mut Context<Self>)
     {
         // Remove all dropped connections
-        self.connection_pool.retain(|addr| addr.connected());
+        self.connection_pool.retain(|conn, _| conn.connected());
+        self.inbound_pool.retain(|conn, _| conn.connected());
+
+        let now = Instant::now();
+        self.banned.retain(|_, expiry| *expiry > now);
+        self.misbehavior_scores.retain(|_, (_, expiry)| *expiry > now);
 
         // If address pool is empty, we feed addresses to address pool but not try to establish a
         // new connection. It may happen in next cycle.
@@ -138,15 +473,24 @@ impl ConnectionPool
         } else if !self.has_enough_connection() {
             let next_idx = self.rng.next_u32() as usize % self.addr_pool.len();
             let addr = self.addr_pool.swap_remove(next_idx);
-            self.add_connection(&addr, ctx);
+            if !self.is_banned(&addr) {
+                let v2 = self.v2_transport;
+                self.add_connection(&addr, v2, ctx);
+            }
         }
     }
 
+    // Only outbound connections count, since inbound peers are not chosen by us.
     fn has_enough_connection(&self) -> bool
     {
         self.water_line <= self.connection_pool.len()
     }
 
+    fn has_room_for_inbound(&self) -> bool
+    {
+        self.inbound_pool.len() < self.max_inbound
+    }
+
     fn feed_initial_addrs(&mut self, ctx: &mut Context<Self>)
     {
         let seeds = match self.network {
@@ -154,16 +498,24 @@ impl ConnectionPool
             Network::Testnet => &TESTNET_DNS_SEEDS[..],
             Network::Regtest => return,
         };
+        let port = match self.network {
+            Network::Bitcoin => BITCOIN_PORT,
+            Network::Testnet => TESTNET_PORT,
+            Network::Regtest => unreachable!(),
+        };
+        // Resolving seeds by ourselves would leak DNS queries outside of the proxy.
+        // Let the proxy resolve them instead, and the seed nodes tell us other addresses.
+        if self.proxy.is_some() {
+            for seed in seeds {
+                self.addr_pool.push(TargetAddr::Domain(seed.to_string(), port));
+            }
+            return;
+        }
         let f = query_dns_seeds(&seeds)
             .into_actor(self)
-            .map(|ips, actor, _ctx| {
-                let port = match actor.network {
-                    Network::Bitcoin => BITCOIN_PORT,
-                    Network::Testnet => TESTNET_PORT,
-                    Network::Regtest => unreachable!(),
-                };
+            .map(move |ips, actor, _ctx| {
                 for ip in ips {
-                    actor.addr_pool.push(SocketAddr::new(ip, port));
+                    actor.addr_pool.push(TargetAddr::Ip(SocketAddr::new(ip, port)));
                 }
             })
             .map_err(|e, _actor, ctx| {
@@ -180,17 +532,53 @@ impl Handler<AddrsResponse> for ConnectionPool
 
     fn handle(&mut self, msg: AddrsResponse, _ctx: &mut Context<Self>)
     {
-        for (_ts, addr) in msg.0 {
+        for addr in msg.0 {
             if self.addr_pool.len() > ADDR_POOL_SIZE {
                 return;
             }
-            if let Ok(a) = addr.socket_addr() {
-                self.addr_pool.push(a);
+            let target = addr.to_target();
+            if self.is_reachable(&addr.addr) && !self.is_banned(&target) {
+                self.addr_pool.push(target);
             }
         }
     }
 }
 
+impl Handler<Listen> for ConnectionPool
+{
+    type Result = Result<SocketAddr, Error>;
+
+    fn handle(&mut self, msg: Listen, ctx: &mut Context<Self>) -> Result<SocketAddr, Error>
+    {
+        let listener = TcpListener::bind(&msg.addr)?;
+        let local_addr = listener.local_addr()?;
+        info!("Start listening on {}", local_addr);
+        ctx.add_stream(listener.incoming());
+        Ok(local_addr)
+    }
+}
+
+impl StreamHandler<TcpStream, io::Error> for ConnectionPool
+{
+    fn handle(&mut self, stream: TcpStream, ctx: &mut Context<Self>)
+    {
+        self.accept_connection(stream, ctx);
+    }
+
+    fn error(&mut self, err: io::Error, _ctx: &mut Context<Self>) -> Running
+    {
+        // e.g. Too many open files. Keep listening.
+        info!("Fail to accept inbound socket : {:?}", err);
+        Running::Continue
+    }
+
+    fn finished(&mut self, _ctx: &mut Context<Self>)
+    {
+        // Losing the listener does not affect outbound connections.
+        info!("Listener is closed");
+    }
+}
+
 impl Handler<GetConnections> for ConnectionPool
 {
     type Result = MessageResult<GetConnections>;
@@ -198,7 +586,7 @@ impl Handler<GetConnections> for ConnectionPool
     fn handle(&mut self, msg: GetConnections, _ctx: &mut Context<Self>) -> MessageResult<GetConnections>
     {
         let iter = self.connection_pool
-            .iter()
+            .keys()
             .filter(|addr| !msg.except.contains(addr))
             .cloned();
         let vec = sample_iter(&mut self.rng, iter, msg.num).unwrap_or_else(|v| v);
@@ -206,20 +594,74 @@ impl Handler<GetConnections> for ConnectionPool
     }
 }
 
+impl Handler<GetAllPeerInfo> for ConnectionPool
+{
+    type Result = ResponseFuture<Vec<PeerInfo>, ()>;
+
+    fn handle(&mut self, _msg: GetAllPeerInfo, _ctx: &mut Context<Self>) -> ResponseFuture<Vec<PeerInfo>, ()>
+    {
+        let fs: Vec<_> = self.connection_pool
+            .iter()
+            .chain(self.inbound_pool.iter())
+            .map(|(conn, addr)| {
+                let addr = addr.clone();
+                conn.send(GetPeerInfo()).then(|res| Ok(res.ok().map(|info| PeerInfo { addr, ..info })))
+            })
+            .collect();
+        let f = ::futures::future::join_all(fs).map(|infos| infos.into_iter().filter_map(|info| info).collect());
+        Box::new(f)
+    }
+}
+
 impl Handler<BanConnection> for ConnectionPool
 {
     type Result = ();
 
     fn handle(&mut self, msg: BanConnection, _ctx: &mut Context<Self>)
     {
-        if let Some(conn) = self.connection_pool.take(&msg.conn) {
+        let removed = self.connection_pool
+            .remove(&msg.conn)
+            .or_else(|| self.inbound_pool.remove(&msg.conn));
+        if let Some(addr) = removed {
+            self.ban(&addr);
+
             // Even if it fail to send Disconnect message, if all Addr are dropped, underlying
             // Connection will stop.
-            conn.do_send(Disconnect());
+            msg.conn.do_send(Disconnect());
+        }
+    }
+}
+
+impl Handler<ReportMisbehavior> for ConnectionPool
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: ReportMisbehavior, _ctx: &mut Context<Self>)
+    {
+        let addr = match self.connection_pool.get(&msg.conn).or_else(|| self.inbound_pool.get(&msg.conn)) {
+            None => return,
+            Some(addr) => addr.clone(),
+        };
+        if self.add_misbehavior(&addr, msg.score) {
+            self.connection_pool.remove(&msg.conn);
+            self.inbound_pool.remove(&msg.conn);
+            msg.conn.do_send(Disconnect());
         }
     }
 }
 
+// Inbound peers come from random ports, so we ban a whole host.
+// Local peers are never banned. Inbound peers through Tor or I2P all come from loopback.
+fn ban_key(addr: &TargetAddr) -> Option<TargetAddr>
+{
+    match addr {
+        TargetAddr::Ip(addr) if addr.ip().is_loopback() || addr.ip().is_unspecified() => None,
+        TargetAddr::Ip(addr) => Some(TargetAddr::Ip(SocketAddr::new(addr.ip(), 0))),
+        TargetAddr::Domain(domain, _) if domain == "localhost" => None,
+        TargetAddr::Domain(domain, _) => Some(TargetAddr::Domain(domain.clone(), 0)),
+    }
+}
+
 fn query_dns_seeds(seeds: &'static [&'static str]) -> Box<Future<Item = Vec<IpAddr>, Error = ResolveError>>
 {
     let f = ResolverFuture::new(ResolverConfig::google(), ResolverOpts::default())
@@ -230,3 +672,75 @@ fn query_dns_seeds(seeds: &'static [&'static str]) -> Box<Future<Item = Vec<IpAd
         .map(|vec_ips| vec_ips.iter().flat_map(|ips| ips.iter()).collect::<Vec<_>>());
     Box::new(f)
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use connection::BAN_THRESHOLD;
+
+    fn connection_pool() -> ConnectionPool
+    {
+        let blockchain = Arc::new(Mutex::new(BlockChain::new(Network::Regtest)));
+        ConnectionPool::new(Network::Regtest, 0, false, blockchain)
+    }
+
+    #[test]
+    fn reject_banned_host_on_any_port()
+    {
+        let mut pool = connection_pool();
+        let peer = TargetAddr::Ip("1.2.3.4:8333".parse().unwrap());
+        assert!(!pool.is_banned(&peer));
+
+        pool.ban(&peer);
+        assert!(pool.is_banned(&peer));
+        assert!(pool.is_banned(&TargetAddr::Ip("1.2.3.4:50123".parse().unwrap())));
+        assert!(!pool.is_banned(&TargetAddr::Ip("1.2.3.5:8333".parse().unwrap())));
+
+        let onion = TargetAddr::Domain("expyuzz4wqqyqhjn.onion".into(), 8333);
+        pool.ban(&onion);
+        assert!(pool.is_banned(&TargetAddr::Domain("expyuzz4wqqyqhjn.onion".into(), 18333)));
+    }
+
+    #[test]
+    fn never_ban_local_peers()
+    {
+        let mut pool = connection_pool();
+        for addr in &["127.0.0.1:50123", "[::1]:50123"] {
+            let peer = TargetAddr::Ip(addr.parse().unwrap());
+            pool.ban(&peer);
+            assert!(!pool.is_banned(&peer));
+        }
+        assert!(pool.banned.is_empty());
+    }
+
+    #[test]
+    fn ban_when_offenses_add_up_across_connections()
+    {
+        let mut pool = connection_pool();
+        let score = BAN_THRESHOLD / 5;
+        for port in 0..4 {
+            let peer = TargetAddr::Ip(SocketAddr::new("1.2.3.4".parse().unwrap(), 50000 + port));
+            assert!(!pool.add_misbehavior(&peer, score));
+            assert!(!pool.is_banned(&peer));
+        }
+        let peer = TargetAddr::Ip("1.2.3.4:8333".parse().unwrap());
+        assert!(pool.add_misbehavior(&peer, score));
+        assert!(pool.is_banned(&peer));
+        assert!(pool.misbehavior_scores.is_empty());
+
+        // Offenses of local peers are not added up.
+        let local = TargetAddr::Ip("127.0.0.1:50123".parse().unwrap());
+        assert!(!pool.add_misbehavior(&local, BAN_THRESHOLD));
+        assert!(pool.misbehavior_scores.is_empty());
+    }
+
+    #[test]
+    fn expire_ban()
+    {
+        let mut pool = connection_pool().with_ban_duration(Duration::from_secs(0));
+        let peer = TargetAddr::Ip("1.2.3.4:8333".parse().unwrap());
+        pool.ban(&peer);
+        assert!(!pool.is_banned(&peer));
+    }
+}
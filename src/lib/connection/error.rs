@@ -3,4 +3,54 @@ pub enum ConnectionError
 {
     #[fail(display = "Detect misbehavior peer")]
     MisbehavePeer,
+
+    #[fail(display = "Peer sends {} message with {} bytes payload, larger than {}", command, size, max)]
+    PayloadTooLarge
+    {
+        command: String,
+        size: u32,
+        max: u32,
+    },
+
+    #[fail(display = "Command {} is longer than 12 bytes", _0)]
+    CommandTooLong(String),
+
+    #[fail(display = "SOCKS5 proxy error : {}", _0)]
+    ProxyFailure(&'static str),
+
+    #[fail(display = "Connecting to ourselves")]
+    SelfConnection,
+
+    #[fail(display = "Peer's protocol version {} is older than {}", version, min_version)]
+    ObsoleteVersion
+    {
+        version: u32,
+        min_version: u32,
+    },
+
+    #[fail(display = "Peer's services {:#x} do not include required {:#x}", services, required)]
+    MissingServices
+    {
+        services: u64,
+        required: u64,
+    },
+
+    #[fail(display = "Peer's user agent is {} bytes, longer than {}", len, max)]
+    UserAgentTooLong
+    {
+        len: usize,
+        max: usize,
+    },
+
+    #[fail(display = "Connecting to peer timed out")]
+    ConnectTimeout,
+
+    #[fail(display = "Handshake with peer timed out")]
+    HandshakeTimeout,
+
+    #[fail(display = "Peer does not send any message within read timeout")]
+    ReadTimeout,
+
+    #[fail(display = "Peer does not support v2 transport")]
+    V2Unsupported,
 }
@@ -0,0 +1,110 @@
+use bitcoin::network::{message::NetworkMessage, message_blockdata::Inventory};
+use bitcoin::util::hash::Sha256dHash;
+
+use connection::{addr::PeerAddr, bloom::{BloomFilter, MerkleBlock},
+                 compact_block::{BlockTxn, BlockTxnRequest, HeaderAndShortIds},
+                 compact_filter::{CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFilters}};
+
+/// A message which is sent or received through `Socket`.
+///
+/// `NetworkMessage` of bitcoin crate does not cover every message which modern peers send.
+/// So this type wraps it and extends it.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum BtcMessage
+{
+    /// A message which bitcoin crate understands.
+    Network(NetworkMessage),
+
+    /// `sendheaders` (BIP130). Peer prefers `headers` to `inv` for new block announcements.
+    SendHeaders,
+
+    /// `wtxidrelay` (BIP339). Sent between `version` and `verack`.
+    WtxidRelay,
+
+    /// `sendaddrv2` (BIP155). Sent between `version` and `verack`.
+    SendAddrV2,
+
+    /// `addrv2` (BIP155). Unlike `addr`, it can carry Tor v3, I2P and CJDNS addresses.
+    AddrV2(Vec<PeerAddr>),
+
+    /// `sendcmpct` (BIP152).
+    SendCmpct
+    {
+        /// Whether peer wants new blocks to be announced by `cmpctblock` directly.
+        announce: bool,
+        version: u64,
+    },
+
+    /// `cmpctblock` (BIP152).
+    CmpctBlock(HeaderAndShortIds),
+
+    /// `getblocktxn` (BIP152). Requests transactions which are missing to reconstruct `cmpctblock`.
+    GetBlockTxn(BlockTxnRequest),
+
+    /// `blocktxn` (BIP152). An answer of `getblocktxn`.
+    BlockTxn(BlockTxn),
+
+    /// `getdata` which contains `MSG_CMPCT_BLOCK` (BIP152) entries.
+    /// bitcoin crate cannot express the inventory type, so such entries are separated from the others.
+    GetDataCmpct
+    {
+        invs: Vec<Inventory>,
+        cmpct_block_hashes: Vec<Sha256dHash>,
+    },
+
+    /// `getcfilters` (BIP157).
+    GetCFilters(GetCFilters),
+
+    /// `cfilter` (BIP157). One is sent for each block which `getcfilters` requests.
+    CFilter(CFilter),
+
+    /// `getcfheaders` (BIP157). Its payload is same as `getcfilters`.
+    GetCFHeaders(GetCFilters),
+
+    /// `cfheaders` (BIP157).
+    CFHeaders(CFHeaders),
+
+    /// `getcfcheckpt` (BIP157).
+    GetCFCheckpt(GetCFCheckpt),
+
+    /// `cfcheckpt` (BIP157).
+    CFCheckpt(CFCheckpt),
+
+    /// `filterload` (BIP37). Peer relays only transactions which match the filter.
+    FilterLoad(BloomFilter),
+
+    /// `filteradd` (BIP37). Adds an element to the loaded filter.
+    FilterAdd(Vec<u8>),
+
+    /// `filterclear` (BIP37). Peer relays all transactions again.
+    FilterClear,
+
+    /// `merkleblock` (BIP37). Followed by `tx` messages of matched transactions.
+    MerkleBlock(MerkleBlock),
+
+    /// `getdata` of `MSG_FILTERED_BLOCK` (BIP37) entries, which bitcoin crate cannot express.
+    GetDataFiltered(Vec<Sha256dHash>),
+
+    /// `notfound` which contains `MSG_FILTERED_BLOCK` (BIP37) entries, separated from the others like `GetDataCmpct`.
+    NotFoundFiltered
+    {
+        invs: Vec<Inventory>,
+        filtered_block_hashes: Vec<Sha256dHash>,
+    },
+
+    /// A message whose command we do not understand.
+    /// Its payload is kept as it is (checksum is already verified).
+    Unknown
+    {
+        command: String,
+        payload: Vec<u8>,
+    },
+}
+
+impl From<NetworkMessage> for BtcMessage
+{
+    fn from(msg: NetworkMessage) -> BtcMessage
+    {
+        BtcMessage::Network(msg)
+    }
+}
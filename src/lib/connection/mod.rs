@@ -1,8 +1,23 @@
+mod addr;
+mod bloom;
+mod compact_block;
+mod compact_filter;
 mod connection;
 mod error;
+mod message;
 
 pub mod socket;
 pub mod connection_pool;
+pub mod socks5;
+pub mod bip324;
 
+pub use self::addr::{NetAddr, PeerAddr};
+pub use self::bloom::{BloomFilter, MerkleBlock, BLOOM_UPDATE_ALL, BLOOM_UPDATE_NONE, BLOOM_UPDATE_P2PUBKEY_ONLY,
+                      MAX_BLOOM_FILTER_SIZE, MAX_FILTERADD_SIZE, MAX_HASH_FUNCS};
+pub use self::compact_block::{BlockTxn, BlockTxnRequest, HeaderAndShortIds, PartialBlock, PrefilledTransaction, TxPool,
+                              COMPACT_BLOCK_VERSION};
+pub use self::compact_filter::{CFCheckpt, CFHeaders, CFilter, GetCFCheckpt, GetCFilters, CFCHECKPT_INTERVAL,
+                               MAX_GETCFHEADERS_SIZE, MAX_GETCFILTERS_SIZE};
 pub use self::connection::*;
 pub use self::error::ConnectionError;
+pub use self::message::BtcMessage;
@@ -1,74 +1,408 @@
-use std::{io::Cursor, net::SocketAddr, time::{SystemTime, UNIX_EPOCH}};
-use bitcoin::network::{address::Address, constants::{Network, PROTOCOL_VERSION}, encodable::ConsensusDecodable,
-                       message::{CommandString, NetworkMessage, RawNetworkMessage}, message_network::VersionMessage,
+use std::{cmp, collections::HashSet, io::{self, Cursor, Read, Write}, net::{Ipv4Addr, SocketAddr},
+          sync::{atomic::{AtomicU64, Ordering}, Arc}, time::{Duration, SystemTime, UNIX_EPOCH}};
+use bitcoin::network::{address::Address, constants::Network, encodable::{ConsensusDecodable, VarInt},
+                       message::{CommandString, NetworkMessage, RawNetworkMessage},
+                       message_blockdata::{InvType, Inventory}, message_network::VersionMessage,
                        serialize::{serialize, Error as BitcoinSerializeError, RawDecoder}};
 use bitcoin::util::hash::Sha256dHash;
 
-use futures::{Future, IntoFuture, Sink, Stream};
-use tokio::{codec::{Encoder, FramedWrite}, io::{shutdown, AsyncRead, AsyncWrite, ReadHalf, Shutdown, WriteHalf},
-            net::TcpStream};
+use futures::{future::{loop_fn, Either, Loop}, stream, Future, IntoFuture, Poll, Sink, Stream};
+use tokio::{codec::{Decoder, Encoder, FramedRead, FramedWrite},
+            io::{shutdown, AsyncRead, AsyncWrite, ReadHalf, Shutdown, WriteHalf}, net::TcpStream,
+            timer::{timeout::Error as TimeoutError, Timeout}};
 use bytes::BytesMut;
 use failure::Error;
 
-use connection::error::ConnectionError;
+use connection::{addr::{decode_addrv2, encode_addrv2, MAX_ADDRV2_ADDR_SIZE, MAX_ADDR_ENTRIES},
+                 bip324::{self, Bip324Stream},
+                 bloom::{decode_filterload, decode_merkleblock, encode_filterload, encode_merkleblock,
+                         MAX_BLOOM_FILTER_SIZE, MAX_FILTERADD_SIZE},
+                 compact_block::{decode_blocktxn, decode_cmpctblock, decode_getblocktxn, encode_blocktxn,
+                                 encode_cmpctblock, encode_getblocktxn, MAX_COMPACT_BLOCK_TXS},
+                 compact_filter::{decode_cfcheckpt, decode_cfheaders, decode_cfilter, decode_getcfcheckpt,
+                                  decode_getcfilters, encode_cfcheckpt, encode_cfheaders, encode_cfilter,
+                                  encode_getcfcheckpt, encode_getcfilters, MAX_GETCFHEADERS_SIZE},
+                 error::ConnectionError, message::BtcMessage,
+                 socks5::{Proxy, TargetAddr}};
 
 pub const USER_AGENT: &str = "bitcoinrs v0.0";
 
+/// Protocol version which we announce.
+/// 70016 or later is required to negotiate `wtxidrelay` (BIP339).
+pub const PROTOCOL_VERSION: u32 = 70016;
+
+/// Peers older than this are rejected by default.
+pub const MIN_PEER_VERSION: u32 = 70001;
+
+const WTXID_RELAY_VERSION: u32 = 70016;
+
+/// Service bits which are advertised in `version` message.
+pub const NODE_NETWORK: u64 = 1;
+pub const NODE_BLOOM: u64 = 1 << 2;
+pub const NODE_WITNESS: u64 = 1 << 3;
+pub const NODE_COMPACT_FILTERS: u64 = 1 << 6;
+pub const NODE_NETWORK_LIMITED: u64 = 1 << 10;
+
+/// Same as `MAX_SUBVERSION_LENGTH` of bitcoin core.
+pub const MAX_USER_AGENT_LEN: usize = 256;
+
+/// Requirements which remote peer's `version` message **MUST** satisfy.
+#[derive(Debug, Clone)]
+pub struct VersionPolicy
+{
+    /// Minimum protocol version we accept.
+    pub min_version: u32,
+    /// Service bits which remote peer must advertise.
+    pub required_services: u64,
+    /// Maximum length of remote peer's user agent.
+    pub max_user_agent_len: usize,
+}
+
+impl VersionPolicy
+{
+    /// Accept any services, since light clients and pruned nodes connect to us as well.
+    pub fn inbound() -> VersionPolicy
+    {
+        VersionPolicy {
+            required_services: 0,
+            ..VersionPolicy::default()
+        }
+    }
+}
+
+impl Default for VersionPolicy
+{
+    /// Accept only a full node which is able to serve witness blocks.
+    fn default() -> VersionPolicy
+    {
+        VersionPolicy {
+            min_version: MIN_PEER_VERSION,
+            required_services: NODE_NETWORK | NODE_WITNESS,
+            max_user_agent_len: MAX_USER_AGENT_LEN,
+        }
+    }
+}
+
+/// Our side of parameters used in handshake.
+#[derive(Debug, Clone)]
+pub struct HandshakeConfig
+{
+    pub start_height: i32,
+    pub services: u64,
+    pub relay: bool,
+    /// Nonce of our `version` message. It should be random for each connection.
+    pub nonce: u64,
+    /// Nonces which our other handshakes are using.
+    /// If remote peer's nonce is one of them, we are connecting to ourselves.
+    pub local_nonces: HashSet<u64>,
+    pub policy: VersionPolicy,
+    /// Whole handshake must complete within this duration.
+    pub timeout: Duration,
+    /// Announce `wtxidrelay` (BIP339) to peer.
+    pub wtxid_relay: bool,
+    /// Announce `sendaddrv2` (BIP155) to peer.
+    pub addr_v2: bool,
+}
+
+/// Features which are agreed with remote peer.
+/// `wtxid_relay` and `addr_v2` are fixed during handshake,
+/// but the others may be announced by peer at any time after that.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct NegotiatedFeatures
+{
+    /// Lower one of our and peer's protocol versions.
+    pub version: u32,
+    /// Both of us announced `wtxidrelay`, so transactions are announced by wtxid.
+    pub wtxid_relay: bool,
+    /// Peer announced `sendaddrv2`, so it wants `addrv2` instead of `addr`.
+    pub addr_v2: bool,
+    /// Peer announced `sendheaders`, so it wants new blocks to be announced by `headers`.
+    pub send_headers: bool,
+    /// Compact block version which peer announced by `sendcmpct`.
+    pub compact_block_version: Option<u64>,
+    /// Peer wants new blocks to be announced by `cmpctblock` directly.
+    pub compact_block_announce: bool,
+}
+
+/// Deadlines of each phase of a connection.
+#[derive(Debug, Clone)]
+pub struct Timeouts
+{
+    pub connect: Duration,
+    pub handshake: Duration,
+    /// Maximum interval between incoming messages.
+    /// `None` means we wait for next message forever.
+    pub read: Option<Duration>,
+}
+
+impl Default for Timeouts
+{
+    /// Same as bitcoin core's default.
+    fn default() -> Timeouts
+    {
+        Timeouts {
+            connect: Duration::from_secs(5),
+            handshake: Duration::from_secs(60),
+            read: Some(Duration::from_secs(20 * 60)),
+        }
+    }
+}
+
+/// A stream which talks bitcoin protocol.
+///
+/// Underlying stream `S` can be any duplex stream such as `TcpStream`, `UnixStream` or a proxied
+/// stream. Since such a stream does not always know addresses, they are supplied separately.
 #[derive(Debug)]
 pub struct Socket<S>
 {
     socket: S,
     network: Network,
+    local_addr: SocketAddr,
+    peer_addr: SocketAddr,
+    read_timeout: Option<Duration>,
+    // Set by `count_bytes`.
+    byte_counts: Option<ByteCounts>,
+}
+
+/// Numbers of bytes which are sent and received through a `CountingStream`.
+#[derive(Debug, Clone, Default)]
+pub struct ByteCounts
+{
+    sent: Arc<AtomicU64>,
+    recv: Arc<AtomicU64>,
+}
+
+impl ByteCounts
+{
+    pub fn sent(&self) -> u64
+    {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn recv(&self) -> u64
+    {
+        self.recv.load(Ordering::Relaxed)
+    }
 }
 
+/// Count bytes which go through a stream.
 #[derive(Debug)]
-pub struct HandshakedSocket<S>(Socket<S>);
+pub struct CountingStream<S>
+{
+    stream: S,
+    counts: ByteCounts,
+}
+
+impl<S: Read> Read for CountingStream<S>
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>
+    {
+        let n = self.stream.read(buf)?;
+        self.counts.recv.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for CountingStream<S>
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>
+    {
+        let n = self.stream.write(buf)?;
+        self.counts.sent.fetch_add(n as u64, Ordering::Relaxed);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()>
+    {
+        self.stream.flush()
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for CountingStream<S> {}
+
+impl<S: AsyncWrite> AsyncWrite for CountingStream<S>
+{
+    fn shutdown(&mut self) -> Poll<(), io::Error>
+    {
+        self.stream.shutdown()
+    }
+}
+
+/// What peer tells about itself in `version` message.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeerVersion
+{
+    pub version: u32,
+    pub services: u64,
+    pub user_agent: String,
+    pub start_height: i32,
+    pub relay: bool,
+}
+
+#[derive(Debug)]
+pub struct HandshakedSocket<S>
+{
+    socket: Socket<S>,
+    features: NegotiatedFeatures,
+    peer_version: PeerVersion,
+    inbound: bool,
+}
 
 impl Socket<TcpStream>
 {
     /// Convenient function to create a new Tcp Socket
-    pub fn connect(addr: &SocketAddr, network: Network) -> impl Future<Item = Self, Error = Error>
+    pub fn connect(addr: &SocketAddr, network: Network, timeout: Duration) -> impl Future<Item = Self, Error = Error>
     {
-        TcpStream::connect(addr)
-            .map(move |socket| Socket::new(socket, network))
+        let f = TcpStream::connect(addr)
             .map_err(|e| Error::from(e))
+            .and_then(move |socket| Socket::from_tcp(socket, network));
+        with_deadline(f, timeout, || ConnectionError::ConnectTimeout)
     }
 
-    pub fn begin_handshake(
-        self,
-        start_height: i32,
-        services: u64,
-        relay: bool,
-    ) -> impl Future<Item = HandshakedSocket<TcpStream>, Error = Error>
+    /// Connect to `target` through SOCKS5 proxy.
+    ///
+    /// Our address is never told to peer. If `target` is a domain (e.g. ".onion" address), peer's
+    /// address is not told either.
+    pub fn connect_via_proxy(
+        proxy: &Proxy,
+        target: &TargetAddr,
+        network: Network,
+        timeout: Duration,
+    ) -> impl Future<Item = Self, Error = Error>
     {
-        begin_handshake(self, start_height, services, relay)
+        let unspecified = SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0);
+        let peer_addr = match target {
+            TargetAddr::Ip(addr) => *addr,
+            TargetAddr::Domain(_, _) => unspecified,
+        };
+        let f = proxy
+            .connect(target)
+            .map(move |socket| Socket::new(socket, network, unspecified, peer_addr));
+        with_deadline(f, timeout, || ConnectionError::ConnectTimeout)
     }
 
-    // TODO
-    pub fn reply_handshake(self) -> Result<HandshakedSocket<TcpStream>, Error>
+    /// Create a new Socket whose addresses are taken from given `TcpStream`.
+    pub fn from_tcp(socket: TcpStream, network: Network) -> Result<Self, Error>
     {
-        unimplemented!();
+        let local_addr = socket.local_addr()?;
+        let peer_addr = socket.peer_addr()?;
+        Ok(Socket::new(socket, network, local_addr, peer_addr))
     }
 }
 
 impl<S> Socket<S>
 {
-    pub fn new(socket: S, network: Network) -> Socket<S>
+    /// `local_addr` and `peer_addr` are told to peer in `version` message.
+    pub fn new(socket: S, network: Network, local_addr: SocketAddr, peer_addr: SocketAddr) -> Socket<S>
+    {
+        Socket {
+            socket,
+            network,
+            local_addr,
+            peer_addr,
+            read_timeout: None,
+            byte_counts: None,
+        }
+    }
+
+    /// Count bytes which go through the current stream.
+    /// Call it before switching to v2 transport to count bytes on the wire, including encryption overhead.
+    pub fn count_bytes(self) -> Socket<CountingStream<S>>
+    {
+        let (stream, mut empty) = self.breakdown();
+        let counts = ByteCounts::default();
+        empty.byte_counts = Some(counts.clone());
+        empty.attach(CountingStream { stream, counts })
+    }
+
+    /// `None` unless `count_bytes` is called.
+    pub fn byte_counts(&self) -> Option<&ByteCounts>
+    {
+        self.byte_counts.as_ref()
+    }
+
+    /// If next message does not arrive within `timeout`, receiving fails.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>)
+    {
+        self.read_timeout = timeout;
+    }
+
+    pub fn local_addr(&self) -> SocketAddr
+    {
+        self.local_addr
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr
     {
-        Socket { socket, network }
+        self.peer_addr
     }
 
-    fn breakdown(self) -> (S, Network)
+    pub fn begin_handshake(self, config: HandshakeConfig) -> impl Future<Item = HandshakedSocket<S>, Error = Error>
+    where S: AsyncRead + AsyncWrite
     {
-        (self.socket, self.network)
+        begin_handshake(self, config)
+    }
+
+    pub fn reply_handshake(self, config: HandshakeConfig) -> impl Future<Item = HandshakedSocket<S>, Error = Error>
+    where S: AsyncRead + AsyncWrite
+    {
+        reply_handshake(self, config)
+    }
+
+    /// Switch to BIP324 v2 transport as an initiator. It must be done before `begin_handshake`.
+    /// Fails with `ConnectionError::V2Unsupported` if peer seems not to support it.
+    pub fn begin_v2_transport(self, timeout: Duration) -> impl Future<Item = Socket<Bip324Stream<S>>, Error = Error>
+    where S: AsyncRead + AsyncWrite
+    {
+        let (socket, empty) = self.breakdown();
+        let f = bip324::initiate(socket, empty.network).map(move |stream| empty.attach(stream));
+        with_deadline(f, timeout, || ConnectionError::HandshakeTimeout)
+    }
+
+    /// Accept BIP324 v2 transport. It must be done before `reply_handshake`.
+    /// If peer speaks v1, we speak v1 as well.
+    pub fn accept_v2_transport(self, timeout: Duration) -> impl Future<Item = Socket<Bip324Stream<S>>, Error = Error>
+    where S: AsyncRead + AsyncWrite
+    {
+        let (socket, empty) = self.breakdown();
+        let f = bip324::respond(socket, empty.network).map(move |stream| empty.attach(stream));
+        with_deadline(f, timeout, || ConnectionError::HandshakeTimeout)
+    }
+
+    /// Keep v1 transport, but in the same type as v2 one so that both are treated equally.
+    pub fn into_v1_transport(self) -> Socket<Bip324Stream<S>>
+    {
+        let (socket, empty) = self.breakdown();
+        let stream = Bip324Stream::plaintext(socket, empty.network, Vec::new());
+        empty.attach(stream)
+    }
+
+    // Returned `Socket<()>` keeps everything except an underlying stream.
+    fn breakdown(self) -> (S, Socket<()>)
+    {
+        let empty = self.attach(());
+        (self.socket, empty)
+    }
+
+    // Create a new Socket which has same properties except an underlying stream.
+    fn attach<T>(&self, socket: T) -> Socket<T>
+    {
+        Socket {
+            socket,
+            network: self.network,
+            local_addr: self.local_addr,
+            peer_addr: self.peer_addr,
+            read_timeout: self.read_timeout,
+            byte_counts: self.byte_counts.clone(),
+        }
     }
 
     pub fn split(self) -> (Socket<ReadHalf<S>>, Socket<WriteHalf<S>>)
     where S: AsyncRead + AsyncWrite
     {
-        let (socket, net) = self.breakdown();
+        let (socket, empty) = self.breakdown();
         let (r, w) = socket.split();
-        (Socket::new(r, net.clone()), Socket::new(w, net))
+        (empty.attach(r), empty.attach(w))
     }
 
     pub fn shutdown(self) -> Shutdown<S>
@@ -77,35 +411,44 @@ impl<S> Socket<S>
         shutdown(self.socket)
     }
 
-    pub fn send_msg(self, msg: NetworkMessage) -> impl Future<Item = Self, Error = Error>
-    where S: AsyncWrite
+    pub fn send_msg<M>(self, msg: M) -> impl Future<Item = Self, Error = Error>
+    where
+        S: AsyncWrite,
+        M: Into<BtcMessage>,
     {
+        let msg = msg.into();
         debug!("Send a message {:?}", msg);
-        let (socket, network) = self.breakdown();
-        let serialized = encode(msg, network.clone());
-
-        ::tokio::io::write_all(socket, serialized)
-            .and_then(|(socket, _)| ::tokio::io::flush(socket))
-            .map_err(Error::from)
-            .map(move |socket| Socket::new(socket, network))
+        let (socket, empty) = self.breakdown();
+        encode(msg, empty.network)
+            .into_future()
+            .and_then(|serialized| ::tokio::io::write_all(socket, serialized).map_err(Error::from))
+            .and_then(|(socket, _)| ::tokio::io::flush(socket).map_err(Error::from))
+            .map(move |socket| empty.attach(socket))
     }
 
-    pub fn send_msg_sink(self) -> impl Sink<SinkItem = NetworkMessage, SinkError = Error>
+    pub fn send_msg_sink(self) -> impl Sink<SinkItem = BtcMessage, SinkError = Error>
     where S: AsyncWrite
     {
-        let (socket, network) = self.breakdown();
-        let encoder = BtcEncoder { network };
+        let (socket, empty) = self.breakdown();
+        let encoder = BtcEncoder {
+            network: empty.network,
+        };
         FramedWrite::new(socket, encoder)
     }
 
-    pub fn recv_msg(self) -> impl Future<Item = (NetworkMessage, Self), Error = Error>
+    /// Receive exactly one message.
+    ///
+    /// Unlike `recv_msg_stream`, this never reads bytes of following messages.
+    /// So it is safe to call this repeatedly, e.g. during handshake.
+    pub fn recv_msg(self) -> impl Future<Item = (BtcMessage, Self), Error = Error>
     where S: AsyncRead
     {
-        let (socket, network) = self.breakdown();
-        let network2 = network.clone();
+        let (socket, empty) = self.breakdown();
+        let network = empty.network;
+        let read_timeout = empty.read_timeout;
         let header_buf: [u8; RAW_NETWORK_MESSAGE_HEADER_SIZE] = [0; RAW_NETWORK_MESSAGE_HEADER_SIZE];
 
-        ::tokio::io::read_exact(socket, header_buf)
+        let f = ::tokio::io::read_exact(socket, header_buf)
             .map_err(Error::from)
             .and_then(move |(socket, bytes)| {
                 let header = decode_msg_header(&bytes, &network)?;
@@ -120,122 +463,442 @@ impl<S> Socket<S>
             })
             .and_then(move |(socket, bytes, header)| {
                 let msg = decode_and_check_msg_payload(&bytes, &header)?;
-                Ok((msg, Socket::new(socket, network2)))
-            })
+                Ok((msg, empty.attach(socket)))
+            });
+
+        match read_timeout {
+            None => Either::A(f),
+            Some(timeout) => Either::B(with_deadline(f, timeout, || ConnectionError::ReadTimeout)),
+        }
     }
 
-    pub fn recv_msg_stream(self) -> impl Stream<Item = NetworkMessage, Error = Error>
-    where S: AsyncRead
+    pub fn recv_msg_stream(self) -> Box<dyn Stream<Item = BtcMessage, Error = Error>>
+    where S: AsyncRead + 'static
     {
-        ::futures::stream::unfold(self, |s| Some(s.recv_msg()))
+        let (socket, empty) = self.breakdown();
+        let stream = FramedRead::new(socket, BtcDecoder::new(empty.network));
+
+        match empty.read_timeout {
+            None => Box::new(stream),
+            Some(timeout) => {
+                let stream = Timeout::new(stream, timeout).map_err(|e| {
+                    if e.is_elapsed() {
+                        info!("Peer does not send any message for a long time");
+                    }
+                    timeout_error(e, || ConnectionError::ReadTimeout)
+                });
+                Box::new(stream)
+            },
+        }
     }
 }
 
 impl<S> HandshakedSocket<S>
 {
+    pub fn features(&self) -> &NegotiatedFeatures
+    {
+        &self.features
+    }
+
+    pub fn peer_version(&self) -> &PeerVersion
+    {
+        &self.peer_version
+    }
+
+    /// True if peer has connected to us.
+    pub fn is_inbound(&self) -> bool
+    {
+        self.inbound
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr
+    {
+        self.socket.peer_addr()
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>)
+    {
+        self.socket.set_read_timeout(timeout);
+    }
+
+    pub fn byte_counts(&self) -> Option<&ByteCounts>
+    {
+        self.socket.byte_counts()
+    }
+
+    /// Same as `Socket::count_bytes`.
+    pub fn count_bytes(self) -> HandshakedSocket<CountingStream<S>>
+    {
+        let (socket, rest) = self.take_socket();
+        rest.replace_socket(socket.count_bytes())
+    }
+
+    /// Replace an underlying stream, e.g. to wrap it or to erase its type.
+    pub fn map_stream<T, F>(self, f: F) -> HandshakedSocket<T>
+    where F: FnOnce(S) -> T
+    {
+        let (socket, rest) = self.take_socket();
+        let (stream, empty) = socket.breakdown();
+        rest.replace_socket(empty.attach(f(stream)))
+    }
+
     pub fn split(self) -> (HandshakedSocket<ReadHalf<S>>, HandshakedSocket<WriteHalf<S>>)
     where S: AsyncRead + AsyncWrite
     {
-        let (r, w) = self.0.split();
-        (HandshakedSocket(r), HandshakedSocket(w))
+        let (r, w) = self.socket.split();
+        let r = HandshakedSocket {
+            socket: r,
+            features: self.features.clone(),
+            peer_version: self.peer_version.clone(),
+            inbound: self.inbound,
+        };
+        let w = HandshakedSocket {
+            socket: w,
+            features: self.features,
+            peer_version: self.peer_version,
+            inbound: self.inbound,
+        };
+        (r, w)
     }
 
     pub fn shutdown(self) -> Shutdown<S>
     where S: AsyncWrite
     {
-        self.0.shutdown()
+        self.socket.shutdown()
     }
 
-    pub fn send_msg(self, msg: NetworkMessage) -> impl Future<Item = Self, Error = Error>
-    where S: AsyncWrite
+    pub fn send_msg<M>(self, msg: M) -> impl Future<Item = Self, Error = Error>
+    where
+        S: AsyncWrite,
+        M: Into<BtcMessage>,
     {
-        self.0.send_msg(msg).map(|s| HandshakedSocket(s))
+        let (socket, rest) = self.take_socket();
+        socket.send_msg(msg).map(move |socket| rest.replace_socket(socket))
     }
 
-    pub fn send_msg_sink(self) -> impl Sink<SinkItem = NetworkMessage, SinkError = Error>
+    pub fn send_msg_sink(self) -> impl Sink<SinkItem = BtcMessage, SinkError = Error>
     where S: AsyncWrite
     {
-        self.0.send_msg_sink()
+        self.socket.send_msg_sink()
     }
 
-    pub fn recv_msg(self) -> impl Future<Item = (NetworkMessage, Self), Error = Error>
+    pub fn recv_msg(self) -> impl Future<Item = (BtcMessage, Self), Error = Error>
     where S: AsyncRead
     {
-        self.0.recv_msg().map(|(msg, socket)| (msg, HandshakedSocket(socket)))
+        let (socket, rest) = self.take_socket();
+        socket.recv_msg().map(move |(msg, socket)| (msg, rest.replace_socket(socket)))
     }
 
-    pub fn recv_msg_stream(self) -> impl Stream<Item = NetworkMessage, Error = Error>
-    where S: AsyncRead
+    pub fn recv_msg_stream(self) -> Box<dyn Stream<Item = BtcMessage, Error = Error>>
+    where S: AsyncRead + 'static
+    {
+        self.socket.recv_msg_stream()
+    }
+
+    // Returned `HandshakedSocket<()>` keeps everything except a socket.
+    fn take_socket(self) -> (Socket<S>, HandshakedSocket<()>)
     {
-        self.0.recv_msg_stream()
+        let rest = HandshakedSocket {
+            socket: self.socket.attach(()),
+            features: self.features,
+            peer_version: self.peer_version,
+            inbound: self.inbound,
+        };
+        (self.socket, rest)
+    }
+
+    fn replace_socket<T>(self, socket: Socket<T>) -> HandshakedSocket<T>
+    {
+        HandshakedSocket {
+            socket,
+            features: self.features,
+            peer_version: self.peer_version,
+            inbound: self.inbound,
+        }
     }
 }
 
-pub fn begin_handshake(
-    socket: Socket<TcpStream>,
-    start_height: i32,
-    services: u64,
-    relay: bool,
-) -> impl Future<Item = HandshakedSocket<TcpStream>, Error = Error>
+/// Handshake as an initiator of a connection.
+/// We send `version` message first and then wait for peer's `version` and `verack`.
+/// Features announced between `version` and `verack` are negotiated as well.
+pub fn begin_handshake<S>(
+    socket: Socket<S>,
+    config: HandshakeConfig,
+) -> impl Future<Item = HandshakedSocket<S>, Error = Error>
+where S: AsyncRead + AsyncWrite
 {
-    version_msg(&socket.socket, start_height, services, relay)
-        .into_future()
-        .and_then(|v| socket.send_msg(NetworkMessage::Version(v)))
-        .and_then(|socket| socket.recv_msg())
-        .and_then(|(msg, socket)| {
-            match msg {
-                NetworkMessage::Version(v) => Ok((v, socket)),
-                msg => {
-                    info!("Fail to handshake. Expect Version msg but found {:?}", msg);
-                    bail!(ConnectionError::MisbehavePeer);
-                },
-            }
+    let timeout = config.timeout;
+    let v = version_msg(&socket, &config);
+    let f = socket
+        .send_msg(NetworkMessage::Version(v))
+        .and_then(|socket| recv_version_msg(socket))
+        .and_then(move |(remote_v, socket)| {
+            check_remote_version_msg(&remote_v, &config).map(|()| (remote_v, socket, config))
         })
-        .and_then(|(remote_v, socket)| check_remote_version_msg(remote_v).map(|()| socket))
-        .and_then(|socket| socket.send_msg(NetworkMessage::Verack))
-        .and_then(|socket| socket.recv_msg())
-        .and_then(|(msg, socket)| {
-            match msg {
-                NetworkMessage::Verack => Ok(HandshakedSocket(socket)),
-                msg => {
-                    info!("Fail to handshake. Expect Verack msg but found {:?}", msg);
-                    bail!(ConnectionError::MisbehavePeer);
-                },
-            }
+        .and_then(|(remote_v, socket, config)| finish_handshake(socket, &remote_v, &config, false));
+    with_deadline(f, timeout, || ConnectionError::HandshakeTimeout)
+}
+
+/// Handshake as a responder of a connection.
+/// We wait for peer's `version` message first and then send our `version` and `verack`.
+pub fn reply_handshake<S>(
+    socket: Socket<S>,
+    config: HandshakeConfig,
+) -> impl Future<Item = HandshakedSocket<S>, Error = Error>
+where S: AsyncRead + AsyncWrite
+{
+    let timeout = config.timeout;
+    let f = recv_version_msg(socket)
+        .and_then(move |(remote_v, socket)| {
+            check_remote_version_msg(&remote_v, &config).map(|()| (remote_v, socket, config))
+        })
+        .and_then(|(remote_v, socket, config)| {
+            let v = version_msg(&socket, &config);
+            socket.send_msg(NetworkMessage::Version(v)).map(|socket| (remote_v, socket, config))
+        })
+        .and_then(|(remote_v, socket, config)| finish_handshake(socket, &remote_v, &config, true));
+    with_deadline(f, timeout, || ConnectionError::HandshakeTimeout)
+}
+
+/// Fail with an error made by `on_elapsed` if `f` does not complete within `timeout`.
+fn with_deadline<F, E>(f: F, timeout: Duration, on_elapsed: E) -> impl Future<Item = F::Item, Error = Error>
+where
+    F: Future<Error = Error>,
+    E: FnOnce() -> ConnectionError,
+{
+    Timeout::new(f, timeout).map_err(|e| timeout_error(e, on_elapsed))
+}
+
+fn timeout_error<E>(e: TimeoutError<Error>, on_elapsed: E) -> Error
+where E: FnOnce() -> ConnectionError
+{
+    if e.is_elapsed() {
+        Error::from(on_elapsed())
+    } else if e.is_inner() {
+        e.into_inner().unwrap()
+    } else {
+        Error::from(e.into_timer().unwrap())
+    }
+}
+
+fn recv_version_msg<S>(socket: Socket<S>) -> impl Future<Item = (VersionMessage, Socket<S>), Error = Error>
+where S: AsyncRead
+{
+    socket.recv_msg().and_then(|(msg, socket)| {
+        match msg {
+            BtcMessage::Network(NetworkMessage::Version(v)) => Ok((v, socket)),
+            msg => {
+                info!("Fail to handshake. Expect Version msg but found {:?}", msg);
+                return Err(Error::from(ConnectionError::MisbehavePeer));
+            },
+        }
+    })
+}
+
+/// Announce features which we support and `verack`, and then wait for peer's `verack`.
+/// Features which peer announces in the meantime are recorded.
+fn finish_handshake<S>(
+    socket: Socket<S>,
+    remote_v: &VersionMessage,
+    config: &HandshakeConfig,
+    inbound: bool,
+) -> impl Future<Item = HandshakedSocket<S>, Error = Error>
+where S: AsyncRead + AsyncWrite
+{
+    // BIP339 requires both of us to announce 70016 or later.
+    let wtxid_relay = config.wtxid_relay && remote_v.version >= WTXID_RELAY_VERSION;
+
+    let mut msgs = Vec::new();
+    if wtxid_relay {
+        msgs.push(BtcMessage::WtxidRelay);
+    }
+    if config.addr_v2 {
+        msgs.push(BtcMessage::SendAddrV2);
+    }
+    msgs.push(BtcMessage::Network(NetworkMessage::Verack));
+
+    let features = NegotiatedFeatures {
+        version: cmp::min(PROTOCOL_VERSION, remote_v.version),
+        ..NegotiatedFeatures::default()
+    };
+    let peer_version = PeerVersion {
+        version: remote_v.version,
+        services: remote_v.services,
+        user_agent: remote_v.user_agent.clone(),
+        start_height: remote_v.start_height,
+        relay: remote_v.relay,
+    };
+
+    stream::iter_ok::<_, Error>(msgs)
+        .fold(socket, |socket, msg| socket.send_msg(msg))
+        .and_then(move |socket| {
+            loop_fn((socket, features), move |(socket, mut features)| {
+                let peer_version = peer_version.clone();
+                socket.recv_msg().and_then(move |(msg, socket)| {
+                    match msg {
+                        BtcMessage::Network(NetworkMessage::Verack) => {
+                            return Ok(Loop::Break(HandshakedSocket {
+                                socket,
+                                features,
+                                peer_version,
+                                inbound,
+                            }));
+                        },
+                        BtcMessage::WtxidRelay => features.wtxid_relay = wtxid_relay,
+                        BtcMessage::SendAddrV2 => features.addr_v2 = true,
+                        BtcMessage::Unknown { command, .. } => {
+                            debug!("Ignore {} msg during handshake", command);
+                        },
+                        msg => {
+                            info!("Fail to handshake. Expect Verack msg but found {:?}", msg);
+                            return Err(Error::from(ConnectionError::MisbehavePeer));
+                        },
+                    }
+                    Ok(Loop::Continue((socket, features)))
+                })
+            })
         })
 }
 
-fn version_msg(socket: &TcpStream, start_height: i32, services: u64, relay: bool) -> Result<VersionMessage, Error>
+fn version_msg<S>(socket: &Socket<S>, config: &HandshakeConfig) -> VersionMessage
 {
     let ts = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-    let sender = Address::new(&socket.local_addr()?, services.clone());
-    let receiver = Address::new(&socket.peer_addr()?, services.clone());
-    Ok(VersionMessage {
+    let sender = Address::new(&socket.local_addr, config.services);
+    let receiver = Address::new(&socket.peer_addr, config.services);
+    VersionMessage {
         version: PROTOCOL_VERSION,
-        services,
+        services: config.services,
         timestamp: ts,
         receiver,
         sender,
-        nonce: 0,
+        nonce: config.nonce,
         user_agent: USER_AGENT.into(),
-        start_height,
-        relay,
-    })
+        start_height: config.start_height,
+        relay: config.relay,
+    }
 }
 
-fn check_remote_version_msg(_version: VersionMessage) -> Result<(), Error>
+fn check_remote_version_msg(version: &VersionMessage, config: &HandshakeConfig) -> Result<(), Error>
 {
-    // Currently does not check anything
+    if version.nonce == config.nonce || config.local_nonces.contains(&version.nonce) {
+        info!("Reject peer. Connecting to ourselves");
+        return Err(Error::from(ConnectionError::SelfConnection));
+    }
+
+    let policy = &config.policy;
+    if version.version < policy.min_version {
+        info!("Reject peer. Protocol version {} is too old", version.version);
+        return Err(Error::from(ConnectionError::ObsoleteVersion {
+            version: version.version,
+            min_version: policy.min_version,
+        }));
+    }
+
+    if version.services & policy.required_services != policy.required_services {
+        info!("Reject peer. Services {:b} are not enough", version.services);
+        return Err(Error::from(ConnectionError::MissingServices {
+            services: version.services,
+            required: policy.required_services,
+        }));
+    }
+
+    if version.user_agent.len() > policy.max_user_agent_len {
+        info!("Reject peer. User agent is too long");
+        return Err(Error::from(ConnectionError::UserAgentTooLong {
+            len: version.user_agent.len(),
+            max: policy.max_user_agent_len,
+        }));
+    }
+
     Ok(())
 }
 
-fn encode(msg: NetworkMessage, network: Network) -> Vec<u8>
+/// Fails if a command of `BtcMessage::Unknown` does not fit in a message header.
+fn encode(msg: BtcMessage, network: Network) -> Result<Vec<u8>, Error>
 {
-    let msg = RawNetworkMessage {
-        magic: network.magic(),
-        payload: msg,
+    if let BtcMessage::Unknown { command, .. } = &msg {
+        if command.len() > COMMAND_SIZE {
+            return Err(Error::from(ConnectionError::CommandTooLong(command.clone())));
+        }
+    }
+    let encoded = match msg {
+        BtcMessage::Network(msg) => {
+            let msg = RawNetworkMessage {
+                magic: network.magic(),
+                payload: msg,
+            };
+            serialize(&msg).unwrap() // Never fail
+        },
+        BtcMessage::SendHeaders => encode_raw("sendheaders", &[], network),
+        BtcMessage::WtxidRelay => encode_raw("wtxidrelay", &[], network),
+        BtcMessage::SendAddrV2 => encode_raw("sendaddrv2", &[], network),
+        BtcMessage::AddrV2(addrs) => encode_raw("addrv2", &encode_addrv2(&addrs), network),
+        BtcMessage::SendCmpct { announce, version } => {
+            let mut payload = serialize(&announce).unwrap();
+            payload.extend_from_slice(&serialize(&version).unwrap());
+            encode_raw("sendcmpct", &payload, network)
+        },
+        BtcMessage::CmpctBlock(cmpct) => encode_raw("cmpctblock", &encode_cmpctblock(&cmpct), network),
+        BtcMessage::GetBlockTxn(req) => encode_raw("getblocktxn", &encode_getblocktxn(&req), network),
+        BtcMessage::BlockTxn(txn) => encode_raw("blocktxn", &encode_blocktxn(&txn), network),
+        BtcMessage::GetDataCmpct { invs, cmpct_block_hashes } => {
+            let mut payload = serialize(&VarInt((invs.len() + cmpct_block_hashes.len()) as u64)).unwrap();
+            for inv in invs.iter() {
+                payload.extend_from_slice(&serialize(inv).unwrap());
+            }
+            for hash in cmpct_block_hashes.iter() {
+                payload.extend_from_slice(&serialize(&MSG_CMPCT_BLOCK).unwrap());
+                payload.extend_from_slice(&serialize(hash).unwrap());
+            }
+            encode_raw("getdata", &payload, network)
+        },
+        BtcMessage::GetDataFiltered(block_hashes) => {
+            let mut payload = serialize(&VarInt(block_hashes.len() as u64)).unwrap();
+            for hash in block_hashes.iter() {
+                payload.extend_from_slice(&serialize(&MSG_FILTERED_BLOCK).unwrap());
+                payload.extend_from_slice(&serialize(hash).unwrap());
+            }
+            encode_raw("getdata", &payload, network)
+        },
+        BtcMessage::NotFoundFiltered { invs, filtered_block_hashes } => {
+            let mut payload = serialize(&VarInt((invs.len() + filtered_block_hashes.len()) as u64)).unwrap();
+            for inv in invs.iter() {
+                payload.extend_from_slice(&serialize(inv).unwrap());
+            }
+            for hash in filtered_block_hashes.iter() {
+                payload.extend_from_slice(&serialize(&MSG_FILTERED_BLOCK).unwrap());
+                payload.extend_from_slice(&serialize(hash).unwrap());
+            }
+            encode_raw("notfound", &payload, network)
+        },
+        BtcMessage::GetCFilters(req) => encode_raw("getcfilters", &encode_getcfilters(&req), network),
+        BtcMessage::CFilter(cfilter) => encode_raw("cfilter", &encode_cfilter(&cfilter), network),
+        BtcMessage::GetCFHeaders(req) => encode_raw("getcfheaders", &encode_getcfilters(&req), network),
+        BtcMessage::CFHeaders(cfheaders) => encode_raw("cfheaders", &encode_cfheaders(&cfheaders), network),
+        BtcMessage::GetCFCheckpt(req) => encode_raw("getcfcheckpt", &encode_getcfcheckpt(&req), network),
+        BtcMessage::CFCheckpt(cfcheckpt) => encode_raw("cfcheckpt", &encode_cfcheckpt(&cfcheckpt), network),
+        BtcMessage::FilterLoad(filter) => encode_raw("filterload", &encode_filterload(&filter), network),
+        BtcMessage::FilterAdd(data) => encode_raw("filteradd", &serialize(&data).unwrap(), network),
+        BtcMessage::FilterClear => encode_raw("filterclear", &[], network),
+        BtcMessage::MerkleBlock(merkle_block) => encode_raw("merkleblock", &encode_merkleblock(&merkle_block), network),
+        BtcMessage::Unknown { command, payload } => encode_raw(&command, &payload, network),
     };
-    serialize(&msg).unwrap() // Never fail
+    Ok(encoded)
+}
+
+/// Encode a message whose payload is already serialized.
+///
+/// # Panic
+/// If length of `command` is longer than `COMMAND_SIZE`.
+pub(crate) fn encode_raw(command: &str, payload: &[u8], network: Network) -> Vec<u8>
+{
+    let mut buf = Vec::with_capacity(RAW_NETWORK_MESSAGE_HEADER_SIZE + payload.len());
+    buf.extend_from_slice(&serialize(&network.magic()).unwrap());
+    buf.extend_from_slice(&serialize(&CommandString(command.into())).unwrap());
+    buf.extend_from_slice(&serialize(&(payload.len() as u32)).unwrap());
+    buf.extend_from_slice(&sha2_checksum(payload));
+    buf.extend_from_slice(payload);
+    buf
 }
 
 struct BtcEncoder
@@ -245,18 +908,126 @@ struct BtcEncoder
 
 impl Encoder for BtcEncoder
 {
-    type Item = NetworkMessage;
+    type Item = BtcMessage;
     type Error = Error;
     fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error>
     {
-        let encoded = encode(item, self.network.clone());
+        let encoded = encode(item, self.network.clone())?;
         dst.extend_from_slice(encoded.as_slice());
         Ok(())
     }
 }
 
+struct BtcDecoder
+{
+    network: Network,
+    // Header of a message whose payload is not fully arrived yet.
+    header: Option<RawNetworkMessageHeader>,
+}
+
+impl BtcDecoder
+{
+    fn new(network: Network) -> BtcDecoder
+    {
+        BtcDecoder {
+            network,
+            header: None,
+        }
+    }
+}
+
+impl Decoder for BtcDecoder
+{
+    type Item = BtcMessage;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error>
+    {
+        if self.header.is_none() {
+            if src.len() < RAW_NETWORK_MESSAGE_HEADER_SIZE {
+                return Ok(None);
+            }
+            let header_bytes = src.split_to(RAW_NETWORK_MESSAGE_HEADER_SIZE);
+            self.header = Some(decode_msg_header(&header_bytes, &self.network)?);
+        }
+
+        // Payload size is already checked in `decode_msg_header`.
+        let payload_size = self.header.as_ref().unwrap().payload_size as usize;
+        if src.len() < payload_size {
+            src.reserve(payload_size - src.len());
+            return Ok(None);
+        }
+
+        let header = self.header.take().unwrap();
+        let payload = src.split_to(payload_size);
+        decode_and_check_msg_payload(&payload, &header).map(Some)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error>
+    {
+        match self.decode(src)? {
+            Some(msg) => Ok(Some(msg)),
+            None if self.header.is_none() && src.is_empty() => Ok(None),
+            None => {
+                info!("Socket is closed in the middle of a message");
+                Err(Error::from(io::Error::from(io::ErrorKind::UnexpectedEof)))
+            },
+        }
+    }
+}
+
+pub(crate) const RAW_NETWORK_MESSAGE_HEADER_SIZE: usize = 24;
 
-const RAW_NETWORK_MESSAGE_HEADER_SIZE: usize = 24;
+/// Commands are padded to this size in a message header.
+pub(crate) const COMMAND_SIZE: usize = 12;
+
+/// Any payload larger than this is rejected. Same as `MAX_SIZE` of bitcoin core.
+pub const MAX_PAYLOAD_SIZE: u32 = 32 * 1024 * 1024;
+
+const MAX_INV_ENTRIES: u32 = 50_000;
+pub(crate) const MAX_HEADERS_ENTRIES: u32 = 2_000;
+const MAX_LOCATOR_ENTRIES: u32 = 101;
+
+/// Inventory type of `merkleblock` in `getdata` (BIP37).
+const MSG_FILTERED_BLOCK: u32 = 3;
+
+/// Inventory type of `cmpctblock` in `getdata` (BIP152).
+const MSG_CMPCT_BLOCK: u32 = 4;
+
+/// Maximum payload size of given command.
+/// Since peer decides `payload_size` in header freely, we **MUST** check it before allocating a buffer.
+pub(crate) fn max_payload_size(command: &str) -> u32
+{
+    const VAR_INT_SIZE: u32 = 9;
+    match command {
+        "verack" | "getaddr" | "mempool" | "sendheaders" | "wtxidrelay" | "sendaddrv2" | "filterclear" => 0,
+        "sendcmpct" => 9,
+        "ping" | "pong" => 8,
+        // Fixed size fields are 85 bytes
+        "version" => 85 + VAR_INT_SIZE + MAX_USER_AGENT_LEN as u32,
+        // Each entry is a timestamp and an address
+        "addr" => VAR_INT_SIZE + MAX_ADDR_ENTRIES * 30,
+        // Timestamp, services, network ID, address and port
+        "addrv2" => VAR_INT_SIZE + MAX_ADDR_ENTRIES * (4 + VAR_INT_SIZE + 1 + 3 + MAX_ADDRV2_ADDR_SIZE + 2),
+        // Each entry is a type and a hash
+        "inv" | "getdata" | "notfound" => VAR_INT_SIZE + MAX_INV_ENTRIES * 36,
+        // Version, locator and stop hash
+        "getblocks" | "getheaders" => 4 + VAR_INT_SIZE + MAX_LOCATOR_ENTRIES * 32 + 32,
+        // Each entry is a header and a zero tx count
+        "headers" => VAR_INT_SIZE + MAX_HEADERS_ENTRIES * 81,
+        // Block hash and differentially encoded indexes, each of which is at most 3 bytes
+        "getblocktxn" => 32 + VAR_INT_SIZE + MAX_COMPACT_BLOCK_TXS as u32 * 3,
+        // Filter type, start height and stop hash
+        "getcfilters" | "getcfheaders" => 1 + 4 + 32,
+        "getcfcheckpt" => 1 + 32,
+        // Filter, number of hash functions, tweak and flags
+        "filterload" => VAR_INT_SIZE + MAX_BLOOM_FILTER_SIZE as u32 + 4 + 4 + 1,
+        "filteradd" => VAR_INT_SIZE + MAX_FILTERADD_SIZE as u32,
+        // Filter type, stop hash, previous filter header and filter hashes
+        "cfheaders" => 1 + 32 + 32 + VAR_INT_SIZE + MAX_GETCFHEADERS_SIZE * 32,
+        _ => MAX_PAYLOAD_SIZE,
+    }
+}
 
 struct RawNetworkMessageHeader
 {
@@ -287,6 +1058,16 @@ fn decode_msg_header(src: &[u8], network: &Network) -> Result<RawNetworkMessageH
     let payload_size = u32::consensus_decode(&mut decoder)?;
     let checksum = <[u8; 4]>::consensus_decode(&mut decoder)?;
 
+    let max_size = max_payload_size(&command_name.0);
+    if payload_size > max_size {
+        warn!("Too large payload : {} ({} bytes)", command_name.0, payload_size);
+        return Err(Error::from(ConnectionError::PayloadTooLarge {
+            command: command_name.0,
+            size: payload_size,
+            max: max_size,
+        }));
+    }
+
     Ok(RawNetworkMessageHeader {
         command_name,
         payload_size,
@@ -296,7 +1077,7 @@ fn decode_msg_header(src: &[u8], network: &Network) -> Result<RawNetworkMessageH
 
 /// # Panic
 /// If length of `src` is not `header.payload_size`.
-fn decode_and_check_msg_payload(src: &[u8], header: &RawNetworkMessageHeader) -> Result<NetworkMessage, Error>
+fn decode_and_check_msg_payload(src: &[u8], header: &RawNetworkMessageHeader) -> Result<BtcMessage, Error>
 {
     assert!(src.len() as u32 == header.payload_size);
 
@@ -316,9 +1097,24 @@ fn decode_and_check_msg_payload(src: &[u8], header: &RawNetworkMessageHeader) ->
         "version" => NetworkMessage::Version(ConsensusDecodable::consensus_decode(&mut decoder)?),
         "verack" => NetworkMessage::Verack,
         "addr" => NetworkMessage::Addr(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "inv" => NetworkMessage::Inv(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "getdata" => NetworkMessage::GetData(ConsensusDecodable::consensus_decode(&mut decoder)?),
-        "notfound" => NetworkMessage::NotFound(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        "inv" => NetworkMessage::Inv(decode_invs(src)?),
+        "getdata" => {
+            let (invs, cmpct_block_hashes, _) = decode_invs_with_extra(src)?;
+            if cmpct_block_hashes.is_empty() {
+                NetworkMessage::GetData(invs)
+            } else {
+                return Ok(BtcMessage::GetDataCmpct { invs, cmpct_block_hashes });
+            }
+        },
+        "notfound" => {
+            // Peer answers `notfound` to `getdata` of merkle blocks with `MSG_FILTERED_BLOCK` entries.
+            let (invs, _, filtered_block_hashes) = decode_invs_with_extra(src)?;
+            if filtered_block_hashes.is_empty() {
+                NetworkMessage::NotFound(invs)
+            } else {
+                return Ok(BtcMessage::NotFoundFiltered { invs, filtered_block_hashes });
+            }
+        },
         "getblocks" => NetworkMessage::GetBlocks(ConsensusDecodable::consensus_decode(&mut decoder)?),
         "getheaders" => NetworkMessage::GetHeaders(ConsensusDecodable::consensus_decode(&mut decoder)?),
         "mempool" => NetworkMessage::MemPool,
@@ -329,15 +1125,88 @@ fn decode_and_check_msg_payload(src: &[u8], header: &RawNetworkMessageHeader) ->
         "pong" => NetworkMessage::Pong(ConsensusDecodable::consensus_decode(&mut decoder)?),
         "tx" => NetworkMessage::Tx(ConsensusDecodable::consensus_decode(&mut decoder)?),
         "alert" => NetworkMessage::Alert(ConsensusDecodable::consensus_decode(&mut decoder)?),
+        // Followings are not supported by bitcoin crate.
+        "sendheaders" => return Ok(BtcMessage::SendHeaders),
+        "wtxidrelay" => return Ok(BtcMessage::WtxidRelay),
+        "sendaddrv2" => return Ok(BtcMessage::SendAddrV2),
+        "addrv2" => return Ok(BtcMessage::AddrV2(decode_addrv2(src)?)),
+        "sendcmpct" => {
+            return Ok(BtcMessage::SendCmpct {
+                announce: ConsensusDecodable::consensus_decode(&mut decoder)?,
+                version: ConsensusDecodable::consensus_decode(&mut decoder)?,
+            });
+        },
+        "cmpctblock" => return Ok(BtcMessage::CmpctBlock(decode_cmpctblock(src)?)),
+        "getblocktxn" => return Ok(BtcMessage::GetBlockTxn(decode_getblocktxn(src)?)),
+        "blocktxn" => return Ok(BtcMessage::BlockTxn(decode_blocktxn(src)?)),
+        "getcfilters" => return Ok(BtcMessage::GetCFilters(decode_getcfilters(src)?)),
+        "cfilter" => return Ok(BtcMessage::CFilter(decode_cfilter(src)?)),
+        "getcfheaders" => return Ok(BtcMessage::GetCFHeaders(decode_getcfilters(src)?)),
+        "cfheaders" => return Ok(BtcMessage::CFHeaders(decode_cfheaders(src)?)),
+        "getcfcheckpt" => return Ok(BtcMessage::GetCFCheckpt(decode_getcfcheckpt(src)?)),
+        "cfcheckpt" => return Ok(BtcMessage::CFCheckpt(decode_cfcheckpt(src)?)),
+        "filterload" => return Ok(BtcMessage::FilterLoad(decode_filterload(src)?)),
+        "filteradd" => return Ok(BtcMessage::FilterAdd(ConsensusDecodable::consensus_decode(&mut decoder)?)),
+        "filterclear" => return Ok(BtcMessage::FilterClear),
+        "merkleblock" => return Ok(BtcMessage::MerkleBlock(decode_merkleblock(src)?)),
         cmd => {
-            warn!("unrecognized network command : {}", cmd);
-            return Err(Error::from(BitcoinSerializeError::UnrecognizedNetworkCommand(
-                cmd.into(),
-            )));
+            debug!("unrecognized network command : {}", cmd);
+            return Ok(BtcMessage::Unknown {
+                command: cmd.into(),
+                payload: src.to_vec(),
+            });
         },
     };
 
-    Ok(msg)
+    Ok(BtcMessage::Network(msg))
+}
+
+/// Decode a payload of `inv`, `getdata` or `notfound` message.
+/// bitcoin crate panics on inventory types it does not know (e.g. `MSG_WTX`), so we skip them here.
+fn decode_invs(src: &[u8]) -> Result<Vec<Inventory>, Error>
+{
+    decode_invs_with_extra(src).map(|(invs, _, _)| invs)
+}
+
+/// Same as `decode_invs`, but hashes of `MSG_CMPCT_BLOCK` and `MSG_FILTERED_BLOCK` entries are returned separately.
+fn decode_invs_with_extra(src: &[u8]) -> Result<(Vec<Inventory>, Vec<Sha256dHash>, Vec<Sha256dHash>), Error>
+{
+    let mut decoder = RawDecoder::new(Cursor::new(src));
+
+    let VarInt(count) = ConsensusDecodable::consensus_decode(&mut decoder)?;
+    if count > MAX_INV_ENTRIES as u64 {
+        info!("Too many inventories : {}", count);
+        return Err(Error::from(ConnectionError::MisbehavePeer));
+    }
+
+    let mut invs = Vec::with_capacity(count as usize);
+    let mut cmpct_block_hashes = Vec::new();
+    let mut filtered_block_hashes = Vec::new();
+    for _ in 0..count {
+        let inv_type: u32 = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        let hash = ConsensusDecodable::consensus_decode(&mut decoder)?;
+        let inv_type = match inv_type {
+            0 => InvType::Error,
+            1 => InvType::Transaction,
+            2 => InvType::Block,
+            0x4000_0001 => InvType::WitnessTransaction,
+            0x4000_0002 => InvType::WitnessBlock,
+            MSG_CMPCT_BLOCK => {
+                cmpct_block_hashes.push(hash);
+                continue;
+            },
+            MSG_FILTERED_BLOCK => {
+                filtered_block_hashes.push(hash);
+                continue;
+            },
+            other => {
+                debug!("Skip unknown inventory type : {:#x}", other);
+                continue;
+            },
+        };
+        invs.push(Inventory { inv_type, hash });
+    }
+    Ok((invs, cmpct_block_hashes, filtered_block_hashes))
 }
 
 fn sha2_checksum(data: &[u8]) -> [u8; 4]
@@ -345,3 +1214,328 @@ fn sha2_checksum(data: &[u8]) -> [u8; 4]
     let checksum = Sha256dHash::from_data(data);
     [checksum[0], checksum[1], checksum[2], checksum[3]]
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use tokio::{net::TcpListener, runtime::current_thread::block_on_all};
+
+    fn handshake_config(nonce: u64, local_nonces: HashSet<u64>) -> HandshakeConfig
+    {
+        HandshakeConfig {
+            start_height: 0,
+            services: 0,
+            relay: false,
+            nonce,
+            local_nonces,
+            policy: VersionPolicy::inbound(),
+            timeout: Duration::from_secs(10),
+            wtxid_relay: true,
+            addr_v2: true,
+        }
+    }
+
+    fn handshake_each_other(
+        initiator_config: HandshakeConfig,
+        responder_config: HandshakeConfig,
+    ) -> Result<(HandshakedSocket<TcpStream>, HandshakedSocket<TcpStream>), Error>
+    {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let listen_addr = listener.local_addr().unwrap();
+
+        let responder = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| Error::from(e))
+            .and_then(|(stream, _)| Socket::from_tcp(stream.unwrap(), Network::Regtest))
+            .and_then(|socket| socket.reply_handshake(responder_config));
+        let initiator = Socket::connect(&listen_addr, Network::Regtest, Duration::from_secs(5))
+            .and_then(|s| s.begin_handshake(initiator_config));
+
+        block_on_all(initiator.join(responder))
+    }
+
+    #[test]
+    fn handshake_with_inbound_peer()
+    {
+        let res = handshake_each_other(handshake_config(1, HashSet::new()), handshake_config(2, HashSet::new()));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn handshake_over_unix_socket()
+    {
+        use tokio::net::UnixStream;
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let a_addr = "10.0.0.1:8333".parse().unwrap();
+        let b_addr = "10.0.0.2:8333".parse().unwrap();
+        let initiator = Socket::new(a, Network::Regtest, a_addr, b_addr)
+            .begin_handshake(handshake_config(1, HashSet::new()));
+        let responder = Socket::new(b, Network::Regtest, b_addr, a_addr)
+            .reply_handshake(handshake_config(2, HashSet::new()));
+
+        let (initiator, responder) = block_on_all(initiator.join(responder)).unwrap();
+        assert!(!initiator.is_inbound());
+        assert!(responder.is_inbound());
+        assert_eq!(initiator.peer_version().user_agent, USER_AGENT);
+        assert_eq!(initiator.peer_version().version, PROTOCOL_VERSION);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn count_bytes_below_v2_transport()
+    {
+        use tokio::net::UnixStream;
+
+        let (a, b) = UnixStream::pair().unwrap();
+        let a_addr = "10.0.0.1:8333".parse().unwrap();
+        let b_addr = "10.0.0.2:8333".parse().unwrap();
+        let a = Socket::new(a, Network::Regtest, a_addr, b_addr).count_bytes();
+        let b = Socket::new(b, Network::Regtest, b_addr, a_addr).count_bytes();
+        let (a_counts, b_counts) = (a.byte_counts().unwrap().clone(), b.byte_counts().unwrap().clone());
+
+        let timeout = Duration::from_secs(10);
+        let ping = BtcMessage::Network(NetworkMessage::Ping(1));
+        let pong = BtcMessage::Network(NetworkMessage::Pong(1));
+        let (sent_ping, sent_pong) = (ping.clone(), pong.clone());
+        // Exchange messages both ways, so that each side has read everything the other has sent.
+        let f = a
+            .begin_v2_transport(timeout)
+            .join(b.accept_v2_transport(timeout))
+            .and_then(|(a, b)| a.send_msg(sent_ping).join(b.recv_msg()))
+            .and_then(|(a, (received, b))| b.send_msg(sent_pong).join(a.recv_msg()).map(|(_b, r)| (received, r)));
+        let (received_ping, (received_pong, _a)) = block_on_all(f).unwrap();
+        assert_eq!(received_ping, ping);
+        assert_eq!(received_pong, pong);
+
+        // Both public keys and the encrypted `ping` at least, which are all larger than plaintext.
+        assert!(a_counts.sent() > 64 + encode(ping, Network::Regtest).unwrap().len() as u64);
+        assert_eq!(a_counts.sent(), b_counts.recv());
+        assert_eq!(a_counts.recv(), b_counts.sent());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn give_up_handshake_with_silent_peer()
+    {
+        use tokio::net::UnixStream;
+
+        // Peer accepts a connection but never replies.
+        let (a, _silent) = UnixStream::pair().unwrap();
+        let addr = "10.0.0.1:8333".parse().unwrap();
+        let config = HandshakeConfig {
+            timeout: Duration::from_millis(100),
+            ..handshake_config(1, HashSet::new())
+        };
+        let res = block_on_all(Socket::new(a, Network::Regtest, addr, addr).begin_handshake(config));
+
+        match res.map_err(|e| e.downcast::<ConnectionError>()) {
+            Err(Ok(ConnectionError::HandshakeTimeout)) => {},
+            other => panic!("Unexpected result : {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn negotiate_features_during_handshake()
+    {
+        let initiator_config = handshake_config(1, HashSet::new());
+        let responder_config = HandshakeConfig {
+            wtxid_relay: false,
+            ..handshake_config(2, HashSet::new())
+        };
+        let (initiator, responder) = handshake_each_other(initiator_config, responder_config).unwrap();
+
+        // `wtxidrelay` needs both of us, but `sendaddrv2` is one-sided.
+        let expected = NegotiatedFeatures {
+            version: PROTOCOL_VERSION,
+            wtxid_relay: false,
+            addr_v2: true,
+            ..NegotiatedFeatures::default()
+        };
+        assert_eq!(initiator.features(), &expected);
+        assert_eq!(responder.features(), &expected);
+    }
+
+    #[test]
+    fn reject_self_connection()
+    {
+        // Responder knows the nonce which initiator uses, i.e. both are ourselves.
+        let mut local_nonces = HashSet::new();
+        local_nonces.insert(1);
+        let res = handshake_each_other(handshake_config(1, HashSet::new()), handshake_config(2, local_nonces));
+        match res.err().as_ref().and_then(|e| e.downcast_ref::<ConnectionError>()) {
+            Some(ConnectionError::SelfConnection) => {},
+            e => panic!("unexpected result : {:?}", e),
+        }
+    }
+
+    fn remote_version_msg(version: u32, services: u64, user_agent: &str) -> VersionMessage
+    {
+        let addr = Address::new(&"127.0.0.1:8333".parse().unwrap(), services);
+        VersionMessage {
+            version,
+            services,
+            timestamp: 0,
+            receiver: addr.clone(),
+            sender: addr,
+            nonce: 0,
+            user_agent: user_agent.into(),
+            start_height: 0,
+            relay: false,
+        }
+    }
+
+    #[test]
+    fn reject_unsatisfying_version_msg()
+    {
+        let config = HandshakeConfig {
+            policy: VersionPolicy::default(),
+            ..handshake_config(1, HashSet::new())
+        };
+        let full_services = NODE_NETWORK | NODE_WITNESS;
+        let check = |version: &VersionMessage| {
+            check_remote_version_msg(version, &config).map_err(|e| e.downcast::<ConnectionError>().unwrap())
+        };
+
+        let ok = remote_version_msg(PROTOCOL_VERSION, full_services, "/Satoshi:0.16.0/");
+        assert!(check(&ok).is_ok());
+
+        let old = remote_version_msg(60000, full_services, "/Satoshi:0.16.0/");
+        match check(&old) {
+            Err(ConnectionError::ObsoleteVersion { version: 60000, .. }) => {},
+            res => panic!("unexpected result : {:?}", res),
+        }
+
+        let pruned = remote_version_msg(PROTOCOL_VERSION, NODE_NETWORK_LIMITED | NODE_WITNESS, "/Satoshi:0.16.0/");
+        match check(&pruned) {
+            Err(ConnectionError::MissingServices { required, .. }) => assert_eq!(required, full_services),
+            res => panic!("unexpected result : {:?}", res),
+        }
+
+        let long_ua = "a".repeat(MAX_USER_AGENT_LEN + 1);
+        let long = remote_version_msg(PROTOCOL_VERSION, full_services, &long_ua);
+        match check(&long) {
+            Err(ConnectionError::UserAgentTooLong { len, max }) => {
+                assert_eq!((len, max), (long_ua.len(), MAX_USER_AGENT_LEN))
+            },
+            res => panic!("unexpected result : {:?}", res),
+        }
+    }
+
+    #[test]
+    fn decode_unknown_command_as_opaque_msg()
+    {
+        let msg = BtcMessage::Unknown {
+            command: "feefilter".into(),
+            payload: vec![0xe8, 0x03, 0, 0, 0, 0, 0, 0],
+        };
+        let encoded = encode(msg.clone(), Network::Bitcoin).unwrap();
+
+        let (header_bytes, payload_bytes) = encoded.split_at(RAW_NETWORK_MESSAGE_HEADER_SIZE);
+        let header = decode_msg_header(header_bytes, &Network::Bitcoin).unwrap();
+        let decoded = decode_and_check_msg_payload(payload_bytes, &header).unwrap();
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn reject_too_long_unknown_command()
+    {
+        let msg = BtcMessage::Unknown {
+            command: "thirteenbytes".into(),
+            payload: Vec::new(),
+        };
+        match encode(msg, Network::Bitcoin).unwrap_err().downcast::<ConnectionError>() {
+            Ok(ConnectionError::CommandTooLong(command)) => assert_eq!(command, "thirteenbytes"),
+            other => panic!("unexpected result : {:?}", other),
+        }
+    }
+
+    #[test]
+    fn skip_unknown_inv_types()
+    {
+        // `MSG_WTX` (5) followed by `MSG_WITNESS_TX`
+        let mut payload = vec![2, 5, 0, 0, 0];
+        payload.extend_from_slice(&[0xaa; 32]);
+        payload.extend_from_slice(&[1, 0, 0, 0x40]);
+        payload.extend_from_slice(&[0xbb; 32]);
+
+        let expected = Inventory {
+            inv_type: InvType::WitnessTransaction,
+            hash: Sha256dHash::from(&[0xbb; 32][..]),
+        };
+        assert_eq!(decode_invs(&payload).unwrap(), vec![expected]);
+    }
+
+    #[test]
+    fn separate_filtered_blocks_in_notfound()
+    {
+        let msg = BtcMessage::NotFoundFiltered {
+            invs: vec![Inventory {
+                inv_type: InvType::Block,
+                hash: Sha256dHash::from(&[0xaa; 32][..]),
+            }],
+            filtered_block_hashes: vec![Sha256dHash::from(&[0xbb; 32][..])],
+        };
+        let encoded = encode(msg.clone(), Network::Bitcoin).unwrap();
+
+        let (header_bytes, payload_bytes) = encoded.split_at(RAW_NETWORK_MESSAGE_HEADER_SIZE);
+        let header = decode_msg_header(header_bytes, &Network::Bitcoin).unwrap();
+        let decoded = decode_and_check_msg_payload(payload_bytes, &header).unwrap();
+        assert_eq!(decoded, msg);
+    }
+
+    #[test]
+    fn reject_too_large_payload_before_reading_it()
+    {
+        let ping = encode(BtcMessage::Network(NetworkMessage::Ping(1)), Network::Bitcoin).unwrap();
+        let header = decode_msg_header(&ping[..RAW_NETWORK_MESSAGE_HEADER_SIZE], &Network::Bitcoin).unwrap();
+        assert_eq!(header.payload_size, 8);
+
+        // Claim that ping has 4 GiB payload.
+        let mut huge_ping = ping.clone();
+        huge_ping[16..20].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        assert!(decode_msg_header(&huge_ping[..RAW_NETWORK_MESSAGE_HEADER_SIZE], &Network::Bitcoin).is_err());
+    }
+
+    #[test]
+    fn decode_msgs_arriving_in_pieces()
+    {
+        let mut bytes = encode(BtcMessage::Network(NetworkMessage::Ping(1)), Network::Bitcoin).unwrap();
+        bytes.extend(encode(BtcMessage::Network(NetworkMessage::Verack), Network::Bitcoin).unwrap());
+        bytes.extend(encode(BtcMessage::Network(NetworkMessage::Pong(1)), Network::Bitcoin).unwrap());
+
+        let mut decoder = BtcDecoder::new(Network::Bitcoin);
+        let mut buf = BytesMut::new();
+        let mut msgs = Vec::new();
+        for chunk in bytes.chunks(7) {
+            buf.extend_from_slice(chunk);
+            while let Some(msg) = decoder.decode(&mut buf).unwrap() {
+                msgs.push(msg);
+            }
+        }
+        assert_eq!(
+            msgs,
+            vec![
+                BtcMessage::Network(NetworkMessage::Ping(1)),
+                BtcMessage::Network(NetworkMessage::Verack),
+                BtcMessage::Network(NetworkMessage::Pong(1)),
+            ]
+        );
+        assert!(decoder.decode_eof(&mut buf).unwrap().is_none());
+    }
+
+    #[test]
+    fn fail_on_eof_in_the_middle_of_msg()
+    {
+        let bytes = encode(BtcMessage::Network(NetworkMessage::Ping(1)), Network::Bitcoin).unwrap();
+
+        // Whole header is arrived but payload is not.
+        let mut decoder = BtcDecoder::new(Network::Bitcoin);
+        let mut buf = BytesMut::from(&bytes[..RAW_NETWORK_MESSAGE_HEADER_SIZE]);
+        assert!(decoder.decode(&mut buf).unwrap().is_none());
+        assert!(decoder.decode_eof(&mut buf).is_err());
+    }
+}
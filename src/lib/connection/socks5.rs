@@ -0,0 +1,331 @@
+use std::net::SocketAddr;
+
+use futures::{Future, IntoFuture};
+use tokio::{io::{read_exact, write_all}, net::TcpStream};
+use rand::{thread_rng, RngCore};
+use failure::Error;
+
+use connection::error::ConnectionError;
+
+const SOCKS_VERSION: u8 = 5;
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const PASSWORD_AUTH_VERSION: u8 = 1;
+const CMD_CONNECT: u8 = 1;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+
+/// SOCKS5 proxy server which outbound connections go through, e.g. Tor.
+/// Only CONNECT command (RFC 1928) is supported.
+#[derive(Debug, Clone)]
+pub struct Proxy
+{
+    pub addr: SocketAddr,
+    pub auth: ProxyAuth,
+    /// Whether the proxy reaches I2P network, e.g. SOCKS proxy of an I2P router. Tor does not.
+    pub i2p: bool,
+}
+
+#[derive(Debug, Clone)]
+pub enum ProxyAuth
+{
+    NoAuth,
+
+    /// Username/password authentication (RFC 1929).
+    Password
+    {
+        username: String,
+        password: String,
+    },
+
+    /// Use random username and password for each connection.
+    /// Tor assigns a different circuit for each of them (stream isolation),
+    /// so peers can not link our connections each other.
+    Isolate,
+}
+
+/// Destination which we ask proxy to connect to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum TargetAddr
+{
+    Ip(SocketAddr),
+
+    /// Proxy resolves a domain name. e.g. ".onion" address.
+    Domain(String, u16),
+}
+
+impl Proxy
+{
+    pub fn new(addr: SocketAddr) -> Proxy
+    {
+        Proxy {
+            addr,
+            auth: ProxyAuth::NoAuth,
+            i2p: false,
+        }
+    }
+
+    /// Connect to `target` through this proxy.
+    /// Returned stream is already connected to `target`.
+    pub fn connect(&self, target: &TargetAddr) -> impl Future<Item = TcpStream, Error = Error>
+    {
+        let credentials = match self.auth {
+            ProxyAuth::NoAuth => None,
+            ProxyAuth::Password {
+                ref username,
+                ref password,
+            } => Some((username.clone(), password.clone())),
+            ProxyAuth::Isolate => Some(random_credentials()),
+        };
+        let connect_req = connect_request(target);
+        let proxy_addr = self.addr;
+
+        check_field_lengths(target, &credentials)
+            .into_future()
+            .and_then(move |()| TcpStream::connect(&proxy_addr).map_err(Error::from))
+            .and_then(|stream| negotiate_method(stream, credentials))
+            .and_then(|stream| write_all(stream, connect_req).map_err(Error::from))
+            .and_then(|(stream, _)| recv_connect_reply(stream))
+    }
+}
+
+fn random_credentials() -> (String, String)
+{
+    let mut rng = thread_rng();
+    (format!("{:x}", rng.next_u64()), format!("{:x}", rng.next_u64()))
+}
+
+fn connect_request(target: &TargetAddr) -> Vec<u8>
+{
+    let mut req = vec![SOCKS_VERSION, CMD_CONNECT, 0];
+    let port = match target {
+        TargetAddr::Ip(SocketAddr::V4(addr)) => {
+            req.push(ATYP_IPV4);
+            req.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        },
+        TargetAddr::Ip(SocketAddr::V6(addr)) => {
+            req.push(ATYP_IPV6);
+            req.extend_from_slice(&addr.ip().octets());
+            addr.port()
+        },
+        TargetAddr::Domain(domain, port) => {
+            req.push(ATYP_DOMAIN);
+            req.push(domain.len() as u8);
+            req.extend_from_slice(domain.as_bytes());
+            *port
+        },
+    };
+    req.push((port >> 8) as u8);
+    req.push(port as u8);
+    req
+}
+
+// Every variable length field of SOCKS5 is prefixed by 1 byte length.
+fn check_field_lengths(target: &TargetAddr, credentials: &Option<(String, String)>) -> Result<(), Error>
+{
+    let is_invalid = |s: &str| s.is_empty() || s.len() > 255;
+    if let TargetAddr::Domain(domain, _) = target {
+        if is_invalid(domain) {
+            return Err(Error::from(ConnectionError::ProxyFailure("invalid domain length")));
+        }
+    }
+    if let Some((username, password)) = credentials {
+        if is_invalid(username) || is_invalid(password) {
+            return Err(Error::from(ConnectionError::ProxyFailure("invalid username or password length")));
+        }
+    }
+    Ok(())
+}
+
+fn negotiate_method(
+    stream: TcpStream,
+    credentials: Option<(String, String)>,
+) -> Box<dyn Future<Item = TcpStream, Error = Error> + Send>
+{
+    let method = match credentials {
+        None => METHOD_NO_AUTH,
+        Some(_) => METHOD_PASSWORD,
+    };
+    let f = write_all(stream, [SOCKS_VERSION, 1, method])
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+        .map_err(Error::from)
+        .and_then(move |(stream, reply)| {
+            if reply[0] != SOCKS_VERSION {
+                return Err(Error::from(ConnectionError::ProxyFailure("unexpected SOCKS version")));
+            }
+            if reply[1] != method {
+                return Err(Error::from(ConnectionError::ProxyFailure("no acceptable authentication method")));
+            }
+            Ok(stream)
+        });
+
+    match credentials {
+        None => Box::new(f),
+        Some((username, password)) => {
+            let mut req = vec![PASSWORD_AUTH_VERSION, username.len() as u8];
+            req.extend_from_slice(username.as_bytes());
+            req.push(password.len() as u8);
+            req.extend_from_slice(password.as_bytes());
+
+            let f = f
+                .and_then(|stream| write_all(stream, req).map_err(Error::from))
+                .and_then(|(stream, _)| read_exact(stream, [0u8; 2]).map_err(Error::from))
+                .and_then(|(stream, reply)| {
+                    if reply[0] != PASSWORD_AUTH_VERSION {
+                        return Err(Error::from(ConnectionError::ProxyFailure("unexpected authentication version")));
+                    }
+                    if reply[1] != 0 {
+                        return Err(Error::from(ConnectionError::ProxyFailure("authentication failed")));
+                    }
+                    Ok(stream)
+                });
+            Box::new(f)
+        },
+    }
+}
+
+fn recv_connect_reply(stream: TcpStream) -> impl Future<Item = TcpStream, Error = Error>
+{
+    // Read a first byte of bound address as well, because it is a length if the address is a domain.
+    read_exact(stream, [0u8; 5])
+        .map_err(Error::from)
+        .and_then(|(stream, reply)| {
+            if reply[0] != SOCKS_VERSION {
+                return Err(Error::from(ConnectionError::ProxyFailure("unexpected SOCKS version")));
+            }
+            if reply[1] != 0 {
+                return Err(Error::from(ConnectionError::ProxyFailure(reply_error_reason(reply[1]))));
+            }
+            // Rest of bound address and 2 bytes port
+            let remaining = match reply[3] {
+                ATYP_IPV4 => 4 - 1 + 2,
+                ATYP_IPV6 => 16 - 1 + 2,
+                ATYP_DOMAIN => reply[4] as usize + 2,
+                _ => return Err(Error::from(ConnectionError::ProxyFailure("unknown address type"))),
+            };
+            Ok((stream, vec![0u8; remaining]))
+        })
+        .and_then(|(stream, buf)| read_exact(stream, buf).map_err(Error::from))
+        .map(|(stream, _bound_addr)| stream)
+}
+
+fn reply_error_reason(rep: u8) -> &'static str
+{
+    match rep {
+        1 => "general SOCKS server failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::{io::{Read, Write}, net::TcpListener, thread};
+    use tokio::runtime::current_thread::block_on_all;
+
+    // Accept one client and behave as a SOCKS5 proxy which requires a password.
+    // Returns the requested destination and the credentials.
+    fn run_proxy_stand_in(listener: TcpListener) -> (Vec<u8>, Vec<u8>)
+    {
+        let (mut stream, _) = listener.accept().unwrap();
+
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).unwrap();
+        assert_eq!(greeting, [SOCKS_VERSION, 1, METHOD_PASSWORD]);
+        stream.write_all(&[SOCKS_VERSION, METHOD_PASSWORD]).unwrap();
+
+        let mut auth = vec![0u8; 2];
+        stream.read_exact(&mut auth).unwrap();
+        let mut username = vec![0u8; auth[1] as usize];
+        stream.read_exact(&mut username).unwrap();
+        let mut password_len = [0u8; 1];
+        stream.read_exact(&mut password_len).unwrap();
+        let mut password = vec![0u8; password_len[0] as usize];
+        stream.read_exact(&mut password).unwrap();
+        stream.write_all(&[PASSWORD_AUTH_VERSION, 0]).unwrap();
+
+        let mut req = [0u8; 5];
+        stream.read_exact(&mut req).unwrap();
+        assert_eq!(req[..4], [SOCKS_VERSION, CMD_CONNECT, 0, ATYP_DOMAIN]);
+        let mut domain_and_port = vec![0u8; req[4] as usize + 2];
+        stream.read_exact(&mut domain_and_port).unwrap();
+        stream.write_all(&[SOCKS_VERSION, 0, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0]).unwrap();
+
+        // Now the stream is relayed to destination. Pretend to be it.
+        stream.write_all(b"hello").unwrap();
+
+        let mut credentials = username;
+        credentials.push(b':');
+        credentials.extend(password);
+        (domain_and_port, credentials)
+    }
+
+    #[test]
+    fn connect_to_onion_through_proxy()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy {
+            addr: listener.local_addr().unwrap(),
+            auth: ProxyAuth::Password {
+                username: "alice".into(),
+                password: "secret".into(),
+            },
+            i2p: false,
+        };
+        let stand_in = thread::spawn(move || run_proxy_stand_in(listener));
+
+        let onion = "expyuzz4wqqyqhjn.onion";
+        let target = TargetAddr::Domain(onion.into(), 8333);
+        let f = proxy.connect(&target).and_then(|stream| read_exact(stream, [0u8; 5]).map_err(Error::from));
+        let (_stream, greeting) = block_on_all(f).unwrap();
+        assert_eq!(&greeting, b"hello");
+
+        let (domain_and_port, credentials) = stand_in.join().unwrap();
+        let mut expected = onion.as_bytes().to_vec();
+        expected.extend_from_slice(&[0x20, 0x8d]);
+        assert_eq!(domain_and_port, expected);
+        assert_eq!(credentials, b"alice:secret".to_vec());
+    }
+
+    #[test]
+    fn reject_unexpected_authentication_version()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = Proxy {
+            addr: listener.local_addr().unwrap(),
+            auth: ProxyAuth::Password {
+                username: "alice".into(),
+                password: "secret".into(),
+            },
+            i2p: false,
+        };
+        let stand_in = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[SOCKS_VERSION, METHOD_PASSWORD]).unwrap();
+            let mut auth = [0u8; 2 + 5 + 1 + 6];
+            stream.read_exact(&mut auth).unwrap();
+            // Success status, but of SOCKS version instead of the subnegotiation version.
+            stream.write_all(&[SOCKS_VERSION, 0]).unwrap();
+        });
+
+        let target = TargetAddr::Domain("expyuzz4wqqyqhjn.onion".into(), 8333);
+        let err = block_on_all(proxy.connect(&target)).unwrap_err();
+        match err.downcast::<ConnectionError>() {
+            Ok(ConnectionError::ProxyFailure(_)) => (),
+            other => panic!("unexpected result : {:?}", other),
+        }
+        stand_in.join().unwrap();
+    }
+}
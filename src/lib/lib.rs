@@ -1,4 +1,6 @@
 extern crate bitcoin;
+extern crate crypto;
+extern crate secp256k1;
 extern crate futures;
 extern crate tokio;
 extern crate trust_dns_resolver;
@@ -8,7 +10,6 @@ extern crate bytes;
 extern crate actix;
 #[macro_use]
 extern crate log;
-#[macro_use]
 extern crate failure;
 #[macro_use]
 extern crate failure_derive;
@@ -16,3 +17,4 @@ extern crate failure_derive;
 pub mod connection;
 pub mod blockchain;
 pub mod process;
+pub(crate) mod util;
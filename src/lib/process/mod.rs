@@ -1 +1,2 @@
 pub mod sync_blockchain;
+pub mod sync_filters;
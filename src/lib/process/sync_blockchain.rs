@@ -2,10 +2,13 @@ use actix::prelude::*;
 use futures::Future;
 
 use blockchain::BlockChain;
-use connection::{Connection, Disconnect, GetHeadersRequest, HeadersResponse};
+use connection::{Connection, Disconnect, GetHeadersRequest, HeadersResponse, Misbehave};
 
 const NUM_MAX_HEADERS_IN_MSG: usize = 2000;
 
+// Same as Bitcoin Core's score of non-connecting headers.
+const INVALID_HEADER_SCORE: u32 = 20;
+
 pub struct SyncBlockChain
 {
     // This should not be None unless all process is completed
@@ -109,10 +112,26 @@ impl Handler<HeadersResponse> for SyncBlockChain
     type Result = ();
     fn handle(&mut self, msg: HeadersResponse, ctx: &mut Context<Self>)
     {
-        let is_finish = msg.0.len() == NUM_MAX_HEADERS_IN_MSG;
-        for lone_header in msg.0 {
+        let headers = match msg {
+            HeadersResponse::Headers(headers) => headers,
+            HeadersResponse::TimedOut => {
+                info!("Peer does not answer getheaders. Disconnect");
+                self.connection.do_send(Disconnect());
+                return self.notify_err(ctx);
+            },
+            HeadersResponse::QueueFull => {
+                info!("Connection is busy with other getheaders");
+                return self.notify_err(ctx);
+            },
+        };
+        let is_finish = headers.len() == NUM_MAX_HEADERS_IN_MSG;
+        for lone_header in headers {
             if let Err(_e) = self.blockchain_mut().try_add(lone_header.header) {
                 info!("Peer sends invalid block header. Disconnect");
+                self.connection.do_send(Misbehave {
+                    score: INVALID_HEADER_SCORE,
+                    reason: "headers not connecting to our chain".into(),
+                });
                 self.connection.do_send(Disconnect());
                 return self.notify_err(ctx);
             }
@@ -0,0 +1,578 @@
+use std::cmp;
+
+use actix::prelude::*;
+use futures::{future::join_all, Future};
+use bitcoin::blockdata::{block::Block, opcodes::All as Opcode, script::Script};
+use bitcoin::network::serialize::BitcoinHash;
+use bitcoin::util::hash::Sha256dHash;
+
+use blockchain::BlockChain;
+use connection::{has_valid_merkle_root, socket::NODE_COMPACT_FILTERS, BlockResponse, CFHeaders, CFilter, Connection,
+                 FilterHeadersResponse, FiltersResponse, GetBlocksRequest, GetFilterHeadersRequest, GetFiltersRequest,
+                 GetPeerInfo, Misbehave, BAN_THRESHOLD, MAX_GETCFHEADERS_SIZE, MAX_GETCFILTERS_SIZE};
+
+/// Light client of BIP157/158 on top of a synced headers-only `BlockChain`.
+///
+/// 1. Filter headers are downloaded from all connections which advertise `NODE_COMPACT_FILTERS`.
+///    If they disagree, the block and filters of the first conflicting height are downloaded,
+///    and connections whose filter is inconsistent with the block are banned.
+/// 2. Filters are downloaded from connections in turn, and checked against the filter headers.
+/// 3. Blocks whose filters match watched scripts are downloaded and sent to `notify`.
+pub struct SyncFilters
+{
+    // Active chain from `start_height`.
+    start_height: u32,
+    block_hashes: Vec<Sha256dHash>,
+    // Verified filter headers of `block_hashes`.
+    filter_headers: Vec<Sha256dHash>,
+    // Filter header of the block before `start_height`. Unless it is genesis, we learn it from peers.
+    prev_filter_header: Option<Sha256dHash>,
+
+    connections: Vec<Addr<Connection>>,
+    scripts: Vec<Script>,
+    notify: Recipient<SyncFiltersResult>,
+
+    // Previous filter header and filter headers of the current batch which each connection answers.
+    // Connections are asked in turn, since a response does not tell which peer sends it.
+    // So an answer of `connections[i]` is `headers_answers[i]`.
+    waiting_headers: bool,
+    headers_answers: Vec<(Sha256dHash, Vec<Sha256dHash>)>,
+    conflict: Option<Conflict>,
+
+    // Index of `block_hashes` from which next filters are requested.
+    next_filter: usize,
+    num_filter_requests: usize,
+    matched_blocks: Vec<Sha256dHash>,
+    pending_blocks: usize,
+}
+
+// Connections disagree on the filter header of `block_hashes[index]`.
+struct Conflict
+{
+    index: usize,
+    block: Option<Block>,
+    // Filters of connections before this index are consistent with the block.
+    checked: usize,
+    num_banned: usize,
+}
+
+#[derive(Message)]
+pub enum SyncFiltersResult
+{
+    /// A block which matches watched scripts. Blocks are sent in order of height.
+    Block(Block),
+
+    /// All blocks are checked. Verified filter headers from `start_height` are returned.
+    Complete(Vec<Sha256dHash>),
+
+    /// No connection serves filters, or connections disagree on filter headers and we can not tell which is wrong.
+    Error,
+}
+
+impl SyncFilters
+{
+    /// Check blocks of the active chain from `start_height`.
+    /// Filter headers are cross-checked among `connections`, so several peers should be given.
+    pub fn new(
+        blockchain: &BlockChain,
+        start_height: u32,
+        connections: Vec<Addr<Connection>>,
+        scripts: Vec<Script>,
+        notify: Recipient<SyncFiltersResult>,
+    ) -> SyncFilters
+    {
+        let block_hashes: Vec<_> = blockchain
+            .active_chain()
+            .iter()
+            .filter(|block| block.height() >= start_height)
+            .map(|block| block.bitcoin_hash())
+            .collect();
+        let start_height = cmp::max(start_height, blockchain.active_chain().iter().next().unwrap().height());
+        SyncFilters {
+            start_height,
+            block_hashes,
+            filter_headers: Vec::new(),
+            prev_filter_header: if start_height == 0 { Some(Sha256dHash::default()) } else { None },
+
+            connections,
+            scripts,
+            notify,
+
+            waiting_headers: false,
+            headers_answers: Vec::new(),
+            conflict: None,
+
+            next_filter: 0,
+            num_filter_requests: 0,
+            matched_blocks: Vec::new(),
+            pending_blocks: 0,
+        }
+    }
+
+    pub fn start_actor(
+        blockchain: &BlockChain,
+        start_height: u32,
+        connections: Vec<Addr<Connection>>,
+        scripts: Vec<Script>,
+        notify: Recipient<SyncFiltersResult>,
+    ) -> Addr<SyncFilters>
+    {
+        SyncFilters::new(blockchain, start_height, connections, scripts, notify).start()
+    }
+
+    // Keep only connections which advertise `NODE_COMPACT_FILTERS`, and then start.
+    fn select_connections(&mut self, ctx: &mut Context<Self>)
+    {
+        let infos: Vec<_> = self
+            .connections
+            .iter()
+            .map(|conn| conn.send(GetPeerInfo()).then(|res| Ok::<_, ()>(res.ok())))
+            .collect();
+        let f = join_all(infos)
+            .into_actor(self)
+            .map(|infos, actor, ctx| {
+                let connections = actor.connections.drain(..).zip(infos);
+                actor.connections = connections
+                    .filter(|(_, info)| {
+                        info.as_ref().map_or(false, |info| info.version.services & NODE_COMPACT_FILTERS != 0)
+                    })
+                    .map(|(conn, _)| conn)
+                    .collect();
+                if actor.connections.is_empty() {
+                    info!("No connection serves filters");
+                    return actor.notify_err(ctx);
+                }
+                actor.request_filter_headers(ctx);
+            })
+            .map_err(|(), _actor, _ctx| unreachable!());
+        ctx.wait(f);
+    }
+
+    // Remove a connection which does not serve filters or sends invalid ones, with its answer if any.
+    fn remove_connection(&mut self, index: usize)
+    {
+        self.connections.remove(index);
+        if index < self.headers_answers.len() {
+            self.headers_answers.remove(index);
+        }
+    }
+
+    fn ban_connection(&mut self, index: usize, reason: &str)
+    {
+        self.connections[index].do_send(Misbehave {
+            score: BAN_THRESHOLD,
+            reason: reason.into(),
+        });
+        self.remove_connection(index);
+    }
+
+    // Returns the range of `block_hashes` of the current batch of filter headers.
+    fn headers_batch(&self) -> (usize, usize)
+    {
+        let from = self.filter_headers.len();
+        let to = cmp::min(from + MAX_GETCFHEADERS_SIZE as usize, self.block_hashes.len()) - 1;
+        (from, to)
+    }
+
+    fn request_filter_headers(&mut self, ctx: &mut Context<Self>)
+    {
+        if self.filter_headers.len() == self.block_hashes.len() {
+            return self.request_filters(ctx);
+        }
+        let (from, to) = self.headers_batch();
+        self.waiting_headers = true;
+        self.headers_answers.clear();
+        self.request_next_filter_headers(from, to, ctx);
+    }
+
+    fn request_next_filter_headers(&mut self, from: usize, to: usize, ctx: &mut Context<Self>)
+    {
+        if self.connections.is_empty() {
+            info!("No connection answers filter headers");
+            return self.notify_err(ctx);
+        }
+        if self.headers_answers.len() == self.connections.len() {
+            self.waiting_headers = false;
+            return self.finish_filter_headers(ctx);
+        }
+        self.connections[self.headers_answers.len()].do_send(GetFilterHeadersRequest {
+            start_height: self.start_height + from as u32,
+            stop_hash: self.block_hashes[to],
+            addr: ctx.address().recipient(),
+        });
+    }
+
+    /// Returns the previous filter header and filter headers if `cfheaders` answers the current batch.
+    fn check_filter_headers(&self, cfheaders: &CFHeaders) -> Option<(Sha256dHash, Vec<Sha256dHash>)>
+    {
+        let (from, to) = self.headers_batch();
+        if cfheaders.stop_hash != self.block_hashes[to] || cfheaders.filter_hashes.len() != to - from + 1 {
+            return None;
+        }
+        let prev = self.filter_headers.last().cloned().or(self.prev_filter_header);
+        if prev.map_or(false, |prev| prev != cfheaders.prev_filter_header) {
+            return None;
+        }
+        Some((cfheaders.prev_filter_header, cfheaders.filter_headers()))
+    }
+
+    // All connections answer the current batch. Accept it if they agree, or resolve a conflict.
+    fn finish_filter_headers(&mut self, ctx: &mut Context<Self>)
+    {
+        if self.headers_answers.is_empty() {
+            info!("No connection answers filter headers");
+            return self.notify_err(ctx);
+        }
+        if let Some(i) = first_conflict(&self.headers_answers) {
+            let index = self.filter_headers.len() + i;
+            info!("Peers do not agree on the filter header at height {}", self.start_height + index as u32);
+            self.conflict = Some(Conflict {
+                index,
+                block: None,
+                checked: 0,
+                num_banned: 0,
+            });
+            self.connections[0].do_send(GetBlocksRequest {
+                block_hashes: vec![self.block_hashes[index]],
+                addr: ctx.address().recipient(),
+            });
+            return;
+        }
+
+        let (prev, headers) = self.headers_answers.swap_remove(0);
+        if self.prev_filter_header.is_none() {
+            self.prev_filter_header = Some(prev);
+        }
+        self.filter_headers.extend(headers);
+        self.request_filter_headers(ctx);
+    }
+
+    // Request a filter of the conflicting block from the next connection.
+    // After all connections are checked, answers of remaining ones are compared again.
+    fn request_conflicting_filter(&mut self, ctx: &mut Context<Self>)
+    {
+        let (index, checked, num_banned) = {
+            let conflict = self.conflict.as_ref().unwrap();
+            (conflict.index, conflict.checked, conflict.num_banned)
+        };
+        if checked < self.connections.len() {
+            self.connections[checked].do_send(GetFiltersRequest {
+                start_height: self.start_height + index as u32,
+                stop_hash: self.block_hashes[index],
+                addr: ctx.address().recipient(),
+            });
+            return;
+        }
+
+        self.conflict = None;
+        if num_banned == 0 {
+            // All filters are consistent with the block, e.g. they differ only in spent scripts.
+            info!("Could not tell which peer sends wrong filter headers");
+            return self.notify_err(ctx);
+        }
+        self.finish_filter_headers(ctx);
+    }
+
+    fn check_conflicting_filter(&mut self, filters: &[CFilter])
+    {
+        let conflict = self.conflict.as_mut().unwrap();
+        let i = conflict.index - self.filter_headers.len();
+        let (prev, headers) = &self.headers_answers[conflict.checked];
+        let prev = if i == 0 { *prev } else { headers[i - 1] };
+        let valid = match (filters, conflict.block.as_ref()) {
+            ([cfilter], Some(block)) => is_consistent_filter(block, cfilter, &prev, &headers[i]),
+            _ => false,
+        };
+        if valid {
+            conflict.checked += 1;
+        } else {
+            conflict.num_banned += 1;
+            let checked = conflict.checked;
+            self.ban_connection(checked, "filter header inconsistent with block");
+        }
+    }
+
+    fn request_filters(&mut self, ctx: &mut Context<Self>)
+    {
+        let from = self.next_filter;
+        if from == self.block_hashes.len() {
+            return self.request_blocks(ctx);
+        }
+        if self.connections.is_empty() {
+            info!("No connection serves filters");
+            return self.notify_err(ctx);
+        }
+        let to = cmp::min(from + MAX_GETCFILTERS_SIZE as usize, self.block_hashes.len()) - 1;
+
+        self.connections[self.filter_connection()].do_send(GetFiltersRequest {
+            start_height: self.start_height + from as u32,
+            stop_hash: self.block_hashes[to],
+            addr: ctx.address().recipient(),
+        });
+    }
+
+    // Connections serve filters in turn.
+    fn filter_connection(&self) -> usize
+    {
+        self.num_filter_requests % self.connections.len()
+    }
+
+    /// Returns false if a filter does not match the verified filter header.
+    fn check_filters(&mut self, filters: &[CFilter]) -> bool
+    {
+        let from = self.next_filter;
+        let to = cmp::min(from + MAX_GETCFILTERS_SIZE as usize, self.block_hashes.len());
+        if filters.len() != to - from {
+            return false;
+        }
+        for (i, cfilter) in (from..to).zip(filters) {
+            let prev = if i == 0 { self.prev_filter_header.unwrap() } else { self.filter_headers[i - 1] };
+            if cfilter.block_hash != self.block_hashes[i] || cfilter.filter.header(&prev) != self.filter_headers[i] {
+                return false;
+            }
+        }
+        for cfilter in filters {
+            if cfilter.filter.match_any(&cfilter.block_hash, self.scripts.iter().map(|s| &s[..])) {
+                self.matched_blocks.push(cfilter.block_hash);
+            }
+        }
+        self.next_filter = to;
+        true
+    }
+
+    fn request_blocks(&mut self, ctx: &mut Context<Self>)
+    {
+        if self.matched_blocks.is_empty() {
+            return self.notify_complete(ctx);
+        }
+        debug!("{} blocks match watched scripts", self.matched_blocks.len());
+        self.pending_blocks = self.matched_blocks.len();
+        self.connections[0].do_send(GetBlocksRequest {
+            block_hashes: self.matched_blocks.clone(),
+            addr: ctx.address().recipient(),
+        });
+    }
+
+    /// Send error message and then stop actor.
+    fn notify_err(&mut self, ctx: &mut Context<Self>)
+    {
+        let f = self.notify
+            .send(SyncFiltersResult::Error)
+            .map_err(|_e| debug!("Caller already dropped"))
+            .into_actor(self)
+            .map(|(), _actor, ctx| ctx.stop());
+        ctx.wait(f);
+    }
+
+    /// Send complete message and then stop actor.
+    fn notify_complete(&mut self, ctx: &mut Context<Self>)
+    {
+        let res = SyncFiltersResult::Complete(self.filter_headers.clone());
+        let f = self.notify
+            .send(res)
+            .map_err(|_e| debug!("Caller already dropped"))
+            .into_actor(self)
+            .map(|(), _actor, ctx| ctx.stop());
+        ctx.wait(f);
+    }
+}
+
+/// Returns the index of the first filter header on which answers disagree.
+fn first_conflict(answers: &[(Sha256dHash, Vec<Sha256dHash>)]) -> Option<usize>
+{
+    let (prev, headers) = &answers[0];
+    answers[1..]
+        .iter()
+        .filter_map(|(other_prev, other_headers)| {
+            if other_prev != prev {
+                return Some(0);
+            }
+            headers.iter().zip(other_headers).position(|(a, b)| a != b)
+        })
+        .min()
+}
+
+/// Returns true if the filter chains to `filter_header` and contains all output scripts of the block.
+/// Scripts which inputs spend can not be checked without previous outputs, so they are trusted.
+fn is_consistent_filter(
+    block: &Block,
+    cfilter: &CFilter,
+    prev_filter_header: &Sha256dHash,
+    filter_header: &Sha256dHash,
+) -> bool
+{
+    let block_hash = block.bitcoin_hash();
+    if cfilter.block_hash != block_hash || cfilter.filter.header(prev_filter_header) != *filter_header {
+        return false;
+    }
+    let outputs = block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.output.iter())
+        .map(|output| &output.script_pubkey[..])
+        .filter(|script| script.first().map_or(false, |op| *op != Opcode::OP_RETURN as u8));
+    cfilter.filter.match_all(&block_hash, outputs)
+}
+
+impl Actor for SyncFilters
+{
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context)
+    {
+        if self.connections.is_empty() {
+            info!("No connection to sync filters");
+            return self.notify_err(ctx);
+        }
+        self.select_connections(ctx);
+    }
+}
+
+impl Handler<FilterHeadersResponse> for SyncFilters
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: FilterHeadersResponse, ctx: &mut Context<Self>)
+    {
+        if !self.waiting_headers {
+            return;
+        }
+        let index = self.headers_answers.len();
+        match msg {
+            FilterHeadersResponse::Headers(cfheaders) => match self.check_filter_headers(&cfheaders) {
+                Some(headers) => self.headers_answers.push(headers),
+                None => {
+                    info!("Peer sends filter headers which do not answer the request");
+                    self.ban_connection(index, "unrequested cfheaders");
+                },
+            },
+            FilterHeadersResponse::Unsupported | FilterHeadersResponse::TimedOut | FilterHeadersResponse::QueueFull => {
+                info!("Peer does not serve filter headers");
+                self.remove_connection(index);
+            },
+        }
+        let (from, to) = self.headers_batch();
+        self.request_next_filter_headers(from, to, ctx);
+    }
+}
+
+impl Handler<FiltersResponse> for SyncFilters
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: FiltersResponse, ctx: &mut Context<Self>)
+    {
+        if self.conflict.is_some() {
+            match msg {
+                FiltersResponse::Filters(filters) => self.check_conflicting_filter(&filters),
+                FiltersResponse::Unsupported | FiltersResponse::TimedOut | FiltersResponse::QueueFull => {
+                    let checked = self.conflict.as_ref().unwrap().checked;
+                    self.remove_connection(checked);
+                },
+            }
+            return self.request_conflicting_filter(ctx);
+        }
+
+        let conn = self.filter_connection();
+        match msg {
+            FiltersResponse::Filters(ref filters) if self.check_filters(filters) => self.num_filter_requests += 1,
+            FiltersResponse::Filters(_) => {
+                info!("Peer sends filters which do not match filter headers");
+                self.ban_connection(conn, "invalid cfilter");
+            },
+            FiltersResponse::Unsupported | FiltersResponse::TimedOut | FiltersResponse::QueueFull => {
+                info!("Peer does not serve filters");
+                self.remove_connection(conn);
+            },
+        }
+        self.request_filters(ctx);
+    }
+}
+
+impl Handler<BlockResponse> for SyncFilters
+{
+    type Result = ();
+
+    fn handle(&mut self, msg: BlockResponse, ctx: &mut Context<Self>)
+    {
+        let block = match msg {
+            BlockResponse::Block(block) => block,
+            BlockResponse::NotFound(_) | BlockResponse::TimedOut(_) | BlockResponse::QueueFull(_) => {
+                info!("Peer does not serve a block");
+                self.pending_blocks = 0;
+                self.conflict = None;
+                return self.notify_err(ctx);
+            },
+        };
+        if self.conflict.is_some() {
+            // Filters are checked against the block, so its transactions must be the ones of the header.
+            if !has_valid_merkle_root(&block) {
+                self.ban_connection(0, "block with wrong merkle root");
+                self.conflict = None;
+                return self.finish_filter_headers(ctx);
+            }
+            self.conflict.as_mut().unwrap().block = Some(block);
+            return self.request_conflicting_filter(ctx);
+        }
+        if self.pending_blocks == 0 {
+            return;
+        }
+        self.pending_blocks -= 1;
+        self.notify.do_send(SyncFiltersResult::Block(block)).unwrap_or_else(|_e| debug!("Caller already dropped"));
+        if self.pending_blocks == 0 {
+            self.notify_complete(ctx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::network::constants::Network;
+    use blockchain::BlockFilter;
+
+    fn hash(n: u8) -> Sha256dHash
+    {
+        Sha256dHash::from_data(&[n])
+    }
+
+    #[test]
+    fn find_first_conflicting_filter_header()
+    {
+        let honest = (hash(0), vec![hash(1), hash(2), hash(3)]);
+        assert_eq!(first_conflict(&[honest.clone(), honest.clone()]), None);
+
+        let late_liar = (hash(0), vec![hash(1), hash(2), hash(9)]);
+        let early_liar = (hash(0), vec![hash(1), hash(9), hash(9)]);
+        assert_eq!(first_conflict(&[honest.clone(), late_liar.clone(), early_liar]), Some(1));
+        assert_eq!(first_conflict(&[late_liar, honest.clone()]), Some(2));
+
+        let other_prev = (hash(9), honest.1.clone());
+        assert_eq!(first_conflict(&[honest, other_prev]), Some(0));
+    }
+
+    #[test]
+    fn detect_filter_inconsistent_with_block()
+    {
+        let block = genesis_block(Network::Testnet);
+        let block_hash = block.bitcoin_hash();
+        let cfilter = |filter: BlockFilter| CFilter {
+            filter_type: 0,
+            block_hash,
+            filter,
+        };
+        let prev = Sha256dHash::default();
+
+        let honest = cfilter(BlockFilter::basic(&block, &[]));
+        let header = honest.filter.header(&prev);
+        assert!(is_consistent_filter(&block, &honest, &prev, &header));
+        assert!(!is_consistent_filter(&block, &honest, &prev, &hash(0)));
+
+        // A liar computes the filter header correctly, but omits the output of the block.
+        let liar = cfilter(BlockFilter::new(&block_hash, vec![&b"fake"[..]]));
+        let liar_header = liar.filter.header(&prev);
+        assert!(!is_consistent_filter(&block, &liar, &prev, &liar_header));
+    }
+}
@@ -0,0 +1,3 @@
+mod siphash;
+
+pub use self::siphash::siphash24;